@@ -5,6 +5,7 @@ import androidx.test.ext.junit.runners.AndroidJUnit4
 import kotlinx.coroutines.ExperimentalCoroutinesApi
 import org.coepi.core.jni.JniApi
 import org.coepi.core.jni.JniLogCallback
+import org.coepi.core.jni.JniStringResult
 import org.coepi.core.jni.JniVoidResult
 import org.coepi.core.services.CoreLogger
 import org.junit.Assert.assertEquals
@@ -47,7 +48,7 @@ class JNIInterfaceBootstrappedTests {
             .absolutePath.removeSuffix("/remove")
 
         val n = JniApi()
-        val result = n.bootstrapCore(dbPath, "debug", true,
+        val result = n.bootstrapCore(dbPath, "debug", true, "{}",
             JniLogCallback(object : CoreLogger {
                 override fun log(level: Int, message: String) {
                     println("[CORE] level: $level, message: $message")
@@ -55,7 +56,7 @@ class JNIInterfaceBootstrappedTests {
             })
         )
         // Double check
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     // Manual testing
@@ -75,7 +76,8 @@ class JNIInterfaceBootstrappedTests {
     @Test
     fun generateTcn() {
         val value = JniApi().generateTcn()
-        assertEquals(value.length, 32)
+        assertEquals(1, value.status)
+        assertEquals(value.obj.length, 32)
     }
 
     @Test
@@ -102,19 +104,19 @@ class JNIInterfaceBootstrappedTests {
     @Test
     fun setCoughTypeNone() {
         val result = JniApi().setCoughType("none")
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
     fun setCoughTypeWet() {
         val result = JniApi().setCoughType("wet")
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
     fun setCoughTypeDry() {
         val result = JniApi().setCoughType("dry")
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
@@ -126,20 +128,20 @@ class JNIInterfaceBootstrappedTests {
     @Test
     fun setCoughDaysIsSet() {
         val result = JniApi().setCoughDays(1, 3)
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
     fun setCoughDaysIsNotSet() {
         // Note: days is ignored
         val result = JniApi().setCoughDays(0, 123)
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
     fun setCoughStatus() {
         val result = JniApi().setCoughStatus("better_and_worse")
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
@@ -151,7 +153,7 @@ class JNIInterfaceBootstrappedTests {
     @Test
     fun setBreathlessnessCause() {
         val result = JniApi().setBreathlessnessCause("leaving_house_or_dressing")
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
@@ -163,21 +165,21 @@ class JNIInterfaceBootstrappedTests {
     @Test
     fun setFeverDaysIsSet() {
         val result = JniApi().setFeverDays(1, 3)
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
     fun setFeverDaysNone() {
         // Note: days is ignored
         val result = JniApi().setFeverDays(0, 3)
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
     fun setFeverTakenTemperatureToday() {
         val result = JniApi()
             .setFeverTakenTemperatureToday(1, 3)
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
@@ -185,13 +187,13 @@ class JNIInterfaceBootstrappedTests {
         // Note: days is ignored
         val result = JniApi()
             .setFeverTakenTemperatureToday(0, 3)
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
     fun setFeverTakenTemperatureSpot() {
         val result = JniApi().setFeverTakenTemperatureSpot("armpit")
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
@@ -204,7 +206,7 @@ class JNIInterfaceBootstrappedTests {
     fun setHigherFeverTemperatureTaken() {
         val result = JniApi()
             .setFeverHighestTemperatureTaken(1, 100f)
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
@@ -212,14 +214,14 @@ class JNIInterfaceBootstrappedTests {
         // Note: temp is ignored
         val result = JniApi()
             .setFeverHighestTemperatureTaken(0, 100f)
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
     fun setEarliestSymptomStartedDaysAgo() {
         val result = JniApi()
             .setEarliestSymptomStartedDaysAgo(1, 10)
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
@@ -227,19 +229,19 @@ class JNIInterfaceBootstrappedTests {
         // Note: days is ignored
         val result = JniApi()
             .setEarliestSymptomStartedDaysAgo(0, 10)
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
     fun clearSymptoms() {
         val result = JniApi().clearSymptoms()
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     @Test
     fun submitSymptoms() {
         val result = JniApi().submitSymptoms()
-        assertEquals(JniVoidResult(1, ""), result)
+        assertEquals(JniStringResult(1, "", "{\"warnings\":[]}"), result)
     }
 
     // TODO more detailed tests, e.g. for each supported enum string (probably it makes sense to add
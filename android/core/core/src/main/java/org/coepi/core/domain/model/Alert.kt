@@ -17,9 +17,19 @@ data class Alert(
     val runnyNose: Boolean,
     val other: Boolean,
     val noSymptoms: Boolean, // https://github.com/Co-Epi/app-ios/issues/268#issuecomment-645583717
-    var contactTime: UnixTime
+    var contactTime: UnixTime,
+    // Id of the reports source (backend) the report was downloaded from
+    val source: String,
+    // Revoked and amended alerts replace the alert with the same id
+    val status: AlertStatus,
+    // Report of a verified positive test, otherwise self-reported
+    val verified: Boolean
 ) : Parcelable
 
+enum class AlertStatus {
+    ACTIVE, REVOKED, AMENDED
+}
+
 enum class FeverSeverity {
     NONE, MILD, SERIOUS
 }
@@ -55,3 +65,10 @@ fun toCoughSeverity(int: Int): CoughSeverity = when (int) {
     3 -> CoughSeverity.DRY
     else -> error("Invalid value: $int")
 }
+
+fun toAlertStatus(int: Int): AlertStatus = when (int) {
+    0 -> AlertStatus.ACTIVE
+    1 -> AlertStatus.REVOKED
+    2 -> AlertStatus.AMENDED
+    else -> error("Invalid value: $int")
+}
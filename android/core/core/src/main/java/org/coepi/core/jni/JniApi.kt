@@ -11,18 +11,24 @@ class JniApi {
         System.loadLibrary("coepi_core")
     }
 
+    // config: JSON, see CoreConfig in Rust. "{}" uses defaults.
+    // Returns JSON with the warnings (problems the core recovered from), see BootstrapResult in Rust.
     external fun bootstrapCore(
-        dbPath: String, level: String, coepiOnly: Boolean, logCallback: JniLogCallback
-    ): JniVoidResult
+        dbPath: String, level: String, coepiOnly: Boolean, config: String,
+        logCallback: JniLogCallback
+    ): JniStringResult
 
     external fun clearSymptoms(): JniVoidResult
 
     external fun fetchNewReports(): JniAlertsArrayResult
 
-    external fun generateTcn(): String
+    external fun generateTcn(): JniStringResult
 
     external fun recordTcn(tcn: String): JniVoidResult
 
+    // Empty string clears the token
+    external fun setAuthToken(token: String): JniVoidResult
+
     // TODO test:
     external fun setBreathlessnessCause(cause: String): JniVoidResult
 
@@ -46,6 +52,26 @@ class JniApi {
 
     external fun submitSymptoms(): JniVoidResult
 
+    external fun reportPreview(): JniStringResult
+
+    external fun revokeReport(): JniVoidResult
+
+    external fun amendReport(): JniVoidResult
+
+    external fun verifyCode(code: String): JniVoidResult
+
+    external fun syncStats(): JniStringResult
+
+    external fun exportBackup(passphrase: String): JniStringResult
+
+    external fun importBackup(passphrase: String, backup: String): JniVoidResult
+
+    external fun exportRecoveryPhrase(): JniStringResult
+
+    external fun importRecoveryPhrase(phrase: String): JniVoidResult
+
+    external fun deleteAllData(): JniStringResult
+
     // Tests ////////////////////////////////////////////////////////////////////////
 
     // Basic
@@ -98,6 +124,12 @@ data class JniVoidResult(
     val message: String
 )
 
+data class JniStringResult(
+    val status: Int,
+    val message: String,
+    val obj: String
+)
+
 data class JniOneAlertResult(
     val status: Int,
     val message: String,
@@ -133,7 +165,11 @@ data class JniAlertsArrayResult(
 data class JniAlert(
     var id: String,
     var report: JniPublicReport,
-    var contactTime: Long
+    var contactTime: Long,
+    var source: String,
+    // 0 -> active, 1 -> revoked, 2 -> amended
+    var status: Int,
+    var verified: Boolean
 )
 
 data class JniPublicReport(
@@ -150,6 +186,9 @@ data class JniPublicReport(
     val noSymptoms: Boolean
 )
 
+// Status returned when the backend rejected the credentials. A new auth token has to be set.
+const val JNI_STATUS_REAUTH_REQUIRED = 6
+
 fun JniVoidResult.asResult(): Result<Unit, Throwable> = when (status) {
     1 -> Success(Unit)
     else -> Failure(Throwable(statusDescription()))
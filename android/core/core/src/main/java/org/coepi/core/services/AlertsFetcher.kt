@@ -4,6 +4,7 @@ import org.coepi.core.jni.JniAlert
 import org.coepi.core.jni.JniAlertsArrayResult
 import org.coepi.core.jni.JniApi
 import org.coepi.core.domain.model.Alert
+import org.coepi.core.domain.model.toAlertStatus
 import org.coepi.core.domain.model.toCoughSeverity
 import org.coepi.core.domain.model.toFeverSeverity
 import org.coepi.core.domain.common.Result
@@ -60,6 +61,9 @@ class AlertsFetcherImpl(private val api: JniApi) :
         diarrhea = report.diarrhea,
         runnyNose = report.runnyNose,
         other = report.other,
-        noSymptoms = report.noSymptoms
+        noSymptoms = report.noSymptoms,
+        source = source,
+        status = toAlertStatus(status),
+        verified = verified
     )
 }
@@ -0,0 +1,14 @@
+package org.coepi.core.services
+
+import org.coepi.core.domain.common.Result
+import org.coepi.core.jni.JniApi
+import org.coepi.core.jni.asResult
+
+interface AuthTokenUpdater {
+    fun setAuthToken(token: String?): Result<Unit, Throwable>
+}
+
+class AuthTokenUpdaterImpl(private val api: JniApi) : AuthTokenUpdater {
+    override fun setAuthToken(token: String?): Result<Unit, Throwable> =
+        api.setAuthToken(token ?: "").asResult()
+}
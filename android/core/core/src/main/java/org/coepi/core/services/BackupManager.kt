@@ -0,0 +1,45 @@
+package org.coepi.core.services
+
+import org.coepi.core.domain.common.Result
+import org.coepi.core.domain.common.Result.Failure
+import org.coepi.core.domain.common.Result.Success
+import org.coepi.core.jni.JniApi
+import org.coepi.core.jni.asResult
+
+interface BackupManager {
+    // Encrypted backup of the keys and observed TCNs (base64), to restore e.g. on a new phone.
+    fun exportBackup(passphrase: String): Result<String, Throwable>
+
+    // Restores a backup created with exportBackup. Reports are matched again with the restored TCNs.
+    fun importBackup(passphrase: String, backup: String): Result<Unit, Throwable>
+
+    // The authorization keys, as a phrase the user can write down to report from a new phone.
+    fun exportRecoveryPhrase(): Result<String, Throwable>
+
+    // Continues with the keys of a phrase created with exportRecoveryPhrase.
+    fun importRecoveryPhrase(phrase: String): Result<Unit, Throwable>
+}
+
+class BackupManagerImpl(private val api: JniApi) : BackupManager {
+    override fun exportBackup(passphrase: String): Result<String, Throwable> {
+        val result = api.exportBackup(passphrase)
+        return when (result.status) {
+            1 -> Success(result.obj)
+            else -> Failure(Throwable("Status: ${result.status} Message: ${result.message}"))
+        }
+    }
+
+    override fun importBackup(passphrase: String, backup: String): Result<Unit, Throwable> =
+        api.importBackup(passphrase, backup).asResult()
+
+    override fun exportRecoveryPhrase(): Result<String, Throwable> {
+        val result = api.exportRecoveryPhrase()
+        return when (result.status) {
+            1 -> Success(result.obj)
+            else -> Failure(Throwable("Status: ${result.status} Message: ${result.message}"))
+        }
+    }
+
+    override fun importRecoveryPhrase(phrase: String): Result<Unit, Throwable> =
+        api.importRecoveryPhrase(phrase).asResult()
+}
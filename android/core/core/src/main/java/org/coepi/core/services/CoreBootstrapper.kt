@@ -5,7 +5,9 @@ import org.coepi.core.jni.JniApi
 import org.coepi.core.jni.JniLogCallback
 
 interface CoreBootstrapper {
-    fun bootstrap(applicationContext: Context, logger: CoreLogger)
+    // Returns JSON with the warnings, e.g. if the database was damaged and had to be replaced:
+    // {"warnings":[{"type":"DatabaseRecovered","problem":"...","salvaged_preferences":2,...}]}
+    fun bootstrap(applicationContext: Context, logger: CoreLogger, config: String = "{}"): String
 }
 
 interface CoreLogger {
@@ -14,18 +16,19 @@ interface CoreLogger {
 
 class CoreBootstrapperImpl(private val api: JniApi) : CoreBootstrapper {
 
-    override fun bootstrap(applicationContext: Context, logger: CoreLogger) {
+    override fun bootstrap(applicationContext: Context, logger: CoreLogger, config: String): String {
         // getDatabasePath requires a db name, but we use need the directory
         // (to initialize multiple databases), so adding and removing a suffix.
         val dbPath = applicationContext.getDatabasePath("remove")
             .absolutePath.removeSuffix("/remove")
 
         val result = api.bootstrapCore(
-            dbPath, "debug", true,
+            dbPath, "debug", true, config,
             JniLogCallback(logger)
         )
         if (result.status != 1) {
             error("Couldn't bootstrap core: status: ${result.status}, message: ${result.message}")
         }
+        return result.obj
     }
 }
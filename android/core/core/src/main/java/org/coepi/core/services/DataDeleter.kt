@@ -0,0 +1,22 @@
+package org.coepi.core.services
+
+import org.coepi.core.domain.common.Result
+import org.coepi.core.domain.common.Result.Failure
+import org.coepi.core.domain.common.Result.Success
+import org.coepi.core.jni.JniApi
+
+interface DataDeleter {
+    // Deletes observed TCNs, keys, fetch progress, sync stats and symptom inputs.
+    // Returns JSON with the deleted rows by table. The core stays usable, with new keys.
+    fun deleteAllData(): Result<String, Throwable>
+}
+
+class DataDeleterImpl(private val api: JniApi) : DataDeleter {
+    override fun deleteAllData(): Result<String, Throwable> {
+        val result = api.deleteAllData()
+        return when (result.status) {
+            1 -> Success(result.obj)
+            else -> Failure(Throwable("Status: ${result.status} Message: ${result.message}"))
+        }
+    }
+}
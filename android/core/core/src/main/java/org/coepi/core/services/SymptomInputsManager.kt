@@ -4,6 +4,8 @@ import com.google.gson.Gson
 import org.coepi.core.jni.JniApi
 import org.coepi.core.jni.asResult
 import org.coepi.core.domain.common.Result
+import org.coepi.core.domain.common.Result.Failure
+import org.coepi.core.domain.common.Result.Success
 import org.coepi.core.domain.model.SymptomId
 import org.coepi.core.domain.model.SymptomId.BREATHLESSNESS
 import org.coepi.core.domain.model.SymptomId.COUGH
@@ -49,6 +51,14 @@ interface SymptomsInputManager {
 
     fun submitSymptoms(): Result<Unit, Throwable>
     fun clearSymptoms(): Result<Unit, Throwable>
+    // JSON with what submitSymptoms would share: the report start and the emission times of the TCNs.
+    fun reportPreview(): Result<String, Throwable>
+    // Withdraws the submitted report, e.g. after a negative test
+    fun revokeReport(): Result<Unit, Throwable>
+    // Replaces the submitted report with the current inputs
+    fun amendReport(): Result<Unit, Throwable>
+    // Verification code of a positive test. The next report is sent as verified.
+    fun verifyCode(code: String): Result<Unit, Throwable>
 }
 
 class SymptomInputsManagerImpl(private val api: JniApi, private val gson: Gson) :
@@ -138,6 +148,20 @@ class SymptomInputsManagerImpl(private val api: JniApi, private val gson: Gson)
 
     override fun clearSymptoms(): Result<Unit, Throwable> = api.clearSymptoms().asResult()
 
+    override fun reportPreview(): Result<String, Throwable> {
+        val result = api.reportPreview()
+        return when (result.status) {
+            1 -> Success(result.obj)
+            else -> Failure(Throwable("Status: ${result.status} Message: ${result.message}"))
+        }
+    }
+
+    override fun revokeReport(): Result<Unit, Throwable> = api.revokeReport().asResult()
+
+    override fun amendReport(): Result<Unit, Throwable> = api.amendReport().asResult()
+
+    override fun verifyCode(code: String): Result<Unit, Throwable> = api.verifyCode(code).asResult()
+
     //endregion
 
     private fun <T : Serializable> UserInput<T>.toJniStringInput(f: (T) -> String): String =
@@ -0,0 +1,21 @@
+package org.coepi.core.services
+
+import org.coepi.core.domain.common.Result
+import org.coepi.core.domain.common.Result.Failure
+import org.coepi.core.domain.common.Result.Success
+import org.coepi.core.jni.JniApi
+
+interface SyncStatsProvider {
+    // JSON with the last successful sync time and the stats of the last syncs, for diagnostics.
+    fun syncStats(): Result<String, Throwable>
+}
+
+class SyncStatsProviderImpl(private val api: JniApi) : SyncStatsProvider {
+    override fun syncStats(): Result<String, Throwable> {
+        val result = api.syncStats()
+        return when (result.status) {
+            1 -> Success(result.obj)
+            else -> Failure(Throwable("Status: ${result.status} Message: ${result.message}"))
+        }
+    }
+}
@@ -1,14 +1,22 @@
 package org.coepi.core.services
 
+import org.coepi.core.domain.common.Result
+import org.coepi.core.domain.common.Result.Failure
+import org.coepi.core.domain.common.Result.Success
 import org.coepi.core.jni.JniApi
 import org.coepi.core.domain.model.Tcn
 import org.coepi.core.extensions.hexToByteArray
 
 interface TcnGenerator {
-    fun generateTcn(): Tcn
+    fun generateTcn(): Result<Tcn, Throwable>
 }
 
 class TcnGeneratorImpl(private val api: JniApi) : TcnGenerator {
-    override fun generateTcn(): Tcn =
-        Tcn(api.generateTcn().hexToByteArray())
+    override fun generateTcn(): Result<Tcn, Throwable> {
+        val result = api.generateTcn()
+        return when (result.status) {
+            1 -> Success(Tcn(result.obj.hexToByteArray()))
+            else -> Failure(Throwable("Status: ${result.status} Message: ${result.message}"))
+        }
+    }
 }
@@ -0,0 +1,121 @@
+use crate::{errors::DaoError, preferences::Database};
+use rusqlite::{params, Row};
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum AlertStatus {
+    Active,
+    // The reporter withdrew the report
+    Revoked,
+    // The reporter replaced the report
+    Amended,
+}
+
+impl AlertStatus {
+    fn to_i64(self) -> i64 {
+        match self {
+            AlertStatus::Active => 0,
+            AlertStatus::Revoked => 1,
+            AlertStatus::Amended => 2,
+        }
+    }
+
+    fn from_i64(value: i64) -> Result<AlertStatus, DaoError> {
+        match value {
+            0 => Ok(AlertStatus::Active),
+            1 => Ok(AlertStatus::Revoked),
+            2 => Ok(AlertStatus::Amended),
+            _ => Err(DaoError::InvalidData(format!(
+                "Invalid alert status: {}",
+                value
+            ))),
+        }
+    }
+}
+
+// An alert sent to the app, with the verification key of the report's authorization key,
+// which identifies the revocations and amendments of the report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredAlert {
+    pub id: String,
+    pub rvk: [u8; 32],
+    pub memo: Vec<u8>,
+    pub contact_time: u64,
+    pub source: String,
+    pub status: AlertStatus,
+}
+
+pub struct AlertDao {
+    db: Arc<Database>,
+}
+
+impl AlertDao {
+    pub fn new(db: Arc<Database>) -> AlertDao {
+        AlertDao { db }
+    }
+
+    // Returns false if there's already an alert with the id
+    pub fn insert(&self, alert: &StoredAlert) -> Result<bool, DaoError> {
+        let inserted = self.db.execute_sql(
+            "insert or ignore into alert(id, rvk, memo, contact_time, source, status)
+            values(?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                alert.id,
+                &alert.rvk[..],
+                alert.memo,
+                alert.contact_time as i64,
+                alert.source,
+                alert.status.to_i64()
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    pub fn update(&self, alert: &StoredAlert) -> Result<(), DaoError> {
+        self.db.execute_sql(
+            "update alert set memo = ?2, status = ?3 where id = ?1",
+            params![alert.id, alert.memo, alert.status.to_i64()],
+        )?;
+        Ok(())
+    }
+
+    pub fn with_rvk(&self, rvk: &[u8; 32]) -> Result<Vec<StoredAlert>, DaoError> {
+        let alerts = self.db.query(
+            "select id, rvk, memo, contact_time, source, status from alert where rvk = ?1",
+            params![&rvk[..]],
+            Self::to_alert,
+        )?;
+        Ok(alerts)
+    }
+
+    // Alerts of contacts older than the reports can cover can't be revoked anymore
+    pub fn delete_contacted_before(&self, time: u64) -> Result<(), DaoError> {
+        self.db.execute_sql(
+            "delete from alert where contact_time < ?1",
+            params![time as i64],
+        )?;
+        Ok(())
+    }
+
+    fn to_alert(row: &Row) -> Result<StoredAlert, DaoError> {
+        let rvk: Vec<u8> = row.get(1)?;
+        if rvk.len() != 32 {
+            return Err(DaoError::InvalidData(format!(
+                "Invalid alert rvk length: {}",
+                rvk.len()
+            )));
+        }
+        let mut rvk_bytes = [0; 32];
+        rvk_bytes.copy_from_slice(&rvk);
+        let contact_time: i64 = row.get(3)?;
+        Ok(StoredAlert {
+            id: row.get(0)?,
+            rvk: rvk_bytes,
+            memo: row.get(2)?,
+            contact_time: contact_time as u64,
+            source: row.get(4)?,
+            status: AlertStatus::from_i64(row.get(5)?)?,
+        })
+    }
+}
@@ -2,7 +2,8 @@ use crate::reporting::symptom_inputs_manager::SymptomInputsProcessor;
 use crate::reports_updater::ObservedTcnProcessor;
 use crate::tcn_ext::tcn_keys::TcnKeys;
 use crate::{
-    composition_root::{bootstrap, dependencies},
+    alerts::AlertStatus,
+    composition_root::{bootstrap, dependencies, CoreConfig},
     errors::ServicesError,
     expect_log,
     reporting::{
@@ -12,10 +13,11 @@ use crate::{
     reports_interval::UnixTime,
     reports_updater::Alert,
     simple_logger,
+    sync_stats::SyncStatsDao,
 };
 use jni::{
     objects::{GlobalRef, JClass, JObject, JString, JValue},
-    sys::{jboolean, jfloat, jint, jobject, jobjectArray, jstring},
+    sys::{jboolean, jfloat, jint, jobject, jobjectArray},
     JNIEnv, JavaVM,
 };
 use log::*;
@@ -34,16 +36,21 @@ pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_bootstrapCore(
     db_path_j_string: JString,
     log_level_j_string: JString,
     log_coepi_only: jboolean,
+    config_j_string: JString,
     log_callback: jobject,
 ) -> jobject {
-    bootstrap_core(
+    // Returns the bootstrap warnings (JSON)
+    jni_string_result(
+        bootstrap_core(
+            &env,
+            db_path_j_string,
+            log_level_j_string,
+            log_coepi_only,
+            config_j_string,
+            log_callback,
+        ),
         &env,
-        db_path_j_string,
-        log_level_j_string,
-        log_coepi_only,
-        log_callback,
     )
-    .to_void_jni(&env)
 }
 
 #[no_mangle]
@@ -84,21 +91,89 @@ pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_recordTcn(
     recordTcn(&env, tcn).to_void_jni(&env)
 }
 
-// NOTE: Returns directly success string
+// Fails if the keys can't be read or stored
 #[no_mangle]
 pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_generateTcn(
     env: JNIEnv,
     _: JClass,
-) -> jstring {
+) -> jobject {
     // Maybe send byte array directly?
-    let tcn_hex = hex::encode(dependencies().tcn_keys.generate_tcn().0);
-    info!("Generated TCN: {:?}", tcn_hex);
+    let result = dependencies()
+        .tcn_keys
+        .generate_tcn()
+        .map(|tcn| hex::encode(tcn.0));
+    info!("Generated TCN: {:?}", result);
+
+    jni_string_result(result, &env)
+}
+
+// Statistics of the last report syncs (fetchNewReports) as JSON, for diagnostics.
+#[no_mangle]
+pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_syncStats(
+    env: JNIEnv,
+    _: JClass,
+) -> jobject {
+    jni_string_result(sync_stats_json(), &env)
+}
+
+// Encrypted backup of the keys and observed TCNs (base64), to restore e.g. on a new phone.
+#[no_mangle]
+pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_exportBackup(
+    env: JNIEnv,
+    _: JClass,
+    passphrase: JString,
+) -> jobject {
+    jni_string_result(export_backup(&env, passphrase), &env)
+}
 
-    let output_res = env.new_string(tcn_hex);
-    // Unclear about when new_string can return Error (TODO), and there's no meaningful handling in the app, so for now crash
-    let output = expect_log!(output_res, "Couldn't create java string");
+// Restores a backup created with exportBackup. Reports are matched again with the restored TCNs.
+#[no_mangle]
+pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_importBackup(
+    env: JNIEnv,
+    _: JClass,
+    passphrase: JString,
+    backup: JString,
+) -> jobject {
+    import_backup(&env, passphrase, backup).to_void_jni(&env)
+}
 
-    output.into_inner()
+// The authorization keys, as a phrase the user can write down to report from a new phone.
+#[no_mangle]
+pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_exportRecoveryPhrase(
+    env: JNIEnv,
+    _: JClass,
+) -> jobject {
+    jni_string_result(dependencies().tcn_keys.export_recovery_phrase(), &env)
+}
+
+// Continues with the keys of a phrase created with exportRecoveryPhrase.
+#[no_mangle]
+pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_importRecoveryPhrase(
+    env: JNIEnv,
+    _: JClass,
+    phrase: JString,
+) -> jobject {
+    import_recovery_phrase(&env, phrase).to_void_jni(&env)
+}
+
+// Deletes observed TCNs, keys, fetch progress, sync stats and symptom inputs.
+// Returns the deleted rows by table (JSON). The core stays bootstrapped, with new keys.
+#[no_mangle]
+pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_deleteAllData(
+    env: JNIEnv,
+    _: JClass,
+) -> jobject {
+    jni_string_result(delete_all_data_json(), &env)
+}
+
+// Sets or replaces the token sent with every request. Empty string clears it.
+#[no_mangle]
+pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_setAuthToken(
+    env: JNIEnv,
+    _: JClass,
+    token: JString,
+) -> jobject {
+    set_auth_token(&env, token).to_void_jni(&env)
 }
 
 #[no_mangle]
@@ -235,23 +310,70 @@ pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_submitSymptoms(
         .to_void_jni(&env)
 }
 
+// Withdraws the submitted report. The contacts' alerts are marked as revoked.
+#[no_mangle]
+pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_revokeReport(
+    env: JNIEnv,
+    _: JClass,
+) -> jobject {
+    dependencies()
+        .symptom_inputs_processor
+        .revoke()
+        .to_void_jni(&env)
+}
+
+// Replaces the submitted report with the current symptom inputs.
+#[no_mangle]
+pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_amendReport(
+    env: JNIEnv,
+    _: JClass,
+) -> jobject {
+    dependencies()
+        .symptom_inputs_processor
+        .amend()
+        .to_void_jni(&env)
+}
+
+// Verification code of a positive test. The next report is sent as verified.
+#[no_mangle]
+pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_verifyCode(
+    env: JNIEnv,
+    _: JClass,
+    code: JString,
+) -> jobject {
+    verify_code(&env, code).to_void_jni(&env)
+}
+
+// What submitting the current symptom inputs would share (JSON), without sending anything.
+#[no_mangle]
+pub unsafe extern "C" fn Java_org_coepi_core_jni_JniApi_reportPreview(
+    env: JNIEnv,
+    _: JClass,
+) -> jobject {
+    jni_string_result(report_preview_json(), &env)
+}
+
 fn bootstrap_core(
     env: &JNIEnv,
     db_path_j_string: JString,
     log_level_j_string: JString,
     log_coepi_only: jboolean,
+    config_j_string: JString,
     log_callback: jobject,
-) -> Result<(), ServicesError> {
+) -> Result<String, ServicesError> {
     init_log(&env, log_level_j_string, log_coepi_only, log_callback);
 
     let db_path_java_str = env.get_string(db_path_j_string)?;
     let db_path_str = db_path_java_str.to_str()?;
 
+    let config_java_str = env.get_string(config_j_string)?;
+    let config = CoreConfig::from_json(config_java_str.to_str()?)?;
+
     info!("Bootstrapping with db path: {:?}", db_path_str);
-    let db_result = bootstrap(db_path_str)?;
-    info!("Bootstrapping result: {:?}", db_result);
+    let result = bootstrap(db_path_str, config)?;
+    info!("Bootstrapping result: {:?}", result);
 
-    Ok(())
+    serde_json::to_string(&result).map_err(ServicesError::from)
 }
 
 fn fetch_new_reports(env: &JNIEnv) -> Result<jobjectArray, ServicesError> {
@@ -272,6 +394,60 @@ fn recordTcn(env: &JNIEnv, tcn: JString) -> Result<(), ServicesError> {
     result
 }
 
+fn set_auth_token(env: &JNIEnv, token: JString) -> Result<(), ServicesError> {
+    let java_str = env.get_string(token)?;
+    let token_str = java_str.to_str()?;
+
+    let token = match token_str {
+        "" => None,
+        _ => Some(token_str.to_owned()),
+    };
+    dependencies().api.set_auth_token(token);
+    Ok(())
+}
+
+fn sync_stats_json() -> Result<String, ServicesError> {
+    let report = dependencies().sync_stats_dao.report()?;
+    serde_json::to_string(&report).map_err(ServicesError::from)
+}
+
+fn report_preview_json() -> Result<String, ServicesError> {
+    let preview = dependencies().symptom_inputs_processor.preview()?;
+    serde_json::to_string(&preview).map_err(ServicesError::from)
+}
+
+fn delete_all_data_json() -> Result<String, ServicesError> {
+    let report = dependencies()
+        .data_eraser
+        .delete_all_data(|| dependencies().symptom_inputs_processor.clear())?;
+    serde_json::to_string(&report).map_err(ServicesError::from)
+}
+
+fn export_backup(env: &JNIEnv, passphrase: JString) -> Result<String, ServicesError> {
+    let passphrase_java_str = env.get_string(passphrase)?;
+    let passphrase_str = passphrase_java_str.to_str()?;
+
+    dependencies().backup.export_backup(passphrase_str)
+}
+
+fn import_backup(env: &JNIEnv, passphrase: JString, backup: JString) -> Result<(), ServicesError> {
+    let passphrase_java_str = env.get_string(passphrase)?;
+    let passphrase_str = passphrase_java_str.to_str()?;
+    let backup_java_str = env.get_string(backup)?;
+    let backup_str = backup_java_str.to_str()?;
+
+    dependencies()
+        .backup
+        .import_backup(passphrase_str, backup_str)
+}
+
+fn import_recovery_phrase(env: &JNIEnv, phrase: JString) -> Result<(), ServicesError> {
+    let phrase_java_str = env.get_string(phrase)?;
+    let phrase_str = phrase_java_str.to_str()?;
+
+    dependencies().tcn_keys.import_recovery_phrase(phrase_str)
+}
+
 fn set_symptom_ids(env: &JNIEnv, ids: JString) -> Result<(), ServicesError> {
     let java_str = env.get_string(ids)?;
     let ids_str = java_str.to_str()?;
@@ -303,6 +479,13 @@ fn set_cough_status(env: &JNIEnv, cough_status: JString) -> Result<(), ServicesE
         .set_cough_status(str)
 }
 
+fn verify_code(env: &JNIEnv, code: JString) -> Result<(), ServicesError> {
+    let java_str = env.get_string(code)?;
+    let str = java_str.to_str()?;
+
+    dependencies().symptom_inputs_processor.verify_code(str)
+}
+
 fn set_breathlessness_cause(env: &JNIEnv, cause: JString) -> Result<(), ServicesError> {
     let java_str = env.get_string(cause)?;
     let str = java_str.to_str()?;
@@ -428,6 +611,29 @@ pub fn jni_void_result(status: i32, message: Option<&str>, env: &JNIEnv) -> jobj
     expect_log!(res, "Couldn't create JNI result object").into_inner()
 }
 
+fn jni_string_result(result: Result<String, ServicesError>, env: &JNIEnv) -> jobject {
+    let (status, message, string) = match result {
+        Ok(string) => (1, None, string),
+        Err(e) => {
+            let jni_error = e.to_jni_error();
+            (jni_error.status, Some(jni_error.message), "".to_owned())
+        }
+    };
+
+    let j_string_res = env.new_string(string);
+    // If we can't create a result to send to JNI, we only can crash
+    let j_string = expect_log!(j_string_res, "Couldn't create JNI string");
+
+    jni_obj_result(
+        status,
+        message.as_deref(),
+        JObject::from(j_string),
+        "org/coepi/core/jni/JniStringResult",
+        "Ljava/lang/String;",
+        env,
+    )
+}
+
 pub fn jni_obj_result(
     status: i32,
     message: Option<&str>,
@@ -567,6 +773,9 @@ fn placeholder_alert() -> Alert {
         id: "0".to_owned(),
         report,
         contact_time: 0,
+        source: "".to_owned(),
+        status: AlertStatus::Active,
+        verified: false,
     }
 }
 
@@ -629,14 +838,29 @@ pub fn alert_to_jobject(alert: Alert, env: &JNIEnv) -> Result<jobject, ServicesE
 
     let earliest_time_j_value = JValue::from(alert.contact_time as i64);
 
+    let source_j_string = env.new_string(alert.source)?;
+    let source_j_value = JValue::from(JObject::from(source_j_string));
+
+    let status = match alert.status {
+        AlertStatus::Active => 0,
+        AlertStatus::Revoked => 1,
+        AlertStatus::Amended => 2,
+    };
+    let status_j_value = JValue::from(status);
+
+    let verified_j_value = JValue::from(alert.verified);
+
     let result: Result<jobject, jni::errors::Error> = env
         .new_object(
             jni_alert_class,
-            "(Ljava/lang/String;Lorg/coepi/core/jni/JniPublicReport;J)V",
+            "(Ljava/lang/String;Lorg/coepi/core/jni/JniPublicReport;JLjava/lang/String;IZ)V",
             &[
                 id_j_value,
                 JValue::from(jni_public_report_obj),
                 earliest_time_j_value,
+                source_j_value,
+                status_j_value,
+                verified_j_value,
             ],
         )
         .map(|o| o.into_inner());
@@ -682,6 +906,10 @@ impl JniErrorMappable for ServicesError {
                 status: 5,
                 message: msg.to_owned(),
             },
+            ServicesError::ReauthRequired(networking_error) => JniError {
+                status: 6,
+                message: format!("{:?}", networking_error),
+            },
         }
     }
 }
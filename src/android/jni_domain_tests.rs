@@ -1,6 +1,8 @@
 use super::android_interface::{alert_to_jobject, jni_obj_result};
 use crate::{
+    alerts::AlertStatus,
     expect_log,
+    networking::DEFAULT_REPORTS_SOURCE_ID,
     reporting::{
         public_report::{CoughSeverity, FeverSeverity, PublicReport},
         symptom_inputs::UserInput,
@@ -82,5 +84,8 @@ fn create_test_alert(id: &str, report_time: u64) -> Alert {
         id: id.to_owned(),
         report,
         contact_time: 1592567315,
+        source: DEFAULT_REPORTS_SOURCE_ID.to_owned(),
+        status: AlertStatus::Active,
+        verified: false,
     }
 }
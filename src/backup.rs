@@ -0,0 +1,469 @@
+use crate::{
+    errors::{DaoError, ServicesError},
+    networking::ReportsSourceConfig,
+    preference_key::{self, PreferenceKey},
+    preferences::{
+        Database, Preferences, PreferencesImpl, AUTHORIZATION_KEY, AUTHORIZATION_KEY_CREATED,
+        LAST_COMPLETED_REPORTS_INTERVAL, RETIRED_AUTHORIZATION_KEYS, TCK, VERIFICATION_TOKEN,
+    },
+    reports_interval::{ReportsInterval, UnixTime},
+    reports_updater::{ObservedTcn, TcnDao},
+    tcn_ext::{
+        recovery_phrase::KeySet,
+        tcn_keys::{TcnKeys, TCN_PERIOD_SECS},
+    },
+};
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hmac::Hmac;
+use log::*;
+use rand::{thread_rng, RngCore};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{collections::HashSet, convert::TryInto, io::Cursor, sync::Arc};
+use tcn::{TemporaryContactKey, TemporaryContactNumber};
+
+// Identifies backups, in case the apps pass something else
+const MAGIC: &[u8; 4] = b"CEBK";
+// Version 1: PBKDF2-HMAC-SHA256 (PBKDF2_ROUNDS) + ChaCha20Poly1305, contents as JSON
+const BACKUP_FORMAT_VERSION: u8 = 1;
+const PBKDF2_ROUNDS: u32 = 100_000;
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const HEADER_SIZE: usize = MAGIC.len() + 1 + SALT_SIZE + NONCE_SIZE;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct BackupContents {
+    created: u64,
+    // Secrets (RAK, TCK) decrypted, so the backup can be restored with another key-encryption key
+    preferences: Vec<(String, String)>,
+    tcns: Vec<BackupTcn>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct BackupTcn {
+    tcn: String,
+    time: u64,
+}
+
+// Exports and restores the keys and observed TCNs, e.g. to move them to a new phone.
+pub struct BackupManager<T: TcnDao, U: TcnKeys> {
+    pub preferences: Arc<PreferencesImpl>,
+    pub tcn_dao: Arc<T>,
+    // The keys are imported with these, as a recovery phrase
+    pub tcn_keys: Arc<U>,
+    pub report_sources: Vec<ReportsSourceConfig>,
+    // Backups are exported and imported in a transaction, so they're consistent
+    pub database: Arc<Database>,
+}
+
+impl<T: TcnDao, U: TcnKeys> BackupManager<T, U> {
+    // Returns the encrypted backup, base64.
+    pub fn export_backup(&self, passphrase: &str) -> Result<String, ServicesError> {
+        // The keys and the TCNs of the same moment
+        let contents = self
+            .tcn_keys
+            .with_keys_locked(|| self.database.transaction(|_| self.backup_contents()))?;
+        let json = serde_json::to_vec(&contents)?;
+        Ok(base64::encode(encrypt(passphrase, &json)?))
+    }
+
+    fn backup_contents(&self) -> Result<BackupContents, ServicesError> {
+        let preferences = self
+            .preferences
+            .export_entries()?
+            .into_iter()
+            // Progress of this device: restored devices have their own
+            .filter(|(key, _)| !is_fetch_progress(key))
+            // Used once, by this device
+            .filter(|(key, _)| key != VERIFICATION_TOKEN.name.as_ref())
+            .collect();
+
+        let tcns = self
+            .tcn_dao
+            .all()?
+            .into_iter()
+            .map(|observed| BackupTcn {
+                tcn: hex::encode(observed.tcn.0),
+                time: observed.time.value,
+            })
+            .collect();
+
+        Ok(BackupContents {
+            created: UnixTime::now().value,
+            preferences,
+            tcns,
+        })
+    }
+
+    // Continues with the keys in the backup and adds its TCNs to the observed ones. The current
+    // keys are retired, as when importing a recovery phrase. Reports are fetched again starting
+    // at the oldest restored TCN, to match them.
+    pub fn import_backup(&self, passphrase: &str, backup: &str) -> Result<(), ServicesError> {
+        let bytes = base64::decode(backup.trim())?;
+        let json = decrypt(passphrase, &bytes)?;
+        let contents: BackupContents = serde_json::from_slice(&json)?;
+        info!(
+            "Importing backup created at: {}, preferences: {}, tcns: {}",
+            contents.created,
+            contents.preferences.len(),
+            contents.tcns.len()
+        );
+
+        let tcns = contents
+            .tcns
+            .iter()
+            .map(|tcn| {
+                let bytes: [u8; 16] = hex::decode(&tcn.tcn)?
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| ServicesError::General(format!("Invalid TCN: {}", tcn.tcn)))?;
+                Ok(ObservedTcn {
+                    tcn: TemporaryContactNumber(bytes),
+                    time: UnixTime { value: tcn.time },
+                })
+            })
+            .collect::<Result<Vec<ObservedTcn>, ServicesError>>()?;
+
+        // All or nothing. The keys are locked first, as when they're used.
+        self.tcn_keys.with_keys_locked(|| {
+            self.database
+                .transaction(|_| self.import_contents(contents, &tcns))
+        })
+    }
+
+    fn import_contents(
+        &self,
+        contents: BackupContents,
+        tcns: &[ObservedTcn],
+    ) -> Result<(), ServicesError> {
+        if let Some(key_set) = key_set(&contents.preferences, contents.created)? {
+            self.tcn_keys.import_keys(key_set)?;
+        }
+        let preferences: Vec<(String, String)> = contents
+            .preferences
+            .into_iter()
+            .filter(|(key, _)| !is_fetch_progress(key) && !is_key(key))
+            .collect();
+        self.preferences.import_entries(&preferences)?;
+
+        let existing: HashSet<([u8; 16], u64)> = self
+            .tcn_dao
+            .all()?
+            .into_iter()
+            .map(|observed| (observed.tcn.0, observed.time.value))
+            .collect();
+        for tcn in tcns {
+            if !existing.contains(&(tcn.tcn.0, tcn.time.value)) {
+                self.tcn_dao.save(tcn)?;
+            }
+        }
+
+        if let Some(oldest) = tcns.iter().map(|tcn| tcn.time.value).min() {
+            self.rematch_from(UnixTime { value: oldest })?;
+        }
+        Ok(())
+    }
+
+    // Moves the fetch progress back, so the reports since time are downloaded and matched again.
+    fn rematch_from(&self, time: UnixTime) -> Result<(), DaoError> {
+        for source in &self.report_sources {
+            let start = ReportsInterval::create_for(&time, source.interval_length);
+            let last_completed = ReportsInterval {
+                number: start.number.saturating_sub(1),
+                length: start.length,
+            };
+            match self
+                .preferences
+                .last_completed_reports_interval(&source.id)?
+            {
+                Some(current)
+                    if current.length == last_completed.length
+                        && current.number <= last_completed.number => {}
+                _ => {
+                    debug!(
+                        "Rematching source: {} from interval: {:?}",
+                        source.id, start
+                    );
+                    self.preferences
+                        .set_last_completed_reports_interval(&source.id, last_completed)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Includes the keys of all the sources
+fn is_fetch_progress(key: &str) -> bool {
+    key.starts_with(LAST_COMPLETED_REPORTS_INTERVAL.name.as_ref())
+}
+
+// Imported with TcnKeys
+fn is_key(key: &str) -> bool {
+    [
+        &AUTHORIZATION_KEY.name,
+        &TCK.name,
+        &AUTHORIZATION_KEY_CREATED.name,
+        &RETIRED_AUTHORIZATION_KEYS.name,
+    ]
+    .iter()
+    .any(|name| *name == key)
+}
+
+// The backup's keys. None if it has no authorization key (none was created yet).
+// Keys created before the creation time was stored are aligned to the backup's creation,
+// as the current TCK is the one of then.
+fn key_set(
+    entries: &[(String, String)],
+    backup_created: u64,
+) -> Result<Option<KeySet>, ServicesError> {
+    let key = match entry(entries, &AUTHORIZATION_KEY)?.flatten() {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let tck_index = match entry(entries, &TCK)?.flatten() {
+        Some(tck) => TemporaryContactKey::read(Cursor::new(&tck))
+            .map_err(|error| ServicesError::General(format!("Invalid TCK: {:?}", error)))?
+            .index(),
+        None => 1,
+    };
+    let created = entry(entries, &AUTHORIZATION_KEY_CREATED)?
+        .flatten()
+        .unwrap_or_else(|| backup_created.saturating_sub((tck_index as u64 - 1) * TCN_PERIOD_SECS));
+    let retired = entry(entries, &RETIRED_AUTHORIZATION_KEYS)?.unwrap_or_default();
+    Ok(Some(KeySet {
+        key,
+        created,
+        tck_index,
+        retired,
+    }))
+}
+
+// Fails if the entry can't be decoded: the keys would be lost
+fn entry<T: DeserializeOwned>(
+    entries: &[(String, String)],
+    key: &PreferenceKey<T>,
+) -> Result<Option<T>, ServicesError> {
+    match entries.iter().find(|(name, _)| *name == key.name) {
+        Some((_, value)) => preference_key::decode(key, value)
+            .map(Some)
+            .ok_or_else(|| ServicesError::General(format!("Invalid backup entry: {}", key.name))),
+        None => Ok(None),
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> ChaCha20Poly1305 {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+// Format: magic || version || salt || nonce || ciphertext. The header is authenticated too.
+fn encrypt(passphrase: &str, plaintext: &[u8]) -> Result<Vec<u8>, ServicesError> {
+    let mut salt = [0u8; SALT_SIZE];
+    let mut nonce = [0u8; NONCE_SIZE];
+    thread_rng().fill_bytes(&mut salt);
+    thread_rng().fill_bytes(&mut nonce);
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(BACKUP_FORMAT_VERSION);
+    bytes.extend_from_slice(&salt);
+    bytes.extend_from_slice(&nonce);
+
+    let ciphertext = derive_key(passphrase, &salt)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &bytes,
+            },
+        )
+        .map_err(|_| ServicesError::General("Couldn't encrypt backup".to_owned()))?;
+    bytes.extend(ciphertext);
+    Ok(bytes)
+}
+
+fn decrypt(passphrase: &str, bytes: &[u8]) -> Result<Vec<u8>, ServicesError> {
+    if bytes.len() < HEADER_SIZE || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(ServicesError::General("Not a backup".to_owned()));
+    }
+    let version = bytes[MAGIC.len()];
+    if version != BACKUP_FORMAT_VERSION {
+        return Err(ServicesError::General(format!(
+            "Unsupported backup version: {}",
+            version
+        )));
+    }
+    let (header, ciphertext) = bytes.split_at(HEADER_SIZE);
+    let salt = &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_SIZE];
+    let nonce = &header[MAGIC.len() + 1 + SALT_SIZE..];
+
+    derive_key(passphrase, salt)
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        // Also if the backup was modified
+        .map_err(|_| {
+            ServicesError::General("Couldn't decrypt backup. Wrong passphrase?".to_owned())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encryption::tests::test_cipher,
+        migrations::tests::test_database,
+        networking::DEFAULT_REPORTS_SOURCE_ID,
+        preferences::{PreferencesDao, PreferencesImpl},
+        reports_updater::TcnDaoImpl,
+        tcn_ext::tcn_keys::{tests::test_tcn_keys, TcnKeysImpl},
+    };
+
+    fn source() -> ReportsSourceConfig {
+        ReportsSourceConfig {
+            id: DEFAULT_REPORTS_SOURCE_ID.to_owned(),
+            base_url: "https://example.com".to_owned(),
+            interval_length: 100,
+        }
+    }
+
+    fn create_manager(
+        with_cipher: bool,
+    ) -> BackupManager<TcnDaoImpl, TcnKeysImpl<PreferencesImpl>> {
+        let database = test_database();
+        let cipher = if with_cipher {
+            Some(test_cipher())
+        } else {
+            None
+        };
+        let preferences = Arc::new(PreferencesImpl::new(
+            PreferencesDao::new(database.clone()),
+            cipher,
+        ));
+        BackupManager {
+            preferences: preferences.clone(),
+            tcn_dao: Arc::new(TcnDaoImpl::new(database.clone())),
+            tcn_keys: Arc::new(test_tcn_keys(preferences, database.clone())),
+            report_sources: vec![source()],
+            database,
+        }
+    }
+
+    fn observed_tcn(byte: u8, time: u64) -> ObservedTcn {
+        ObservedTcn {
+            tcn: TemporaryContactNumber([byte; 16]),
+            time: UnixTime { value: time },
+        }
+    }
+
+    #[test]
+    fn restores_keys_and_tcns() {
+        let old_device = create_manager(true);
+        old_device
+            .preferences
+            .set_autorization_key([1; 32])
+            .unwrap();
+        old_device.tcn_dao.save(&observed_tcn(1, 1050)).unwrap();
+        old_device.tcn_dao.save(&observed_tcn(2, 1230)).unwrap();
+        old_device
+            .preferences
+            .set_last_completed_reports_interval(
+                DEFAULT_REPORTS_SOURCE_ID,
+                ReportsInterval {
+                    number: 20,
+                    length: 100,
+                },
+            )
+            .unwrap();
+
+        let backup = old_device.export_backup("passphrase").unwrap();
+
+        let new_device = create_manager(false);
+        new_device.tcn_dao.save(&observed_tcn(3, 1500)).unwrap();
+        new_device.tcn_keys.generate_tcn().unwrap();
+        let new_device_key = new_device.preferences.authorization_key().unwrap().unwrap();
+        new_device.import_backup("passphrase", &backup).unwrap();
+        // Importing twice doesn't duplicate the TCNs
+        new_device.import_backup("passphrase", &backup).unwrap();
+
+        assert_eq!(
+            new_device.preferences.authorization_key().unwrap(),
+            Some([1; 32])
+        );
+        // The new device's key is retired, so its TCNs can still be reported
+        let retired = new_device.preferences.retired_authorization_keys().unwrap();
+        assert_eq!(retired.len(), 1);
+        assert_eq!(retired[0].key, new_device_key);
+        let mut tcns = new_device.tcn_dao.all().unwrap();
+        tcns.sort_by_key(|tcn| tcn.time.value);
+        assert_eq!(
+            tcns,
+            vec![
+                observed_tcn(1, 1050),
+                observed_tcn(2, 1230),
+                observed_tcn(3, 1500)
+            ]
+        );
+        // Fetches again starting at the interval of the oldest TCN
+        assert_eq!(
+            new_device
+                .preferences
+                .last_completed_reports_interval(DEFAULT_REPORTS_SOURCE_ID)
+                .unwrap(),
+            Some(ReportsInterval {
+                number: 9,
+                length: 100
+            })
+        );
+    }
+
+    #[test]
+    fn fails_with_wrong_passphrase() {
+        let manager = create_manager(false);
+        manager.preferences.set_autorization_key([1; 32]).unwrap();
+        let backup = manager.export_backup("passphrase").unwrap();
+
+        assert!(manager.import_backup("wrong", &backup).is_err());
+    }
+
+    #[test]
+    fn fails_with_invalid_tcn() {
+        let manager = create_manager(false);
+        let contents = BackupContents {
+            created: 1000,
+            preferences: vec![],
+            tcns: vec![BackupTcn {
+                tcn: "abcd".to_owned(),
+                time: 1000,
+            }],
+        };
+        let bytes = encrypt("passphrase", &serde_json::to_vec(&contents).unwrap()).unwrap();
+
+        assert!(manager
+            .import_backup("passphrase", &base64::encode(bytes))
+            .is_err());
+        assert!(manager.tcn_dao.all().unwrap().is_empty());
+    }
+
+    #[test]
+    fn fails_with_tampered_backup() {
+        let manager = create_manager(false);
+        let mut bytes = base64::decode(manager.export_backup("passphrase").unwrap()).unwrap();
+        // Header is authenticated too
+        bytes[MAGIC.len() + 1] ^= 1;
+
+        assert!(manager
+            .import_backup("passphrase", &base64::encode(bytes))
+            .is_err());
+        assert!(manager.import_backup("passphrase", "abcd").is_err());
+    }
+}
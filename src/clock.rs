@@ -0,0 +1,44 @@
+use crate::reports_interval::UnixTime;
+
+// Source of the current time, so time dependent logic can be tested.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> UnixTime;
+}
+
+pub struct SystemClock {}
+
+impl Clock for SystemClock {
+    fn now(&self) -> UnixTime {
+        UnixTime::now()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    pub struct FakeClock {
+        time: AtomicU64,
+    }
+
+    impl FakeClock {
+        pub fn new(time: u64) -> FakeClock {
+            FakeClock {
+                time: AtomicU64::new(time),
+            }
+        }
+
+        pub fn advance(&self, secs: u64) {
+            self.time.fetch_add(secs, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> UnixTime {
+            UnixTime {
+                value: self.time.load(Ordering::SeqCst),
+            }
+        }
+    }
+}
@@ -1,29 +1,69 @@
-use crate::networking::{TcnApi, TcnApiImpl};
+use crate::cover_traffic::{CoverTrafficApi, CoverTrafficConfig, ReportQueueDao};
+use crate::networking::{NetworkingConfig, TcnApi, TcnApiImpl};
 use crate::reports_updater::{
-    ObservedTcnProcessor, ObservedTcnProcessorImpl, ReportsUpdater, TcnDao, TcnDaoImpl, TcnMatcher,
-    TcnMatcherRayon,
+    ObservedTcnProcessor, ObservedTcnProcessorImpl, ReportsSource, ReportsUpdater, TcnDao,
+    TcnDaoImpl, TcnMatcher, TcnMatcherRayon,
 };
 use crate::{
+    alerts::AlertDao,
+    backup::BackupManager,
+    clock::SystemClock,
+    data_deletion::DataEraser,
+    database_recovery::{open_database, BootstrapWarning},
+    encryption::SecretCipher,
     errors::ServicesError,
     expect_log,
-    preferences::{Database, Preferences, PreferencesDao, PreferencesImpl},
+    preferences::{Preferences, PreferencesDao, PreferencesImpl},
     reporting::{
         memo::{MemoMapper, MemoMapperImpl},
-        symptom_inputs::{SymptomInputs, SymptomInputsSubmitterImpl},
+        symptom_inputs::{ReportingConfig, SymptomInputs, SymptomInputsSubmitterImpl},
         symptom_inputs_manager::{
             SymptomInputsManagerImpl, SymptomInputsProcessor, SymptomInputsProcessorImpl,
         },
     },
-    tcn_ext::tcn_keys::{TcnKeys, TcnKeysImpl},
+    sync_stats::{SyncStatsDao, SyncStatsDaoImpl},
+    tcn_ext::{
+        emitted_tcns::EmittedTcnDao,
+        key_randomness::key_randomness,
+        tcn_keys::{KeysConfig, TcnKeys, TcnKeysImpl},
+    },
+    verification::{fake_verification_api, VerificationApi, VerificationConfig, Verifier},
 };
 use log::*;
 use once_cell::sync::OnceCell;
 use parking_lot::RwLock;
-use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+// Connections for reads, in addition to the one for writes
+const READER_CONNECTIONS: usize = 3;
+
+// Configuration passed by the apps at bootstrap, as JSON. Missing fields use defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CoreConfig {
+    pub networking: NetworkingConfig,
+    pub cover_traffic: CoverTrafficConfig,
+    pub keys: KeysConfig,
+    pub reporting: ReportingConfig,
+    pub verification: VerificationConfig,
+    // Hex, 32 bytes. Encrypts the keys stored in the database. Should be kept in the Keychain / Keystore.
+    pub key_encryption_key: Option<String>,
+    // Hex, 32 bytes. Encrypts the whole database. Requires the "sqlcipher" feature.
+    pub database_key: Option<String>,
+}
+
+impl CoreConfig {
+    pub fn from_json(json: &str) -> Result<CoreConfig, ServicesError> {
+        if json.trim().is_empty() {
+            return Ok(CoreConfig::default());
+        }
+        serde_json::from_str(json).map_err(ServicesError::from)
+    }
+}
+
 #[allow(dead_code)]
-pub struct CompositionRoot<'a, A, B, C, D, F, G, H, I>
+pub struct CompositionRoot<'a, A, B, C, D, F, G, H, I, J>
 where
     A: Preferences,
     B: TcnDao,
@@ -33,12 +73,18 @@ where
     G: ObservedTcnProcessor,
     H: MemoMapper,
     I: TcnKeys,
+    J: SyncStatsDao,
 {
-    pub api: &'a D,
-    pub reports_updater: ReportsUpdater<'a, A, B, C, D, H>,
+    pub api: Arc<D>,
+    // Reports have to be posted with this, to be mixed with cover traffic (if enabled)
+    pub report_uploader: Arc<CoverTrafficApi<D>>,
+    pub reports_updater: ReportsUpdater<'a, A, B, C, D, H, J>,
     pub symptom_inputs_processor: F,
     pub observed_tcn_processor: G,
     pub tcn_keys: Arc<I>,
+    pub sync_stats_dao: Arc<J>,
+    pub backup: BackupManager<B, I>,
+    pub data_eraser: DataEraser<I>,
 }
 
 pub static COMP_ROOT: OnceCell<
@@ -52,29 +98,42 @@ pub static COMP_ROOT: OnceCell<
                 SymptomInputsSubmitterImpl<
                     MemoMapperImpl,
                     TcnKeysImpl<PreferencesImpl>,
-                    TcnApiImpl,
+                    CoverTrafficApi<TcnApiImpl>,
+                    PreferencesImpl,
                 >,
             >,
         >,
         ObservedTcnProcessorImpl<TcnDaoImpl>,
         MemoMapperImpl,
         TcnKeysImpl<PreferencesImpl>,
+        SyncStatsDaoImpl,
     >,
 > = OnceCell::new();
 
-pub fn bootstrap(db_path: &str) -> Result<(), ServicesError> {
+// Returned to the apps by bootstrap
+#[derive(Debug, Serialize)]
+pub struct BootstrapResult {
+    // Problems the core recovered from, e.g. a damaged database. The apps may inform the user.
+    pub warnings: Vec<BootstrapWarning>,
+}
+
+pub fn bootstrap(db_path: &str, config: CoreConfig) -> Result<BootstrapResult, ServicesError> {
     info!("Bootstrapping with db path: {:?}", db_path);
 
     let sqlite_path = format!("{}/db.sqlite", db_path);
     debug!("Sqlite path: {:?}", sqlite_path);
 
-    if let Err(_) = COMP_ROOT.set(create_comp_root(sqlite_path.as_ref())) {
+    let (comp_root, warnings) = create_comp_root(sqlite_path.as_ref(), config)?;
+
+    if let Err(_) = COMP_ROOT.set(comp_root) {
         return Err(ServicesError::General(
             "Couldn't initialize dependencies".to_owned(),
         ));
     };
+    // Only now: the uploads of a failed or repeated bootstrap would run alongside these
+    dependencies().report_uploader.clone().start();
 
-    Ok(())
+    Ok(BootstrapResult { warnings })
 }
 
 pub fn dependencies() -> &'static CompositionRoot<
@@ -89,13 +148,15 @@ pub fn dependencies() -> &'static CompositionRoot<
                 'static,
                 MemoMapperImpl,
                 TcnKeysImpl<PreferencesImpl>,
-                TcnApiImpl,
+                CoverTrafficApi<TcnApiImpl>,
+                PreferencesImpl,
             >,
         >,
     >,
     ObservedTcnProcessorImpl<TcnDaoImpl>,
     MemoMapperImpl,
     TcnKeysImpl<PreferencesImpl>,
+    SyncStatsDaoImpl,
 > {
     let res = COMP_ROOT
         .get()
@@ -108,59 +169,118 @@ pub fn dependencies() -> &'static CompositionRoot<
 
 fn create_comp_root(
     sqlite_path: &str,
-) -> CompositionRoot<
-    'static,
-    PreferencesImpl,
-    TcnDaoImpl,
-    TcnMatcherRayon,
-    TcnApiImpl,
-    SymptomInputsProcessorImpl<
-        SymptomInputsManagerImpl<
-            SymptomInputsSubmitterImpl<
-                'static,
-                MemoMapperImpl,
-                TcnKeysImpl<PreferencesImpl>,
-                TcnApiImpl,
+    config: CoreConfig,
+) -> Result<
+    (
+        CompositionRoot<
+            'static,
+            PreferencesImpl,
+            TcnDaoImpl,
+            TcnMatcherRayon,
+            TcnApiImpl,
+            SymptomInputsProcessorImpl<
+                SymptomInputsManagerImpl<
+                    SymptomInputsSubmitterImpl<
+                        'static,
+                        MemoMapperImpl,
+                        TcnKeysImpl<PreferencesImpl>,
+                        CoverTrafficApi<TcnApiImpl>,
+                        PreferencesImpl,
+                    >,
+                >,
             >,
+            ObservedTcnProcessorImpl<TcnDaoImpl>,
+            MemoMapperImpl,
+            TcnKeysImpl<PreferencesImpl>,
+            SyncStatsDaoImpl,
         >,
-    >,
-    ObservedTcnProcessorImpl<TcnDaoImpl>,
-    MemoMapperImpl,
-    TcnKeysImpl<PreferencesImpl>,
+        Vec<BootstrapWarning>,
+    ),
+    ServicesError,
 > {
-    let api = &TcnApiImpl {};
+    let (database, warnings) = open_database(
+        sqlite_path,
+        config.database_key.as_deref(),
+        READER_CONNECTIONS,
+    )?;
 
-    let connection_res = Connection::open(sqlite_path);
-    let connection = expect_log!(connection_res, "Couldn't create database!");
-    let database = Arc::new(Database::new(connection));
+    let api = Arc::new(TcnApiImpl::new(&config.networking));
+    let report_uploader = Arc::new(CoverTrafficApi::new(
+        api.clone(),
+        &config.cover_traffic,
+        config.keys.max_report_keys(),
+        Arc::new(ReportQueueDao::new(database.clone())),
+    )?);
 
     let preferences_dao = PreferencesDao::new(database.clone());
-    let preferences = Arc::new(PreferencesImpl {
-        dao: preferences_dao,
-    });
+    let cipher = match &config.key_encryption_key {
+        Some(key) => Some(SecretCipher::from_hex(key)?),
+        None => {
+            warn!("No key-encryption key passed. Secrets will be stored unencrypted.");
+            None
+        }
+    };
+    let preferences = Arc::new(PreferencesImpl::new(preferences_dao, cipher));
+    preferences.encrypt_plaintext_secrets()?;
 
     let memo_mapper = &MemoMapperImpl {};
 
-    let tcn_keys = Arc::new(TcnKeysImpl {
-        preferences: preferences.clone(),
-    });
+    let emitted_tcns = Arc::new(EmittedTcnDao::new(database.clone()));
+
+    let key_randomness = key_randomness(config.keys.seed.as_deref())?;
+
+    let tcn_keys = Arc::new(TcnKeysImpl::new(
+        preferences.clone(),
+        database.clone(),
+        config.keys.rotation_period_secs,
+        Arc::new(SystemClock {}),
+        emitted_tcns.clone(),
+        key_randomness,
+    ));
+
+    let verification_api: Option<Arc<dyn VerificationApi>> = match &config.verification.url {
+        Some(url) => Some(Arc::new(api.with_base_url(url))),
+        None if !config.verification.fake_codes.is_empty() => Some(fake_verification_api(
+            config.verification.fake_codes.clone(),
+        )?),
+        None => None,
+    };
 
     let symptom_inputs_submitter = SymptomInputsSubmitterImpl {
         memo_mapper,
         tcn_keys: tcn_keys.clone(),
-        api,
+        api: report_uploader.clone(),
+        preferences: preferences.clone(),
+        verifier: Arc::new(Verifier::new(verification_api, preferences.clone())),
+        config: config.reporting.clone(),
     };
 
     let tcn_dao = Arc::new(TcnDaoImpl::new(database.clone()));
+    let sync_stats_dao = Arc::new(SyncStatsDaoImpl::new(database.clone()));
 
-    CompositionRoot {
-        api,
+    let sources = config
+        .networking
+        .report_sources()
+        .into_iter()
+        .map(|source| ReportsSource {
+            api: Arc::new(api.with_base_url(&source.base_url)),
+            id: source.id,
+            interval_length: source.interval_length,
+        })
+        .collect();
+
+    let comp_root = CompositionRoot {
+        api: api.clone(),
+        report_uploader: report_uploader.clone(),
         reports_updater: ReportsUpdater {
             preferences: preferences.clone(),
             tcn_dao: tcn_dao.clone(),
             tcn_matcher: TcnMatcherRayon {},
-            api,
+            sources,
             memo_mapper,
+            sync_stats_dao: sync_stats_dao.clone(),
+            emitted_tcns,
+            alert_dao: Arc::new(AlertDao::new(database.clone())),
         },
         symptom_inputs_processor: SymptomInputsProcessorImpl {
             inputs_manager: SymptomInputsManagerImpl {
@@ -172,5 +292,19 @@ fn create_comp_root(
             tcn_dao: tcn_dao.clone(),
         },
         tcn_keys: tcn_keys.clone(),
-    }
+        sync_stats_dao,
+        backup: BackupManager {
+            preferences: preferences.clone(),
+            tcn_dao: tcn_dao.clone(),
+            tcn_keys: tcn_keys.clone(),
+            report_sources: config.networking.report_sources(),
+            database: database.clone(),
+        },
+        data_eraser: DataEraser {
+            database,
+            database_path: sqlite_path.to_owned(),
+            tcn_keys: tcn_keys.clone(),
+        },
+    };
+    Ok((comp_root, warnings))
 }
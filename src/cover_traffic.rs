@@ -0,0 +1,619 @@
+use crate::{
+    errors::{DaoError, ServicesError},
+    networking::{reports_body, NetworkingError, PaddedUpload, TcnApi, UNKNOWN_HTTP_STATUS},
+    preferences::Database,
+    reporting::memo::MAX_MARKER_SIZE,
+    verification::ReportVerification,
+};
+use log::*;
+use rand::{thread_rng, Rng, RngCore};
+use rusqlite::{params, Row, NO_PARAMS};
+use serde::Deserialize;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+// Size (bytes, before base64) of the random reports sent as cover.
+// Similar to a signed CoEpi report, though the padding makes the exact value irrelevant.
+const COVER_REPORT_SIZE: usize = 150;
+// Largest signed report (bytes, before base64): verification key, TCK, indices, memo type and
+// length, memo (its length is a byte) and signature
+const MAX_SIGNED_REPORT_SIZE: usize = 32 + 32 + 2 + 2 + 1 + 1 + 255 + 64;
+// Token of verified uploads, issued by the verification server
+const MAX_VERIFICATION_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CoverTrafficConfig {
+    pub enabled: bool,
+    // Size of every upload (reports + padding). None: the largest real upload. Bootstrap fails
+    // if smaller, as real uploads would be distinguishable.
+    pub upload_size: Option<usize>,
+    pub min_interval_secs: u64,
+    pub max_interval_secs: u64,
+}
+
+impl Default for CoverTrafficConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            upload_size: None,
+            min_interval_secs: 6 * 60 * 60,
+            max_interval_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+// The reports of a submission signed with max_reports keys, with a verification
+pub fn max_upload_size(max_reports: usize) -> usize {
+    // Base64, after the marker of revocations and amendments
+    let report = MAX_MARKER_SIZE + (MAX_SIGNED_REPORT_SIZE / 3 + 1) * 4;
+    // JSON array of strings
+    2 + max_reports * (report + 3) + MAX_VERIFICATION_SIZE
+}
+
+// When enabled, reports are not posted immediately but queued (in the database, so they're uploaded
+// also after a restart), and uploaded at random intervals.
+// If there's no queued report when an upload is due, a cover report (random bytes) is sent.
+// Real and cover uploads are padded to the same size, so a network observer can't tell them apart.
+// The reports of a submission (one per authorization key) are sent in one upload, so the number of
+// uploads doesn't reveal the number of keys either.
+pub struct CoverTrafficApi<T: TcnApi> {
+    api: Arc<T>,
+    enabled: bool,
+    upload_size: usize,
+    min_interval: Duration,
+    max_interval: Duration,
+    queue: Arc<ReportQueueDao>,
+    stopped: AtomicBool,
+}
+
+impl<T: TcnApi> CoverTrafficApi<T> {
+    // max_reports: the most reports of a submission (KeysConfig::max_report_keys)
+    pub fn new(
+        api: Arc<T>,
+        config: &CoverTrafficConfig,
+        max_reports: usize,
+        queue: Arc<ReportQueueDao>,
+    ) -> Result<CoverTrafficApi<T>, ServicesError> {
+        let required = max_upload_size(max_reports);
+        let upload_size = match config.upload_size {
+            Some(size) if config.enabled && size < required => {
+                return Err(ServicesError::General(format!(
+                    "Upload size: {} is smaller than the largest upload: {}",
+                    size, required
+                )))
+            }
+            Some(size) => size,
+            None => required,
+        };
+        Ok(Self::with_intervals(
+            api,
+            config.enabled,
+            upload_size,
+            queue,
+            Duration::from_secs(config.min_interval_secs),
+            Duration::from_secs(config.max_interval_secs),
+        ))
+    }
+
+    fn with_intervals(
+        api: Arc<T>,
+        enabled: bool,
+        upload_size: usize,
+        queue: Arc<ReportQueueDao>,
+        min_interval: Duration,
+        max_interval: Duration,
+    ) -> CoverTrafficApi<T> {
+        CoverTrafficApi {
+            api,
+            enabled,
+            upload_size,
+            min_interval,
+            max_interval,
+            queue,
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    pub fn next_delay(&self) -> Duration {
+        let min = self.min_interval.as_millis() as u64;
+        let max = self.max_interval.as_millis() as u64;
+        if min >= max {
+            return self.min_interval;
+        }
+        Duration::from_millis(thread_rng().gen_range(min, max))
+    }
+
+    // Uploads the oldest queued report, or a cover report if there's none.
+    // A real report stays queued until uploaded: if uploading fails, it's retried in the next slot.
+    // It's claimed while uploading, so it isn't uploaded again meanwhile.
+    pub fn send_next(&self) -> Result<(), NetworkingError> {
+        let queued = self.queue.claim().map_err(queue_error)?;
+
+        let upload = match &queued {
+            Some(queued) => self.pad(queued.report.clone(), queued.verification.clone(), false),
+            None => self.pad(Self::cover_report(), None, true),
+        };
+
+        let result = self.api.post_padded_report(upload);
+
+        match (&result, queued) {
+            (Ok(()), Some(queued)) => self.queue.remove(queued.id).map_err(queue_error)?,
+            (Err(error), Some(queued)) => {
+                warn!("Couldn't upload report: {:?}. Will retry.", error);
+                self.queue.release(queued.id).map_err(queue_error)?
+            }
+            _ => {}
+        }
+
+        result
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    // The verification is padded too: verified uploads have the same size as the others
+    fn pad(
+        &self,
+        report: String,
+        verification: Option<ReportVerification>,
+        cover: bool,
+    ) -> PaddedUpload {
+        let size = report.len() + verification.as_ref().map_or(0, |v| v.size());
+        if size > self.upload_size {
+            warn!(
+                "Report size: {} exceeds upload size: {}. Upload will be distinguishable.",
+                size, self.upload_size
+            );
+        }
+        let padding = "0".repeat(self.upload_size.saturating_sub(size));
+        PaddedUpload {
+            report,
+            verification,
+            padding,
+            cover,
+        }
+    }
+
+    // Shaped as a submission with one report
+    fn cover_report() -> String {
+        let mut bytes = vec![0u8; COVER_REPORT_SIZE];
+        thread_rng().fill_bytes(&mut bytes);
+        reports_body(&[base64::encode(bytes)])
+    }
+}
+
+// The report wasn't queued or uploaded
+fn queue_error(error: DaoError) -> NetworkingError {
+    NetworkingError {
+        http_status: UNKNOWN_HTTP_STATUS,
+        message: format!("Report queue: {}", error),
+    }
+}
+
+impl<T: TcnApi + Send + Sync + 'static> CoverTrafficApi<T> {
+    // Starts the upload schedule in a background thread. Does nothing if not enabled.
+    pub fn start(self: Arc<Self>) {
+        if !self.enabled {
+            return;
+        }
+        info!("Starting cover traffic");
+        // Claimed by an interrupted upload (e.g. the app was killed meanwhile)
+        if let Err(error) = self.queue.release_all() {
+            error!("Couldn't release claimed reports: {:?}", error);
+        }
+
+        thread::spawn(move || loop {
+            thread::sleep(self.next_delay());
+            if self.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            if let Err(error) = self.send_next() {
+                error!("Scheduled upload failed: {:?}", error);
+            }
+        });
+    }
+}
+
+impl<T: TcnApi> TcnApi for CoverTrafficApi<T> {
+    fn get_reports(
+        &self,
+        interval_number: u64,
+        interval_length: u64,
+    ) -> Result<Vec<String>, NetworkingError> {
+        self.api.get_reports(interval_number, interval_length)
+    }
+
+    fn post_report(&self, report: String) -> Result<(), NetworkingError> {
+        self.post_reports(vec![report])
+    }
+
+    // Queued as one upload, so the number of uploads doesn't reveal the number of keys
+    fn post_reports(&self, reports: Vec<String>) -> Result<(), NetworkingError> {
+        if self.enabled {
+            debug!("Queuing reports for next scheduled upload");
+            self.queue
+                .push(&reports_body(&reports), None)
+                .map_err(queue_error)
+        } else {
+            self.api.post_reports(reports)
+        }
+    }
+
+    // Queued as one upload, so they're sent together
+    fn post_verified_reports(
+        &self,
+        reports: Vec<String>,
+        verification: ReportVerification,
+    ) -> Result<(), NetworkingError> {
+        if self.enabled {
+            debug!("Queuing verified reports for next scheduled upload");
+            self.queue
+                .push(&reports_body(&reports), Some(&verification))
+                .map_err(queue_error)
+        } else {
+            self.api.post_verified_reports(reports, verification)
+        }
+    }
+
+    fn post_padded_report(&self, upload: PaddedUpload) -> Result<(), NetworkingError> {
+        self.api.post_padded_report(upload)
+    }
+}
+
+struct QueuedReport {
+    id: i64,
+    report: String,
+    verification: Option<ReportVerification>,
+}
+
+// Reports waiting for an upload slot, oldest first
+pub struct ReportQueueDao {
+    db: Arc<Database>,
+}
+
+impl ReportQueueDao {
+    pub fn new(db: Arc<Database>) -> ReportQueueDao {
+        ReportQueueDao { db }
+    }
+
+    fn push(
+        &self,
+        report: &str,
+        verification: Option<&ReportVerification>,
+    ) -> Result<(), DaoError> {
+        self.db.execute_sql(
+            "insert into report_queue(report, token) values(?1, ?2)",
+            params![report, verification.map(|verification| &verification.token)],
+        )?;
+        Ok(())
+    }
+
+    // The oldest report not being uploaded, marked as being uploaded
+    fn claim(&self) -> Result<Option<QueuedReport>, DaoError> {
+        self.db.transaction(|_| {
+            let mut queued = self.db.query(
+                "select id, report, token from report_queue where sending = 0
+                order by id limit 1",
+                NO_PARAMS,
+                Self::to_queued_report,
+            )?;
+            let queued = queued.pop();
+            if let Some(queued) = &queued {
+                self.db.execute_sql(
+                    "update report_queue set sending = 1 where id = ?1",
+                    params![queued.id],
+                )?;
+            }
+            Ok(queued)
+        })
+    }
+
+    // Uploading failed: it can be claimed again
+    fn release(&self, id: i64) -> Result<(), DaoError> {
+        self.db.execute_sql(
+            "update report_queue set sending = 0 where id = ?1",
+            params![id],
+        )?;
+        Ok(())
+    }
+
+    fn release_all(&self) -> Result<(), DaoError> {
+        self.db
+            .execute_sql("update report_queue set sending = 0", NO_PARAMS)?;
+        Ok(())
+    }
+
+    fn remove(&self, id: i64) -> Result<(), DaoError> {
+        self.db
+            .execute_sql("delete from report_queue where id = ?1", params![id])?;
+        Ok(())
+    }
+
+    fn to_queued_report(row: &Row) -> Result<QueuedReport, DaoError> {
+        let token: Option<String> = row.get(2)?;
+        Ok(QueuedReport {
+            id: row.get(0)?,
+            report: row.get(1)?,
+            verification: token.map(|token| ReportVerification { token }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::tests::test_database;
+    use parking_lot::Mutex;
+    use std::time::Instant;
+
+    struct RecordedUpload {
+        time: Instant,
+        size: usize,
+        report: String,
+        verification: Option<ReportVerification>,
+        padding_len: usize,
+        cover: bool,
+    }
+
+    #[derive(Default)]
+    struct TcnApiRecorder {
+        uploads: Mutex<Vec<RecordedUpload>>,
+        direct_posts: Mutex<Vec<String>>,
+        fail: AtomicBool,
+    }
+
+    impl TcnApi for TcnApiRecorder {
+        fn get_reports(&self, _: u64, _: u64) -> Result<Vec<String>, NetworkingError> {
+            Ok(vec![])
+        }
+
+        fn post_report(&self, report: String) -> Result<(), NetworkingError> {
+            self.direct_posts.lock().push(report);
+            Ok(())
+        }
+
+        fn post_verified_reports(
+            &self,
+            reports: Vec<String>,
+            _: ReportVerification,
+        ) -> Result<(), NetworkingError> {
+            self.direct_posts.lock().extend(reports);
+            Ok(())
+        }
+
+        fn post_padded_report(&self, upload: PaddedUpload) -> Result<(), NetworkingError> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(NetworkingError {
+                    http_status: 500,
+                    message: "Test".to_owned(),
+                });
+            }
+            self.uploads.lock().push(RecordedUpload {
+                time: Instant::now(),
+                size: upload.size(),
+                padding_len: upload.padding.len(),
+                report: upload.report,
+                verification: upload.verification,
+                cover: upload.cover,
+            });
+            Ok(())
+        }
+    }
+
+    fn test_queue() -> Arc<ReportQueueDao> {
+        Arc::new(ReportQueueDao::new(test_database()))
+    }
+
+    fn enabled_config() -> CoverTrafficConfig {
+        CoverTrafficConfig {
+            enabled: true,
+            upload_size: Some(2048),
+            ..CoverTrafficConfig::default()
+        }
+    }
+
+    // For submissions signed with at most 2 keys
+    fn create_api(
+        recorder: Arc<TcnApiRecorder>,
+        config: &CoverTrafficConfig,
+        queue: Arc<ReportQueueDao>,
+    ) -> CoverTrafficApi<TcnApiRecorder> {
+        CoverTrafficApi::new(recorder, config, 2, queue).unwrap()
+    }
+
+    #[test]
+    fn posts_directly_if_disabled() {
+        let recorder = Arc::new(TcnApiRecorder::default());
+        let api = create_api(
+            recorder.clone(),
+            &CoverTrafficConfig::default(),
+            test_queue(),
+        );
+
+        api.post_report("report".to_owned()).unwrap();
+
+        assert_eq!(*recorder.direct_posts.lock(), vec!["report".to_owned()]);
+        assert!(recorder.uploads.lock().is_empty());
+        // Not validated: there's no padding
+        assert!(CoverTrafficApi::new(
+            recorder,
+            &CoverTrafficConfig {
+                upload_size: Some(10),
+                ..CoverTrafficConfig::default()
+            },
+            2,
+            test_queue()
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn real_and_cover_uploads_have_same_size() {
+        let recorder = Arc::new(TcnApiRecorder::default());
+        let api = create_api(recorder.clone(), &enabled_config(), test_queue());
+
+        let verification = ReportVerification::new("token");
+        api.post_reports(vec!["report1".to_owned(), "report2".to_owned()])
+            .unwrap();
+        api.post_verified_reports(
+            vec!["verified1".to_owned(), "verified2".to_owned()],
+            verification.clone(),
+        )
+        .unwrap();
+        assert!(recorder.uploads.lock().is_empty());
+
+        api.send_next().unwrap();
+        api.send_next().unwrap();
+        api.send_next().unwrap();
+
+        let uploads = recorder.uploads.lock();
+        assert!(recorder.direct_posts.lock().is_empty());
+        assert_eq!(uploads.len(), 3);
+        // The reports of a submission in one upload
+        assert_eq!(uploads[0].report, r#"["report1","report2"]"#);
+        assert!(!uploads[0].cover);
+        assert_eq!(uploads[1].report, r#"["verified1","verified2"]"#);
+        assert_eq!(uploads[1].verification, Some(verification));
+        assert!(uploads[2].cover);
+        assert!(uploads.iter().all(|upload| upload.size == 2048));
+    }
+
+    #[test]
+    fn pads_to_largest_upload() {
+        let recorder = Arc::new(TcnApiRecorder::default());
+        let config = CoverTrafficConfig {
+            enabled: true,
+            ..CoverTrafficConfig::default()
+        };
+        let api = CoverTrafficApi::new(recorder.clone(), &config, 15, test_queue()).unwrap();
+
+        let report = base64::encode(&[0; MAX_SIGNED_REPORT_SIZE][..]);
+        api.post_verified_reports(vec![report; 15], ReportVerification::new("token"))
+            .unwrap();
+        api.send_next().unwrap();
+        api.send_next().unwrap();
+
+        let uploads = recorder.uploads.lock();
+        assert!(uploads
+            .iter()
+            .all(|upload| upload.size == max_upload_size(15)));
+        assert!(uploads[0].padding_len > 0);
+
+        // Real uploads would be larger than the cover ones
+        let config = CoverTrafficConfig {
+            upload_size: Some(max_upload_size(15) - 1),
+            ..config
+        };
+        assert!(CoverTrafficApi::new(recorder.clone(), &config, 15, test_queue()).is_err());
+    }
+
+    #[test]
+    fn failed_upload_is_retried() {
+        let recorder = Arc::new(TcnApiRecorder::default());
+        let api = create_api(recorder.clone(), &enabled_config(), test_queue());
+
+        api.post_report("report".to_owned()).unwrap();
+        recorder.fail.store(true, Ordering::SeqCst);
+        assert!(api.send_next().is_err());
+
+        recorder.fail.store(false, Ordering::SeqCst);
+        api.send_next().unwrap();
+
+        let uploads = recorder.uploads.lock();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].report, r#"["report"]"#);
+        assert!(!uploads[0].cover);
+    }
+
+    #[test]
+    fn queued_reports_are_uploaded_after_restart() {
+        let recorder = Arc::new(TcnApiRecorder::default());
+        let queue = test_queue();
+        let verification = ReportVerification::new("token");
+        create_api(recorder.clone(), &enabled_config(), queue.clone())
+            .post_verified_reports(vec!["verified".to_owned()], verification.clone())
+            .unwrap();
+
+        let restarted = create_api(recorder.clone(), &enabled_config(), queue);
+        restarted.send_next().unwrap();
+        restarted.send_next().unwrap();
+
+        let uploads = recorder.uploads.lock();
+        assert_eq!(uploads[0].report, r#"["verified"]"#);
+        assert_eq!(uploads[0].verification, Some(verification));
+        assert!(uploads[1].cover);
+    }
+
+    #[test]
+    fn claimed_report_is_not_uploaded_again() {
+        let recorder = Arc::new(TcnApiRecorder::default());
+        let queue = test_queue();
+        let api = create_api(recorder.clone(), &enabled_config(), queue.clone());
+
+        api.post_report("report".to_owned()).unwrap();
+        // Being uploaded, e.g. by an interrupted run
+        assert!(queue.claim().unwrap().is_some());
+        api.send_next().unwrap();
+        queue.release_all().unwrap();
+        api.send_next().unwrap();
+
+        let uploads = recorder.uploads.lock();
+        assert!(uploads[0].cover);
+        assert_eq!(uploads[1].report, r#"["report"]"#);
+        assert!(!uploads[1].cover);
+    }
+
+    #[test]
+    fn delay_is_within_interval() {
+        let api = CoverTrafficApi::with_intervals(
+            Arc::new(TcnApiRecorder::default()),
+            true,
+            2048,
+            test_queue(),
+            Duration::from_secs(10),
+            Duration::from_secs(20),
+        );
+        for _ in 0..100 {
+            let delay = api.next_delay();
+            assert!(delay >= Duration::from_secs(10) && delay < Duration::from_secs(20));
+        }
+    }
+
+    #[test]
+    fn uploads_on_schedule() {
+        let recorder = Arc::new(TcnApiRecorder::default());
+        let api = Arc::new(CoverTrafficApi::with_intervals(
+            recorder.clone(),
+            true,
+            2048,
+            test_queue(),
+            Duration::from_millis(10),
+            Duration::from_millis(30),
+        ));
+
+        api.post_report("report".to_owned()).unwrap();
+        api.clone().start();
+
+        let start = Instant::now();
+        while recorder.uploads.lock().len() < 4 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        api.stop();
+
+        let uploads = recorder.uploads.lock();
+        assert!(uploads.len() >= 4);
+        assert_eq!(uploads.iter().filter(|upload| !upload.cover).count(), 1);
+        assert!(uploads.iter().all(|upload| upload.size == 2048));
+        for pair in uploads.windows(2) {
+            assert!(pair[1].time.duration_since(pair[0].time) >= Duration::from_millis(10));
+        }
+    }
+}
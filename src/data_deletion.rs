@@ -0,0 +1,215 @@
+use crate::{
+    database_recovery::damaged_files, errors::ServicesError, preferences::Database,
+    tcn_ext::tcn_keys::TcnKeys,
+};
+use log::*;
+use rusqlite::NO_PARAMS;
+use serde::Serialize;
+use std::{collections::BTreeMap, fs, path::Path, sync::Arc};
+
+// Tables containing user data, deleted (and salvaged from a damaged database) with these.
+// New tables with user data have to be added here.
+pub const USER_DATA_TABLES: &[&str] = &[
+    "preferences",
+    "tcn",
+    "sync_stats",
+    "emitted_tcn",
+    "alert",
+    "report_queue",
+];
+
+// Sent to the apps, as confirmation of the erasure
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DeletionReport {
+    // Deleted rows by table
+    pub deleted_rows: BTreeMap<String, u64>,
+    // Rows left after deleting. Always 0: otherwise deleting fails.
+    pub remaining_rows: u64,
+    // Copies of the database deleted (damaged databases, interrupted encryption)
+    pub deleted_files: u64,
+}
+
+pub struct DataEraser<T>
+where
+    T: TcnKeys,
+{
+    pub database: Arc<Database>,
+    // Path of the database file, to find its copies
+    pub database_path: String,
+    pub tcn_keys: Arc<T>,
+}
+
+impl<T> DataEraser<T>
+where
+    T: TcnKeys,
+{
+    // Deletes observed TCNs, keys, fetch progress, sync stats and queued reports, vacuums the
+    // database and deletes its copies. clear_inputs (e.g. the symptom inputs in memory) runs with
+    // the rows, so nothing is left if either fails. The core stays usable: new keys are generated
+    // when needed.
+    pub fn delete_all_data<F>(&self, clear_inputs: F) -> Result<DeletionReport, ServicesError>
+    where
+        F: FnOnce() -> Result<(), ServicesError>,
+    {
+        info!("Deleting all data");
+        // Otherwise e.g. a TCN generated meanwhile could write the deleted keys back
+        let deleted_rows = self.tcn_keys.with_keys_locked(|| {
+            self.database.transaction(|tx| -> Result<_, ServicesError> {
+                // Overwrites the deleted content with zeros, instead of only marking it as free
+                tx.execute_batch("pragma secure_delete = on")?;
+                let mut deleted_rows = BTreeMap::new();
+                for table in USER_DATA_TABLES {
+                    let count = tx.execute(&format!("delete from {}", table), NO_PARAMS)?;
+                    deleted_rows.insert(table.to_string(), count as u64);
+                }
+                clear_inputs()?;
+                Ok(deleted_rows)
+            })
+        })?;
+        self.database.secure_vacuum()?;
+
+        let remaining_rows = self.count_rows()?;
+        if remaining_rows > 0 {
+            return Err(ServicesError::General(format!(
+                "Couldn't delete all data. Remaining rows: {}",
+                remaining_rows
+            )));
+        }
+
+        let deleted_files = self.delete_database_copies()?;
+
+        info!(
+            "Deleted all data: {:?}, files: {}",
+            deleted_rows, deleted_files
+        );
+        Ok(DeletionReport {
+            deleted_rows,
+            remaining_rows,
+            deleted_files,
+        })
+    }
+
+    // Left by database recovery (if deleting them failed) and an interrupted encryption
+    fn delete_database_copies(&self) -> Result<u64, ServicesError> {
+        let mut files = damaged_files(&self.database_path)?;
+        for suffix in &["", "-wal", "-shm", "-journal"] {
+            let file = format!("{}.encrypted{}", self.database_path, suffix);
+            if Path::new(&file).exists() {
+                files.push(file.into());
+            }
+        }
+        for file in &files {
+            fs::remove_file(file).map_err(|error| {
+                ServicesError::General(format!("Couldn't delete: {:?}: {}", file, error))
+            })?;
+        }
+        Ok(files.len() as u64)
+    }
+
+    fn count_rows(&self) -> Result<u64, ServicesError> {
+        let mut count = 0;
+        for table in USER_DATA_TABLES {
+            let table_count: i64 = self.database.query_row(
+                &format!("select count(*) from {}", table),
+                NO_PARAMS,
+                |row| row.get(0),
+            )?;
+            count += table_count as u64;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database_encryption::tests::temp_db_path,
+        migrations::tests::test_database,
+        preferences::{Preferences, PreferencesDao, PreferencesImpl},
+        reports_interval::UnixTime,
+        reports_updater::{ObservedTcn, TcnDao, TcnDaoImpl},
+        tcn_ext::tcn_keys::tests::test_tcn_keys,
+    };
+    use tcn::TemporaryContactNumber;
+
+    #[test]
+    fn deletes_all_data() {
+        let database = test_database();
+        let preferences = Arc::new(PreferencesImpl::new(
+            PreferencesDao::new(database.clone()),
+            None,
+        ));
+        let tcn_dao = TcnDaoImpl::new(database.clone());
+        preferences.set_autorization_key([1; 32]).unwrap();
+        tcn_dao
+            .save(&ObservedTcn {
+                tcn: TemporaryContactNumber([1; 16]),
+                time: UnixTime { value: 1000 },
+            })
+            .unwrap();
+
+        database
+            .execute_sql(
+                "insert into report_queue(report) values('report')",
+                NO_PARAMS,
+            )
+            .unwrap();
+        let path = temp_db_path();
+        let copies = [
+            format!("{}.damaged-1000", path),
+            format!("{}.damaged-1000-wal", path),
+            format!("{}.encrypted", path),
+        ];
+        for copy in &copies {
+            fs::write(copy, "data").unwrap();
+        }
+
+        let eraser = DataEraser {
+            database: database.clone(),
+            database_path: path,
+            tcn_keys: Arc::new(test_tcn_keys(preferences.clone(), database.clone())),
+        };
+        let mut cleared_inputs = false;
+        let report = eraser
+            .delete_all_data(|| {
+                cleared_inputs = true;
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(report.deleted_rows["preferences"], 1);
+        assert_eq!(report.deleted_rows["tcn"], 1);
+        assert_eq!(report.deleted_rows["sync_stats"], 0);
+        assert_eq!(report.deleted_rows["report_queue"], 1);
+        assert_eq!(report.remaining_rows, 0);
+        assert_eq!(report.deleted_files, 3);
+        assert!(copies.iter().all(|copy| !Path::new(copy).exists()));
+        assert!(preferences.authorization_key().unwrap().is_none());
+        assert!(tcn_dao.all().unwrap().is_empty());
+        assert!(cleared_inputs);
+
+        // Still usable
+        preferences.set_autorization_key([2; 32]).unwrap();
+        assert_eq!(preferences.authorization_key().unwrap(), Some([2; 32]));
+    }
+
+    #[test]
+    fn user_data_tables_are_all_tables() {
+        let database = test_database();
+        let mut tables: Vec<String> = database
+            .query(
+                "select name from sqlite_master where type = 'table' and name not like 'sqlite_%'",
+                NO_PARAMS,
+                |row| Ok(row.get(0)?),
+            )
+            .unwrap();
+        tables.sort();
+        let mut user_data_tables = USER_DATA_TABLES.to_vec();
+        user_data_tables.sort();
+
+        // If this fails, a new table has to be added to USER_DATA_TABLES (or, if it can't contain
+        // user data, excluded here)
+        assert_eq!(tables, user_data_tables);
+    }
+}
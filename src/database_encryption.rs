@@ -0,0 +1,211 @@
+use crate::errors::ServicesError;
+use log::*;
+use rusqlite::Connection;
+use std::{fs::File, io::Read};
+
+// First bytes of every unencrypted SQLite file. SQLCipher files start with random bytes (salt).
+const PLAINTEXT_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+// Opens the database. If there's a key, the whole file is encrypted with SQLCipher, which
+// requires the "sqlcipher" feature. An existing unencrypted database is encrypted first.
+pub fn open_connection(path: &str, key: Option<&str>) -> Result<Connection, ServicesError> {
+    match key {
+        Some(key) => open_encrypted(path, key),
+        None => {
+            if is_encrypted(path) {
+                warn!("Database seems to be encrypted, but no key was passed");
+            }
+            Connection::open(path).map_err(ServicesError::from)
+        }
+    }
+}
+
+// True if the file exists and isn't a plaintext SQLite database.
+// Empty files are created by SQLite before the first write, so they count as plaintext.
+pub fn is_encrypted(path: &str) -> bool {
+    let mut header = [0u8; 16];
+    match File::open(path).and_then(|mut file| file.read(&mut header)) {
+        Ok(0) | Err(_) => false,
+        Ok(_) => &header != PLAINTEXT_HEADER,
+    }
+}
+
+#[cfg(not(feature = "sqlcipher"))]
+fn open_encrypted(_path: &str, _key: &str) -> Result<Connection, ServicesError> {
+    Err(ServicesError::General(
+        "A database key was passed, but core wasn't built with \"sqlcipher\"".to_owned(),
+    ))
+}
+
+#[cfg(feature = "sqlcipher")]
+fn open_encrypted(path: &str, key: &str) -> Result<Connection, ServicesError> {
+    let key = raw_key(key)?;
+
+    if std::path::Path::new(path).exists() && !is_encrypted(path) {
+        encrypt_plaintext_database(path, &key)?;
+    }
+
+    let conn = Connection::open(path)?;
+    conn.pragma_update(None, "key", &key)?;
+    // The key is only verified when reading. Fails if the key is wrong.
+    conn.query_row(
+        "select count(*) from sqlite_master",
+        rusqlite::NO_PARAMS,
+        |row| row.get::<_, i64>(0),
+    )
+    .map_err(|error| {
+        error!("Couldn't open encrypted database: {:?}", error);
+        ServicesError::General("Couldn't open encrypted database. Wrong key?".to_owned())
+    })?;
+    Ok(conn)
+}
+
+// SQLCipher raw key syntax, to skip the key derivation: the key is already random.
+#[cfg(feature = "sqlcipher")]
+fn raw_key(key_hex: &str) -> Result<String, ServicesError> {
+    let bytes = hex::decode(key_hex)?;
+    if bytes.len() != 32 {
+        return Err(ServicesError::General(format!(
+            "Invalid database key length: {}, expected: 32",
+            bytes.len()
+        )));
+    }
+    Ok(format!("x'{}'", hex::encode(bytes)))
+}
+
+// One-time migration: exports the plaintext database into an encrypted copy, which then
+// replaces it. If something fails, the plaintext database is left as it was.
+#[cfg(feature = "sqlcipher")]
+fn encrypt_plaintext_database(path: &str, raw_key: &str) -> Result<(), ServicesError> {
+    info!("Encrypting plaintext database");
+    let encrypted_path = format!("{}.encrypted", path);
+    let _ = std::fs::remove_file(&encrypted_path); // leftover of a failed migration
+
+    {
+        let conn = Connection::open(path)?;
+        // sqlcipher_export doesn't copy the schema version
+        let version: i64 =
+            conn.query_row("pragma user_version", rusqlite::NO_PARAMS, |row| row.get(0))?;
+        conn.execute(
+            "attach database ?1 as encrypted key ?2",
+            rusqlite::params![encrypted_path, raw_key],
+        )?;
+        conn.query_row(
+            "select sqlcipher_export('encrypted')",
+            rusqlite::NO_PARAMS,
+            |_| Ok(()),
+        )?;
+        conn.execute_batch(&format!(
+            "pragma encrypted.user_version = {}; detach database encrypted;",
+            version
+        ))?;
+        // Empties the WAL (plaintext) into the database file, which is replaced below
+        conn.query_row(
+            "pragma wal_checkpoint(truncate)",
+            rusqlite::NO_PARAMS,
+            |_| Ok(()),
+        )?;
+    }
+
+    // Otherwise they'd be left with plaintext data, and be applied to the encrypted database
+    for suffix in &["-wal", "-shm"] {
+        match std::fs::remove_file(format!("{}{}", path, suffix)) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                return Err(ServicesError::General(format!(
+                    "Couldn't remove plaintext database file: {}",
+                    error
+                )))
+            }
+            _ => {}
+        }
+    }
+
+    std::fs::rename(&encrypted_path, path).map_err(|error| {
+        ServicesError::General(format!("Couldn't replace plaintext database: {}", error))
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::{env, path::Path};
+    use uuid::Uuid;
+
+    pub fn temp_db_path() -> String {
+        env::temp_dir()
+            .join(format!("coepi-{}.sqlite", Uuid::new_v4()))
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    // The database and its WAL files
+    pub fn remove_db_files(path: &str) {
+        for suffix in &["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    fn create_plaintext_database(path: &str) {
+        let conn = Connection::open(path).unwrap();
+        // As the app's database: data can be in the WAL file
+        conn.execute_batch(
+            "pragma journal_mode = wal;
+             create table test(value text); insert into test(value) values('a');
+             pragma user_version = 3;",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn plaintext_database_is_not_encrypted() {
+        let path = temp_db_path();
+        assert!(!is_encrypted(&path));
+
+        create_plaintext_database(&path);
+
+        assert!(!is_encrypted(&path));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    #[cfg(not(feature = "sqlcipher"))]
+    fn fails_opening_with_key_without_feature() {
+        let path = temp_db_path();
+        assert!(open_connection(&path, Some(&hex::encode([1; 32]))).is_err());
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    #[cfg(feature = "sqlcipher")]
+    fn encrypts_plaintext_database() {
+        use rusqlite::{params, NO_PARAMS};
+
+        let path = temp_db_path();
+        create_plaintext_database(&path);
+        let key = hex::encode([1; 32]);
+
+        let conn = open_connection(&path, Some(&key)).unwrap();
+
+        assert!(is_encrypted(&path));
+        let value: String = conn
+            .query_row("select value from test", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(value, "a");
+        let version: i64 = conn
+            .query_row("pragma user_version", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, 3);
+        drop(conn);
+
+        // Reopening doesn't migrate again
+        let conn = open_connection(&path, Some(&key)).unwrap();
+        conn.execute("insert into test(value) values(?1)", params!["b"])
+            .unwrap();
+        drop(conn);
+
+        assert!(open_connection(&path, Some(&hex::encode([2; 32]))).is_err());
+        remove_db_files(&path);
+    }
+}
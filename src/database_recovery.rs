@@ -0,0 +1,451 @@
+use crate::{
+    data_deletion::USER_DATA_TABLES,
+    database_encryption::{is_encrypted, open_connection},
+    errors::ServicesError,
+    migrations::MIGRATIONS,
+    preferences::{Database, PreferencesDao},
+    reports_interval::UnixTime,
+    reports_updater::{ObservedTcn, TcnDao, TcnDaoImpl},
+};
+use log::*;
+use rusqlite::{types::Value, Connection, ErrorCode, NO_PARAMS};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tcn::TemporaryContactNumber;
+
+// Something went wrong at bootstrap, but the core could recover. Sent to the app.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum BootstrapWarning {
+    // The database was damaged. It was replaced with a new one, containing the rows that could
+    // still be read, and deleted.
+    DatabaseRecovered {
+        problem: String,
+        salvaged_preferences: u64,
+        salvaged_tcns: u64,
+        // Of the other tables (e.g. queued reports, alerts), by table
+        salvaged_rows: BTreeMap<String, u64>,
+    },
+}
+
+// Opens and migrates the database. If it's damaged, it's moved aside and replaced with a new one,
+// with what could be salvaged from it (keys, observed TCNs, queued reports...). The damaged copy
+// is then deleted: it contains the keys and TCNs, and what could be read is in the new one.
+pub fn open_database(
+    path: &str,
+    key: Option<&str>,
+    reader_count: usize,
+) -> Result<(Arc<Database>, Vec<BootstrapWarning>), ServicesError> {
+    let problem = match check_integrity(path, key)? {
+        None => {
+            let database = open_and_migrate(path, key, reader_count)?;
+            return Ok((database, vec![]));
+        }
+        Some(problem) => problem,
+    };
+    error!("Database is damaged: {}. Recovering.", problem);
+
+    let moved_to = move_aside(path)?;
+    let salvaged = salvage(&moved_to, key);
+    let database = open_and_migrate(path, key, reader_count)?;
+    let (salvaged_preferences, salvaged_tcns, salvaged_rows) = restore(&database, salvaged);
+    info!(
+        "Recovered database. Salvaged preferences: {}, tcns: {}, other rows: {:?}",
+        salvaged_preferences, salvaged_tcns, salvaged_rows
+    );
+    remove_database_files(&moved_to);
+
+    Ok((
+        database,
+        vec![BootstrapWarning::DatabaseRecovered {
+            problem,
+            salvaged_preferences,
+            salvaged_tcns,
+            salvaged_rows,
+        }],
+    ))
+}
+
+fn open_and_migrate(
+    path: &str,
+    key: Option<&str>,
+    reader_count: usize,
+) -> Result<Arc<Database>, ServicesError> {
+    let database = Database::open(path, key, reader_count)?;
+    database.migrate(MIGRATIONS)?;
+    Ok(Arc::new(database))
+}
+
+// Returns the problem, if the database is damaged.
+// Errors that don't mean damage (e.g. a wrong key, or I/O errors) are returned as errors:
+// the database may be fine, so it's not replaced.
+fn check_integrity(path: &str, key: Option<&str>) -> Result<Option<String>, ServicesError> {
+    if !Path::new(path).exists() {
+        return Ok(None);
+    }
+    let conn = open_for_salvage(path, key)?;
+    match conn.query_row("pragma quick_check", NO_PARAMS, |row| {
+        row.get::<_, String>(0)
+    }) {
+        Ok(result) if result == "ok" => Ok(None),
+        Ok(result) => Ok(Some(result)),
+        Err(error) if is_damage(&error) => Ok(Some(error.to_string())),
+        Err(error) => Err(ServicesError::from(error)),
+    }
+}
+
+// Opens the file as it is. A plaintext database isn't encrypted yet (if there's a key):
+// a damaged one couldn't be.
+fn open_for_salvage(path: &str, key: Option<&str>) -> Result<Connection, ServicesError> {
+    let key = if is_encrypted(path) { key } else { None };
+    open_connection(path, key)
+}
+
+fn is_damage(error: &rusqlite::Error) -> bool {
+    match error {
+        rusqlite::Error::SqliteFailure(error, _) => match error.code {
+            ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// Moves the database (and its WAL files, which contain committed data) to a new path.
+fn move_aside(path: &str) -> Result<String, ServicesError> {
+    let moved_to = format!("{}.damaged-{}", path, UnixTime::now().value);
+    fs::rename(path, &moved_to).map_err(|error| {
+        ServicesError::General(format!("Couldn't move damaged database: {}", error))
+    })?;
+    for suffix in &["-wal", "-shm"] {
+        let file = format!("{}{}", path, suffix);
+        if Path::new(&file).exists() {
+            if let Err(error) = fs::rename(&file, format!("{}{}", moved_to, suffix)) {
+                warn!("Couldn't move: {}: {}", file, error);
+                let _ = fs::remove_file(&file);
+            }
+        }
+    }
+    Ok(moved_to)
+}
+
+// The files left by move_aside, with their WAL files
+pub fn damaged_files(path: &str) -> Result<Vec<PathBuf>, ServicesError> {
+    let path = Path::new(path);
+    let (dir, name) = match (
+        path.parent(),
+        path.file_name().and_then(|name| name.to_str()),
+    ) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Ok(vec![]),
+    };
+    let prefix = format!("{}.damaged-", name);
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let mut files = vec![];
+    for entry in fs::read_dir(dir).map_err(|error| ServicesError::General(error.to_string()))? {
+        let entry = entry.map_err(|error| ServicesError::General(error.to_string()))?;
+        if let Some(file_name) = entry.file_name().to_str() {
+            if file_name.starts_with(&prefix) {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+// The database and its WAL files
+pub fn remove_database_files(path: &str) {
+    for suffix in &["", "-wal", "-shm"] {
+        let file = format!("{}{}", path, suffix);
+        if Path::new(&file).exists() {
+            if let Err(error) = fs::remove_file(&file) {
+                warn!("Couldn't delete: {}: {}", file, error);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Salvaged {
+    preferences: Vec<(String, String)>,
+    tcns: Vec<ObservedTcn>,
+    // The other user data tables, copied as they are
+    tables: Vec<SalvagedTable>,
+}
+
+#[derive(Debug)]
+struct SalvagedTable {
+    name: &'static str,
+    columns: Vec<String>,
+    rows: Vec<Vec<Value>>,
+}
+
+// Reads what's still readable. Stops reading a table at the first error.
+fn salvage(path: &str, key: Option<&str>) -> Salvaged {
+    let conn = match open_for_salvage(path, key) {
+        Ok(conn) => conn,
+        Err(error) => {
+            warn!("Can't salvage damaged database: {:?}", error);
+            return Salvaged::default();
+        }
+    };
+    Salvaged {
+        preferences: read_rows(&conn, "select key, value from preferences", |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        }),
+        tcns: read_rows(&conn, "select tcn, contact_time from tcn", |row| {
+            // Hex text, or blob (newer versions)
+            let bytes = match row.get::<_, Value>(0)? {
+                Value::Text(hex) => hex::decode(hex).unwrap_or_default(),
+                Value::Blob(bytes) => bytes,
+                _ => vec![],
+            };
+            let time: i64 = row.get(1)?;
+            Ok(Some(bytes).filter(|bytes| bytes.len() == 16).map(|bytes| {
+                let mut tcn = [0; 16];
+                tcn.copy_from_slice(&bytes);
+                ObservedTcn {
+                    tcn: TemporaryContactNumber(tcn),
+                    time: UnixTime { value: time as u64 },
+                }
+            }))
+        })
+        .into_iter()
+        .flatten()
+        .collect(),
+        tables: USER_DATA_TABLES
+            .iter()
+            .filter(|table| !["preferences", "tcn"].contains(table))
+            .filter_map(|table| salvage_table(&conn, table))
+            .collect(),
+    }
+}
+
+fn salvage_table(conn: &Connection, table: &'static str) -> Option<SalvagedTable> {
+    let sql = format!("select * from {}", table);
+    let columns = match conn.prepare(&sql) {
+        Ok(statement) => statement
+            .column_names()
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<String>>(),
+        Err(error) => {
+            warn!("Can't salvage: {}: {:?}", sql, error);
+            return None;
+        }
+    };
+    let rows = read_rows(conn, &sql, |row| {
+        (0..columns.len()).map(|index| row.get(index)).collect()
+    });
+    Some(SalvagedTable {
+        name: table,
+        columns,
+        rows,
+    })
+}
+
+fn read_rows<T, F>(conn: &Connection, sql: &str, f: F) -> Vec<T>
+where
+    F: Fn(&rusqlite::Row) -> Result<T, rusqlite::Error>,
+{
+    let mut values = vec![];
+    let mut statement = match conn.prepare(sql) {
+        Ok(statement) => statement,
+        Err(error) => {
+            warn!("Can't salvage: {}: {:?}", sql, error);
+            return values;
+        }
+    };
+    let mut rows = match statement.query(NO_PARAMS) {
+        Ok(rows) => rows,
+        Err(error) => {
+            warn!("Can't salvage: {}: {:?}", sql, error);
+            return values;
+        }
+    };
+    loop {
+        match rows.next() {
+            Ok(Some(row)) => match f(row) {
+                Ok(value) => values.push(value),
+                Err(error) => warn!("Skipping damaged row: {:?}", error),
+            },
+            Ok(None) => break,
+            Err(error) => {
+                warn!("Stopped salvaging: {}: {:?}", sql, error);
+                break;
+            }
+        }
+    }
+    values
+}
+
+// Returns the number of restored preferences, TCNs and rows of the other tables
+fn restore(database: &Arc<Database>, salvaged: Salvaged) -> (u64, u64, BTreeMap<String, u64>) {
+    let preferences_dao = PreferencesDao::new(database.clone());
+    let preferences = salvaged
+        .preferences
+        .iter()
+        // Stored values: the secrets stay encrypted
+        .filter(|(key, value)| preferences_dao.save(key, value).is_ok())
+        .count();
+
+    let tcn_dao = TcnDaoImpl::new(database.clone());
+    let tcns = salvaged
+        .tcns
+        .iter()
+        .filter(|tcn| tcn_dao.save(tcn).is_ok())
+        .count();
+
+    let mut rows = BTreeMap::new();
+    for table in salvaged.tables {
+        // By name: the damaged database may have been created by an older version
+        let sql = format!(
+            "insert or ignore into {}({}) values({})",
+            table.name,
+            table.columns.join(", "),
+            vec!["?"; table.columns.len()].join(", ")
+        );
+        let count = table
+            .rows
+            .iter()
+            .filter(|row| match database.execute_sql(&sql, *row) {
+                Ok(count) => count > 0,
+                Err(error) => {
+                    warn!("Couldn't restore row: {}: {:?}", sql, error);
+                    false
+                }
+            })
+            .count();
+        rows.insert(table.name.to_owned(), count as u64);
+    }
+
+    (preferences as u64, tcns as u64, rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database_encryption::tests::{remove_db_files, temp_db_path};
+    use std::io::Write;
+
+    fn create_database(path: &str) {
+        let database = Database::open(path, None, 0).unwrap();
+        database.migrate(MIGRATIONS).unwrap();
+        database
+            .execute_sql(
+                "insert into report_queue(report, token) values('report', 'token')",
+                NO_PARAMS,
+            )
+            .unwrap();
+        PreferencesDao::new(Arc::new(database))
+            .save("authorization_key", "stored key")
+            .unwrap();
+    }
+
+    #[test]
+    fn opens_healthy_database_without_warnings() {
+        let path = temp_db_path();
+        create_database(&path);
+
+        let (database, warnings) = open_database(&path, None, 1).unwrap();
+
+        assert!(warnings.is_empty());
+        assert_eq!(
+            PreferencesDao::new(database.clone())
+                .load("authorization_key")
+                .unwrap(),
+            Some("stored key".to_owned())
+        );
+        drop(database);
+        remove_db_files(&path);
+    }
+
+    #[test]
+    fn replaces_file_that_is_not_a_database() {
+        let path = temp_db_path();
+        fs::File::create(&path)
+            .unwrap()
+            .write_all(&[7; 4096])
+            .unwrap();
+
+        let (database, warnings) = open_database(&path, None, 1).unwrap();
+
+        assert_eq!(warnings.len(), 1);
+        let BootstrapWarning::DatabaseRecovered {
+            salvaged_preferences,
+            ..
+        } = &warnings[0];
+        assert_eq!(*salvaged_preferences, 0);
+        assert!(damaged_files(&path).unwrap().is_empty());
+        // Usable
+        PreferencesDao::new(database.clone())
+            .save("tck", "value")
+            .unwrap();
+        drop(database);
+        remove_db_files(&path);
+    }
+
+    #[test]
+    fn salvages_readable_rows_of_damaged_database() {
+        let path = temp_db_path();
+        create_database(&path);
+        {
+            // Damages the tcn table's data. The preferences table stays readable.
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch("pragma journal_mode = delete").unwrap();
+            let root_page: i64 = conn
+                .query_row(
+                    "select rootpage from sqlite_master where name = 'tcn'",
+                    NO_PARAMS,
+                    |row| row.get(0),
+                )
+                .unwrap();
+            let page_size: i64 = conn
+                .query_row("pragma page_size", NO_PARAMS, |row| row.get(0))
+                .unwrap();
+            drop(conn);
+            let mut bytes = fs::read(&path).unwrap();
+            let start = ((root_page - 1) * page_size) as usize;
+            for byte in &mut bytes[start..start + 100] {
+                *byte = 0xff;
+            }
+            fs::write(&path, bytes).unwrap();
+        }
+
+        let (database, warnings) = open_database(&path, None, 1).unwrap();
+
+        assert_eq!(warnings.len(), 1);
+        let BootstrapWarning::DatabaseRecovered {
+            salvaged_preferences,
+            salvaged_rows,
+            ..
+        } = &warnings[0];
+        assert_eq!(*salvaged_preferences, 1);
+        assert_eq!(salvaged_rows["report_queue"], 1);
+        let queued: (String, Option<String>) = database
+            .query_row("select report, token from report_queue", NO_PARAMS, |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(queued, ("report".to_owned(), Some("token".to_owned())));
+        assert_eq!(
+            PreferencesDao::new(database.clone())
+                .load("authorization_key")
+                .unwrap(),
+            Some("stored key".to_owned())
+        );
+        // Deleted once salvaged
+        assert!(damaged_files(&path).unwrap().is_empty());
+        drop(database);
+        remove_db_files(&path);
+    }
+}
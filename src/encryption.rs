@@ -0,0 +1,132 @@
+use crate::errors::ServicesError;
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::{thread_rng, RngCore};
+
+// Marks (and versions) encrypted values, to tell them apart from legacy plaintext ones.
+const ENCRYPTED_PREFIX: &str = "enc1:";
+const NONCE_SIZE: usize = 12;
+pub const KEY_SIZE: usize = 32;
+
+// Encrypts secrets (RAK, TCK) stored in the database with a key-encryption key, which the apps
+// keep in the Keychain / Keystore and pass at bootstrap.
+// Format: "enc1:" + base64(nonce || ciphertext)
+pub struct SecretCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl SecretCipher {
+    pub fn new(key: [u8; KEY_SIZE]) -> SecretCipher {
+        SecretCipher {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        }
+    }
+
+    pub fn from_hex(key_hex: &str) -> Result<SecretCipher, ServicesError> {
+        let bytes = hex::decode(key_hex)?;
+        if bytes.len() != KEY_SIZE {
+            return Err(ServicesError::General(format!(
+                "Invalid key-encryption key length: {}, expected: {}",
+                bytes.len(),
+                KEY_SIZE
+            )));
+        }
+        let mut key = [0; KEY_SIZE];
+        key.copy_from_slice(&bytes);
+        Ok(Self::new(key))
+    }
+
+    pub fn is_encrypted(value: &str) -> bool {
+        value.starts_with(ENCRYPTED_PREFIX)
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<String, ServicesError> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        thread_rng().fill_bytes(&mut nonce);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| ServicesError::General("Couldn't encrypt secret".to_owned()))?;
+
+        let mut bytes = nonce.to_vec();
+        bytes.extend(ciphertext);
+        Ok(format!("{}{}", ENCRYPTED_PREFIX, base64::encode(bytes)))
+    }
+
+    pub fn decrypt(&self, value: &str) -> Result<Vec<u8>, ServicesError> {
+        if !Self::is_encrypted(value) {
+            return Err(ServicesError::General("Value is not encrypted".to_owned()));
+        }
+        let bytes = base64::decode(&value[ENCRYPTED_PREFIX.len()..])?;
+        if bytes.len() < NONCE_SIZE {
+            return Err(ServicesError::General(
+                "Encrypted value too short".to_owned(),
+            ));
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_SIZE);
+
+        // Fails too if the key is not the one the value was encrypted with
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| ServicesError::General("Couldn't decrypt secret".to_owned()))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn test_cipher() -> SecretCipher {
+        SecretCipher::new([7; KEY_SIZE])
+    }
+
+    #[test]
+    fn encrypts_and_decrypts() {
+        let cipher = test_cipher();
+
+        let encrypted = cipher.encrypt(b"secret").unwrap();
+
+        assert!(SecretCipher::is_encrypted(&encrypted));
+        assert!(!encrypted.contains("secret"));
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), b"secret".to_vec());
+    }
+
+    #[test]
+    fn uses_random_nonce() {
+        let cipher = test_cipher();
+        assert_ne!(
+            cipher.encrypt(b"secret").unwrap(),
+            cipher.encrypt(b"secret").unwrap()
+        );
+    }
+
+    #[test]
+    fn fails_decrypting_with_other_key() {
+        let encrypted = test_cipher().encrypt(b"secret").unwrap();
+        let other_cipher = SecretCipher::new([8; KEY_SIZE]);
+
+        assert!(other_cipher.decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn fails_decrypting_tampered_value() {
+        let cipher = test_cipher();
+        let encrypted = cipher.encrypt(b"secret").unwrap();
+        let mut bytes = base64::decode(&encrypted[ENCRYPTED_PREFIX.len()..]).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let tampered = format!("{}{}", ENCRYPTED_PREFIX, base64::encode(bytes));
+
+        assert!(cipher.decrypt(&tampered).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_key_hex() {
+        assert!(SecretCipher::from_hex("abcd").is_err());
+        assert!(SecretCipher::from_hex("zz").is_err());
+        assert!(SecretCipher::from_hex(&hex::encode([1; KEY_SIZE])).is_ok());
+    }
+}
@@ -6,11 +6,56 @@ pub type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 #[derive(Debug)]
 pub enum ServicesError {
     Networking(NetworkingError),
+    // The backend rejected the credentials (401/403). The app should provide a new auth token.
+    ReauthRequired(NetworkingError),
     Error(Error),
     FFIParameters(String),
     General(String),
 }
 
+// Errors of the data access layer (DAOs)
+#[derive(Debug)]
+pub enum DaoError {
+    Database(rusqlite::Error),
+    // A stored value couldn't be decoded
+    InvalidData(String),
+}
+
+impl fmt::Display for DaoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DaoError::Database(error) => write!(f, "Database error: {}", error),
+            DaoError::InvalidData(message) => write!(f, "Invalid data: {}", message),
+        }
+    }
+}
+
+impl error::Error for DaoError {}
+
+impl From<rusqlite::Error> for DaoError {
+    fn from(error: rusqlite::Error) -> Self {
+        DaoError::Database(error)
+    }
+}
+
+impl From<hex::FromHexError> for DaoError {
+    fn from(error: hex::FromHexError) -> Self {
+        DaoError::InvalidData(format!("{}", error))
+    }
+}
+
+impl From<serde_json::Error> for DaoError {
+    fn from(error: serde_json::Error) -> Self {
+        DaoError::InvalidData(format!("{}", error))
+    }
+}
+
+impl From<DaoError> for ServicesError {
+    fn from(error: DaoError) -> Self {
+        ServicesError::Error(Box::new(error))
+    }
+}
+
 impl fmt::Display for ServicesError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -25,7 +70,11 @@ impl From<Error> for ServicesError {
 
 impl From<NetworkingError> for ServicesError {
     fn from(error: NetworkingError) -> Self {
-        ServicesError::Networking(error)
+        if error.is_reauth_required() {
+            ServicesError::ReauthRequired(error)
+        } else {
+            ServicesError::Networking(error)
+        }
     }
 }
 
@@ -68,6 +117,24 @@ impl From<hex::FromHexError> for ServicesError {
     }
 }
 
+impl From<base64::DecodeError> for ServicesError {
+    fn from(error: base64::DecodeError) -> Self {
+        ServicesError::Error(Box::new(StdError::new(
+            ErrorKind::Other,
+            format!("{}", error),
+        )))
+    }
+}
+
+impl From<std::string::FromUtf8Error> for ServicesError {
+    fn from(error: std::string::FromUtf8Error) -> Self {
+        ServicesError::Error(Box::new(StdError::new(
+            ErrorKind::Other,
+            format!("{}", error),
+        )))
+    }
+}
+
 impl From<std::str::Utf8Error> for ServicesError {
     fn from(error: std::str::Utf8Error) -> Self {
         ServicesError::Error(Box::new(StdError::new(
@@ -37,16 +37,31 @@ typedef struct {
   int64_t time;
 } CoreLogMessage;
 
-CFStringRef bootstrap_core(const char *db_path, CoreLogLevel level, bool coepi_only);
+CFStringRef amend_report(void);
+
+CFStringRef bootstrap_core(const char *db_path,
+                           CoreLogLevel level,
+                           bool coepi_only,
+                           const char *c_config);
 
 int32_t call_callback(void (*callback)(int32_t, bool, CFStringRef));
 
 CFStringRef clear_symptoms(void);
 
+CFStringRef delete_all_data(void);
+
+CFStringRef export_backup(const char *c_passphrase);
+
+CFStringRef export_recovery_phrase(void);
+
 CFStringRef fetch_new_reports(void);
 
 CFStringRef generate_tcn(void);
 
+CFStringRef import_backup(const char *c_passphrase, const char *c_backup);
+
+CFStringRef import_recovery_phrase(const char *c_phrase);
+
 FFIReturnStruct pass_and_return_struct(const FFIParameterStruct *par);
 
 int32_t pass_struct(const FFIParameterStruct *par);
@@ -59,8 +74,12 @@ int32_t register_callback(void (*callback)(int32_t, bool, CFStringRef));
 
 int32_t register_log_callback(void (*log_callback)(CoreLogMessage));
 
+CFStringRef report_preview(void);
+
 FFIReturnStruct return_struct(void);
 
+CFStringRef revoke_report(void);
+
 #if defined(TARGET_OS_ANDROID)
 char *rust_greeting(const char *to);
 #endif
@@ -69,6 +88,8 @@ char *rust_greeting(const char *to);
 char *rust_greeting2(const char *to);
 #endif
 
+CFStringRef set_auth_token(const char *c_token);
+
 CFStringRef set_breathlessness_cause(const char *c_cause);
 
 CFStringRef set_cough_days(uint8_t c_is_set, uint32_t c_days);
@@ -93,6 +114,10 @@ int32_t setup_logger(CoreLogLevel level, bool coepi_only);
 
 CFStringRef submit_symptoms(void);
 
+CFStringRef sync_stats(void);
+
 int32_t trigger_callback(const char *my_str);
 
 int32_t trigger_logging_macros(void);
+
+CFStringRef verify_code(const char *c_code);
@@ -1,8 +1,9 @@
 use crate::reporting::symptom_inputs_manager::SymptomInputsProcessor;
 use crate::reports_updater::ObservedTcnProcessor;
+use crate::sync_stats::SyncStatsDao;
 use crate::tcn_ext::tcn_keys::TcnKeys;
 use crate::{
-    composition_root::{bootstrap, dependencies, COMP_ROOT},
+    composition_root::{bootstrap, dependencies, CoreConfig},
     errors::ServicesError,
     networking,
 };
@@ -41,6 +42,7 @@ pub unsafe extern "C" fn bootstrap_core(
     db_path: *const c_char,
     level: CoreLogLevel,
     coepi_only: bool,
+    c_config: *const c_char,
 ) -> CFStringRef {
     let level_string = level.to_string();
     let filter_level = LevelFilter::from_str(&level_string).expect("Incorrect log level selected!");
@@ -48,7 +50,10 @@ pub unsafe extern "C" fn bootstrap_core(
 
     let db_path_str = cstring_to_str(&db_path);
     println!("Bootstrapping with db path: {:?}", db_path_str);
-    let result = db_path_str.and_then(|path| bootstrap(path).map_err(ServicesError::from));
+    let config = cstring_to_str(&c_config).and_then(CoreConfig::from_json);
+    let result = db_path_str.and_then(|path| {
+        config.and_then(|config| bootstrap(path, config).map_err(ServicesError::from))
+    });
     info!("Bootstrapping result: {:?}", result);
     return to_result_str(result);
 }
@@ -72,26 +77,86 @@ pub unsafe extern "C" fn record_tcn(c_tcn: *const c_char) -> CFStringRef {
     return to_result_str(result);
 }
 
-// NOTE: Returns directly success string
+// Fails if the keys can't be read or stored
 #[no_mangle]
 pub unsafe extern "C" fn generate_tcn() -> CFStringRef {
     // TODO hex encoding in component, or send byte array directly?
-    let tcn_hex = hex::encode(
-        COMP_ROOT
-            .get()
-            .expect("Not bootstrapped")
-            .tcn_keys
-            .generate_tcn()
-            .0,
-    );
-    info!("Generated TCN: {:?}", tcn_hex);
-
-    let cf_string = CFString::new(&tcn_hex);
-    let cf_string_ref = cf_string.as_concrete_TypeRef();
+    let result = dependencies()
+        .tcn_keys
+        .generate_tcn()
+        .map(|tcn| hex::encode(tcn.0));
+    info!("Generated TCN: {:?}", result);
+    return to_result_str(result);
+}
 
-    ::std::mem::forget(cf_string);
+// Statistics of the last report syncs (fetch_new_reports), for diagnostics.
+#[no_mangle]
+pub unsafe extern "C" fn sync_stats() -> CFStringRef {
+    let result = dependencies()
+        .sync_stats_dao
+        .report()
+        .map_err(ServicesError::from);
+    return to_result_str(result);
+}
+
+// Encrypted backup of the keys and observed TCNs (base64), to restore e.g. on a new phone.
+#[no_mangle]
+pub unsafe extern "C" fn export_backup(c_passphrase: *const c_char) -> CFStringRef {
+    let result = cstring_to_str(&c_passphrase)
+        .and_then(|passphrase| dependencies().backup.export_backup(passphrase));
+    return to_result_str(result);
+}
 
-    cf_string_ref
+// The authorization keys, as a phrase the user can write down to report from a new phone.
+#[no_mangle]
+pub unsafe extern "C" fn export_recovery_phrase() -> CFStringRef {
+    let result = dependencies().tcn_keys.export_recovery_phrase();
+    return to_result_str(result);
+}
+
+// Continues with the keys of a phrase created with export_recovery_phrase.
+#[no_mangle]
+pub unsafe extern "C" fn import_recovery_phrase(c_phrase: *const c_char) -> CFStringRef {
+    let result = cstring_to_str(&c_phrase)
+        .and_then(|phrase| dependencies().tcn_keys.import_recovery_phrase(phrase));
+    return to_result_str(result);
+}
+
+// Restores a backup created with export_backup. Reports are matched again with the restored TCNs.
+#[no_mangle]
+pub unsafe extern "C" fn import_backup(
+    c_passphrase: *const c_char,
+    c_backup: *const c_char,
+) -> CFStringRef {
+    let result = cstring_to_str(&c_passphrase).and_then(|passphrase| {
+        let backup = cstring_to_str(&c_backup)?;
+        dependencies().backup.import_backup(passphrase, backup)
+    });
+    return to_result_str(result);
+}
+
+// Deletes observed TCNs, keys, fetch progress, sync stats and symptom inputs.
+// Returns the deleted rows by table. The core stays bootstrapped, with new keys.
+#[no_mangle]
+pub unsafe extern "C" fn delete_all_data() -> CFStringRef {
+    let result = dependencies()
+        .data_eraser
+        .delete_all_data(|| dependencies().symptom_inputs_processor.clear());
+    return to_result_str(result);
+}
+
+// Sets or replaces the token sent with every request. Empty string clears it.
+#[no_mangle]
+pub unsafe extern "C" fn set_auth_token(c_token: *const c_char) -> CFStringRef {
+    let token_str = cstring_to_str(&c_token);
+    let result = token_str.map(|token| {
+        let token = match token {
+            "" => None,
+            _ => Some(token.to_owned()),
+        };
+        dependencies().api.set_auth_token(token)
+    });
+    return to_result_str(result);
 }
 
 fn to_result_str<T: Serialize>(result: Result<T, ServicesError>) -> CFStringRef {
@@ -101,6 +166,11 @@ fn to_result_str<T: Serialize>(result: Result<T, ServicesError>) -> CFStringRef
             data: Some(success),
             error_message: None,
         },
+        Err(ServicesError::ReauthRequired(e)) => LibResult {
+            status: 401,
+            data: None,
+            error_message: Some(e.to_string()),
+        },
         // TODO better error identification, using HTTP status for everything is weird.
         Err(e) => LibResult {
             status: 500,
@@ -251,6 +321,35 @@ pub unsafe extern "C" fn submit_symptoms() -> CFStringRef {
     return to_result_str(result);
 }
 
+// What submit_symptoms would share with the current inputs, without sending anything.
+#[no_mangle]
+pub unsafe extern "C" fn report_preview() -> CFStringRef {
+    let result = dependencies().symptom_inputs_processor.preview();
+    return to_result_str(result);
+}
+
+// Withdraws the submitted report. The contacts' alerts are marked as revoked.
+#[no_mangle]
+pub unsafe extern "C" fn revoke_report() -> CFStringRef {
+    let result = dependencies().symptom_inputs_processor.revoke();
+    return to_result_str(result);
+}
+
+// Replaces the submitted report with the current inputs.
+#[no_mangle]
+pub unsafe extern "C" fn amend_report() -> CFStringRef {
+    let result = dependencies().symptom_inputs_processor.amend();
+    return to_result_str(result);
+}
+
+// Verification code of a positive test. The next report is sent as verified.
+#[no_mangle]
+pub unsafe extern "C" fn verify_code(c_code: *const c_char) -> CFStringRef {
+    let result = cstring_to_str(&c_code)
+        .and_then(|code| dependencies().symptom_inputs_processor.verify_code(code));
+    return to_result_str(result);
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn post_report(c_report: *const c_char) -> CFStringRef {
     info!("Posting report: {:?}", c_report);
@@ -259,7 +358,7 @@ pub unsafe extern "C" fn post_report(c_report: *const c_char) -> CFStringRef {
 
     let result = report.and_then(|report| {
         dependencies()
-            .api
+            .report_uploader
             .post_report(report.to_owned())
             .map_err(ServicesError::from)
     });
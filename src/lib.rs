@@ -1,15 +1,27 @@
 #[macro_use]
 extern crate serde_big_array;
 use errors::Error;
+mod alerts;
+mod backup;
+mod clock;
 mod composition_root;
+mod cover_traffic;
+mod data_deletion;
+mod database_encryption;
+mod database_recovery;
+mod encryption;
 mod errors;
+mod migrations;
 mod networking;
+mod preference_key;
 mod preferences;
 mod reporting;
 mod reports_interval;
 mod reports_updater;
 mod simple_logger;
+mod sync_stats;
 mod tcn_ext;
+mod verification;
 
 #[cfg(any(target_os = "ios", target_os = "macos"))]
 mod ios;
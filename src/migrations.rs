@@ -0,0 +1,300 @@
+use crate::preferences::Migration;
+use log::*;
+use rusqlite::{params, types::Value, Transaction, NO_PARAMS};
+
+// Ordered by version. Never modify a released migration: add a new one instead.
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create preferences, tcn and sync_stats tables",
+        apply: create_initial_tables,
+    },
+    Migration {
+        version: 2,
+        description: "Store TCNs as blobs, index contact time",
+        apply: store_tcns_as_blobs,
+    },
+    Migration {
+        version: 3,
+        description: "Create emitted_tcn table",
+        apply: create_emitted_tcn_table,
+    },
+    Migration {
+        version: 4,
+        description: "Create alert table",
+        apply: create_alert_table,
+    },
+    Migration {
+        version: 5,
+        description: "Create report_queue table",
+        apply: create_report_queue_table,
+    },
+    Migration {
+        version: 6,
+        description: "Index observed TCNs",
+        apply: index_tcns,
+    },
+];
+
+// Installs that predate the migrations already have these tables (with user_version 0),
+// so they're created only if they don't exist.
+fn create_initial_tables(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "create table if not exists preferences(
+            key text primary key,
+            value text not null
+        )",
+        NO_PARAMS,
+    )?;
+    tx.execute(
+        "create table if not exists tcn(
+            tcn text not null,
+            contact_time integer not null
+        )",
+        NO_PARAMS,
+    )?;
+    tx.execute(
+        "create table if not exists sync_stats(
+            time integer not null,
+            successful integer not null,
+            stats text not null
+        )",
+        NO_PARAMS,
+    )?;
+    Ok(())
+}
+
+// Hex text -> 16 byte blobs. TCNs that aren't valid hex / 16 bytes are dropped:
+// they couldn't be read anyway.
+fn store_tcns_as_blobs(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "create table tcn_blob(
+            tcn blob not null,
+            contact_time integer not null
+        )",
+        NO_PARAMS,
+    )?;
+    {
+        let mut select = tx.prepare("select tcn, contact_time from tcn")?;
+        let mut insert = tx.prepare("insert into tcn_blob(tcn, contact_time) values(?1, ?2)")?;
+        let mut rows = select.query(NO_PARAMS)?;
+        while let Some(row) = rows.next()? {
+            let bytes = match row.get::<_, Value>(0)? {
+                Value::Text(hex) => hex::decode(hex).ok(),
+                Value::Blob(bytes) => Some(bytes),
+                _ => None,
+            };
+            match bytes {
+                Some(bytes) if bytes.len() == 16 => {
+                    insert.execute(params![bytes, row.get::<_, i64>(1)?])?;
+                }
+                _ => warn!("Dropping invalid stored TCN"),
+            }
+        }
+    }
+    tx.execute("drop table tcn", NO_PARAMS)?;
+    tx.execute("alter table tcn_blob rename to tcn", NO_PARAMS)?;
+    tx.execute(
+        "create index tcn_contact_time on tcn(contact_time)",
+        NO_PARAMS,
+    )?;
+    Ok(())
+}
+
+// Log of the TCNs broadcast by this device
+fn create_emitted_tcn_table(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "create table emitted_tcn(
+            tcn blob primary key,
+            key_created integer not null,
+            tck_index integer not null,
+            time integer not null
+        )",
+        NO_PARAMS,
+    )?;
+    tx.execute(
+        "create index emitted_tcn_time on emitted_tcn(time)",
+        NO_PARAMS,
+    )?;
+    Ok(())
+}
+
+// Alerts sent to the app, to apply revocations and amendments of their reports
+fn create_alert_table(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "create table alert(
+            id text primary key,
+            rvk blob not null,
+            memo blob not null,
+            contact_time integer not null,
+            source text not null,
+            status integer not null
+        )",
+        NO_PARAMS,
+    )?;
+    tx.execute("create index alert_rvk on alert(rvk)", NO_PARAMS)?;
+    Ok(())
+}
+
+// Reports waiting for their cover traffic upload, so they're not lost if the app is killed
+fn create_report_queue_table(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "create table report_queue(
+            id integer primary key autoincrement,
+            report text not null,
+            token text,
+            sending integer not null default 0
+        )",
+        NO_PARAMS,
+    )?;
+    Ok(())
+}
+
+// For the sightings of a TCN
+fn index_tcns(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute("create index tcn_tcn on tcn(tcn)", NO_PARAMS)?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::preferences::Database;
+    use rusqlite::{params, Connection};
+    use std::sync::Arc;
+
+    // In-memory database with all the migrations applied
+    pub fn test_database() -> Arc<Database> {
+        let database =
+            Database::new(Connection::open_in_memory().expect("Couldn't create database!"));
+        database
+            .migrate(MIGRATIONS)
+            .expect("Couldn't migrate database");
+        Arc::new(database)
+    }
+
+    fn add_table_with_column(tx: &Transaction) -> Result<(), rusqlite::Error> {
+        tx.execute("create table test(value text)", NO_PARAMS)?;
+        Ok(())
+    }
+
+    fn insert_row(tx: &Transaction) -> Result<(), rusqlite::Error> {
+        tx.execute("insert into test(value) values('a')", NO_PARAMS)?;
+        Ok(())
+    }
+
+    fn fail(tx: &Transaction) -> Result<(), rusqlite::Error> {
+        tx.execute("insert into non_existing(value) values('a')", NO_PARAMS)?;
+        Ok(())
+    }
+
+    fn count_rows(database: &Database) -> i64 {
+        database
+            .query_row("select count(*) from test", params![], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn applies_pending_migrations_in_order() {
+        let database = Database::new(Connection::open_in_memory().unwrap());
+        let migrations = [
+            Migration {
+                version: 1,
+                description: "Add table",
+                apply: add_table_with_column,
+            },
+            Migration {
+                version: 2,
+                description: "Insert row",
+                apply: insert_row,
+            },
+        ];
+
+        assert_eq!(database.migrate(&migrations[..1]).unwrap(), 1);
+        assert_eq!(count_rows(&database), 0);
+
+        assert_eq!(database.migrate(&migrations).unwrap(), 2);
+        assert_eq!(count_rows(&database), 1);
+
+        // Already applied: nothing to do
+        assert_eq!(database.migrate(&migrations).unwrap(), 2);
+        assert_eq!(count_rows(&database), 1);
+    }
+
+    #[test]
+    fn failed_migration_leaves_database_intact() {
+        let database = Database::new(Connection::open_in_memory().unwrap());
+        let migrations = [
+            Migration {
+                version: 1,
+                description: "Add table",
+                apply: add_table_with_column,
+            },
+            Migration {
+                version: 2,
+                description: "Insert row",
+                apply: insert_row,
+            },
+            Migration {
+                version: 3,
+                description: "Fail",
+                apply: fail,
+            },
+        ];
+        database.migrate(&migrations[..1]).unwrap();
+
+        assert!(database.migrate(&migrations).is_err());
+
+        assert_eq!(database.user_version().unwrap(), 1);
+        assert_eq!(count_rows(&database), 0);
+    }
+
+    #[test]
+    fn keeps_existing_tables_of_unversioned_database() {
+        let database = Database::new(Connection::open_in_memory().unwrap());
+        database
+            .execute_sql(
+                "create table preferences(key text primary key, value text not null)",
+                params![],
+            )
+            .unwrap();
+        database
+            .execute_sql(
+                "insert into preferences(key, value) values('tck', 'value')",
+                params![],
+            )
+            .unwrap();
+
+        database.migrate(MIGRATIONS).unwrap();
+
+        let value: String = database
+            .query_row(
+                "select value from preferences where key='tck'",
+                params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(value, "value");
+    }
+
+    #[test]
+    fn converts_hex_tcns_to_blobs() {
+        let database = Database::new(Connection::open_in_memory().unwrap());
+        database.migrate(&MIGRATIONS[..1]).unwrap();
+        database
+            .execute_sql(
+                "insert into tcn(tcn, contact_time) values(?1, 1000), ('invalid', 2000)",
+                params![hex::encode([1u8; 16])],
+            )
+            .unwrap();
+
+        database.migrate(MIGRATIONS).unwrap();
+
+        let rows = database
+            .query("select tcn, contact_time from tcn", params![], |row| {
+                Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?))
+            })
+            .unwrap();
+        assert_eq!(rows, vec![(vec![1u8; 16], 1000)]);
+    }
+}
@@ -0,0 +1,159 @@
+use log::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+
+// Version of the stored format. Increment when the encoding of existing keys changes,
+// and handle the older versions in decode.
+pub const PREFERENCE_FORMAT_VERSION: u32 = 1;
+
+// A typed preference. Declaring a constant is all that's needed to add a setting:
+// pub const THRESHOLD: PreferenceKey<f32> = PreferenceKey::new("threshold", || 0.5);
+pub struct PreferenceKey<T> {
+    // Stored key. Don't change it for existing preferences: the stored values would be lost.
+    pub name: Cow<'static, str>,
+    // Returned if there's no stored value or it can't be decoded
+    pub default: fn() -> T,
+    // Key material. Encrypted if there's a cipher.
+    pub secret: bool,
+    // Decodes values stored before the versioned format
+    pub legacy: Option<fn(&str) -> Option<T>>,
+}
+
+impl<T> PreferenceKey<T> {
+    pub const fn new(name: &'static str, default: fn() -> T) -> PreferenceKey<T> {
+        PreferenceKey {
+            name: Cow::Borrowed(name),
+            default,
+            secret: false,
+            legacy: None,
+        }
+    }
+
+    pub const fn secret(mut self) -> PreferenceKey<T> {
+        self.secret = true;
+        self
+    }
+
+    pub const fn legacy(mut self, legacy: fn(&str) -> Option<T>) -> PreferenceKey<T> {
+        self.legacy = Some(legacy);
+        self
+    }
+
+    // Same preference stored under a different name, e.g. one per reports source.
+    pub fn named(&self, name: String) -> PreferenceKey<T> {
+        PreferenceKey {
+            name: Cow::Owned(name),
+            default: self.default,
+            secret: self.secret,
+            legacy: self.legacy,
+        }
+    }
+
+    pub fn default_value(&self) -> T {
+        (self.default)()
+    }
+}
+
+// A key whatever its type, for what's done by name (e.g. exports).
+pub trait AnyPreferenceKey: Sync {
+    fn name(&self) -> &str;
+    fn is_secret(&self) -> bool;
+}
+
+impl<T> AnyPreferenceKey for PreferenceKey<T> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn is_secret(&self) -> bool {
+        self.secret
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<V> {
+    version: u32,
+    value: V,
+}
+
+pub fn encode<T: Serialize>(value: &T) -> Result<String, serde_json::Error> {
+    serde_json::to_string(&Envelope {
+        version: PREFERENCE_FORMAT_VERSION,
+        value,
+    })
+}
+
+// None (logged) if the stored value is malformed or has an unknown version.
+pub fn decode<T: DeserializeOwned>(key: &PreferenceKey<T>, stored: &str) -> Option<T> {
+    match serde_json::from_str::<Envelope<Value>>(stored) {
+        Ok(envelope) if envelope.version == PREFERENCE_FORMAT_VERSION => {
+            serde_json::from_value(envelope.value)
+                .map_err(|error| error!("Invalid preference: {}: {:?}", key.name, error))
+                .ok()
+        }
+        Ok(envelope) => {
+            error!(
+                "Preference: {} has unknown version: {}",
+                key.name, envelope.version
+            );
+            None
+        }
+        Err(_) => match key.legacy {
+            Some(legacy) => {
+                let value = legacy(stored);
+                if value.is_none() {
+                    error!("Invalid legacy preference: {}", key.name);
+                }
+                value
+            }
+            None => {
+                error!("Invalid preference: {}", key.name);
+                None
+            }
+        },
+    }
+}
+
+// Legacy decoder for values stored as plain JSON
+pub fn legacy_json<T: DeserializeOwned>(stored: &str) -> Option<T> {
+    serde_json::from_str(stored).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COUNT: PreferenceKey<u32> = PreferenceKey::new("count", || 3);
+    const LEGACY_COUNT: PreferenceKey<u32> =
+        PreferenceKey::new("count", || 3).legacy(legacy_json::<u32>);
+
+    #[test]
+    fn encodes_and_decodes() {
+        let encoded = encode(&5u32).unwrap();
+        assert_eq!(encoded, r#"{"version":1,"value":5}"#);
+        assert_eq!(decode(&COUNT, &encoded), Some(5));
+    }
+
+    #[test]
+    fn malformed_value_decodes_to_none() {
+        assert_eq!(decode(&COUNT, "not json"), None);
+        assert_eq!(decode(&COUNT, r#"{"version":1,"value":"abc"}"#), None);
+        assert_eq!(decode(&COUNT, r#"{"version":2,"value":5}"#), None);
+    }
+
+    #[test]
+    fn decodes_legacy_value() {
+        assert_eq!(decode(&COUNT, "5"), None);
+        assert_eq!(decode(&LEGACY_COUNT, "5"), Some(5));
+    }
+
+    #[test]
+    fn named_key_keeps_properties() {
+        let key = LEGACY_COUNT.secret().named("count_2".to_owned());
+        assert_eq!(key.name, "count_2");
+        assert!(key.secret);
+        assert_eq!(key.default_value(), 3);
+        assert_eq!(decode(&key, "5"), Some(5));
+    }
+}
@@ -1,11 +1,27 @@
-use crate::{byte_vec_to_32_byte_array, expect_log, reports_interval::ReportsInterval};
+use crate::{
+    byte_vec_to_32_byte_array,
+    database_encryption::open_connection,
+    encryption::SecretCipher,
+    errors::{DaoError, ServicesError},
+    networking::DEFAULT_REPORTS_SOURCE_ID,
+    preference_key::{self, legacy_json, AnyPreferenceKey, PreferenceKey},
+    reports_interval::ReportsInterval,
+};
 use log::*;
-use rusqlite::{params, Connection, Row, ToSql};
-use serde::{Deserialize, Serialize};
+use parking_lot::{Mutex, MutexGuard, ReentrantMutex, ReentrantMutexGuard, RwLock};
+use rusqlite::{params, Connection, Row, ToSql, Transaction, TransactionBehavior, NO_PARAMS};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
 use std::fmt;
 use std::{
+    collections::HashMap,
+    ops::Deref,
     option::Option,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, ThreadId},
 };
 
 pub const TCK_SIZE_IN_BYTES: usize = 66;
@@ -58,55 +74,66 @@ pub struct PreferencesDao {
 }
 
 impl PreferencesDao {
-    pub fn load(&self, key: &str) -> Option<String> {
+    pub fn load(&self, key: &str) -> Result<Option<String>, DaoError> {
         let result = self.db.query_row(
             "select value from preferences where key=?1",
             &[key],
-            |row| {
-                let res = row.get(0);
-                let value: String = expect_log!(res, "Invalid row");
-                Ok(value)
-            },
+            |row| row.get(0),
         );
 
-        if let Err(e) = &result {
-            match e {
-                // Empty result: not an error
-                rusqlite::Error::QueryReturnedNoRows => {}
-                _ => error!("Error loading preference: {:?}", e),
-            }
+        match result {
+            Ok(value) => Ok(Some(value)),
+            // Empty result: not an error
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(error) => Err(DaoError::from(error)),
         }
-
-        result.ok()
     }
 
-    pub fn save(&self, key: &str, value: &str) {
-        let res = self.db.execute_sql(
+    pub fn save(&self, key: &str, value: &str) -> Result<(), DaoError> {
+        self.db.execute_sql(
             "insert or replace into preferences(key, value) values(?1, ?2)",
             params![key, value],
-        );
-        expect_log!(res, "Couldn't insert preference");
+        )?;
+        Ok(())
+    }
+
+    pub fn keys(&self) -> Result<Vec<String>, DaoError> {
+        let keys = self
+            .db
+            .query("select key from preferences", NO_PARAMS, |row| {
+                row.get(0).map_err(DaoError::from)
+            })?;
+        Ok(keys)
     }
 
     pub fn new(db: Arc<Database>) -> PreferencesDao {
-        Self::create_table_if_not_exists(&db);
         PreferencesDao { db }
     }
 
-    fn create_table_if_not_exists(db: &Arc<Database>) {
-        let res = db.execute_sql(
-            "create table if not exists preferences(
-                key text primary key,
-                value text not null
-            )",
-            params![],
-        );
-        expect_log!(res, "Couldn't create preferences table");
+    fn after_commit<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.db.after_commit(f)
     }
 }
 
+// Schema change, applied once, identified by the version it migrates the database to.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub apply: fn(&Transaction) -> Result<(), rusqlite::Error>,
+}
+
+// Reads use a pool of connections (if the database was opened from a file), so long reads
+// (e.g. all the TCNs while matching) don't block writes (e.g. recording TCNs), and vice versa.
+// Requires WAL mode, where readers see the last committed state while the writer writes.
 pub struct Database {
-    conn: Mutex<Connection>,
+    // Reentrant, so the code running in a transaction can use the database (see transaction)
+    conn: ReentrantMutex<Connection>,
+    readers: Vec<Mutex<Connection>>,
+    next_reader: AtomicUsize,
+    // The thread running a transaction. Its reads use conn, to see the transaction's writes.
+    transaction_thread: Mutex<Option<ThreadId>>,
+    // Run when the transaction is committed (see after_commit)
+    after_commit: Mutex<Vec<Box<dyn FnOnce() + Send>>>,
 }
 
 impl Database {
@@ -117,28 +144,48 @@ impl Database {
         P: IntoIterator,
         P::Item: ToSql,
     {
-        let res = self.conn.lock();
-        let conn = expect_log!(res, "Couldn't lock mutex");
+        let conn = self.conn.lock();
         conn.execute(sql, pars)
     }
 
+    // Rows f can't read are skipped (logged), so one corrupt row doesn't make all the others unreadable.
     pub fn query<T, P, F>(&self, sql: &str, params: P, f: F) -> Result<Vec<T>, rusqlite::Error>
     where
         P: IntoIterator,
         P::Item: ToSql,
-        F: Fn(&Row<'_>) -> T,
+        F: Fn(&Row<'_>) -> Result<T, DaoError>,
     {
-        let res = self.conn.lock();
-        let conn = expect_log!(res, "Couldn't lock mutex");
+        let mut objs = Vec::new();
+        self.query_each(sql, params, f, |obj| objs.push(obj))?;
+        Ok(objs)
+    }
+
+    // Passes the rows to consumer as they're read, without collecting them.
+    pub fn query_each<T, P, F, C>(
+        &self,
+        sql: &str,
+        params: P,
+        f: F,
+        mut consumer: C,
+    ) -> Result<(), rusqlite::Error>
+    where
+        P: IntoIterator,
+        P::Item: ToSql,
+        F: Fn(&Row<'_>) -> Result<T, DaoError>,
+        C: FnMut(T),
+    {
+        let conn = self.reader();
 
         let mut statement = conn.prepare(sql)?;
         let mut rows = statement.query(params)?;
 
-        let mut objs = Vec::new();
-        while let Some(row) = rows.next().unwrap() {
-            objs.push(f(row));
+        while let Some(row) = rows.next()? {
+            match f(row) {
+                Ok(obj) => consumer(obj),
+                Err(error) => warn!("Skipping invalid row: {}, query: {}", error, sql),
+            }
         }
-        Ok(objs)
+        Ok(())
     }
 
     pub fn query_row<T, P, F>(&self, sql: &str, params: P, f: F) -> Result<T, rusqlite::Error>
@@ -147,76 +194,545 @@ impl Database {
         P::Item: ToSql,
         F: FnOnce(&Row<'_>) -> Result<T, rusqlite::Error>,
     {
-        let res = self.conn.lock();
-        let conn = expect_log!(res, "Couldn't lock mutex");
+        let conn = self.reader();
         conn.query_row(sql, params, f)
     }
 
+    // Single connection, used for reads and writes. For in-memory databases.
     pub fn new(conn: Connection) -> Database {
         Database {
-            conn: Mutex::new(conn),
+            conn: ReentrantMutex::new(conn),
+            readers: vec![],
+            next_reader: AtomicUsize::new(0),
+            transaction_thread: Mutex::new(None),
+            after_commit: Mutex::new(vec![]),
+        }
+    }
+
+    // Opens the file in WAL mode, with one connection for writes and reader_count for reads.
+    pub fn open(
+        path: &str,
+        key: Option<&str>,
+        reader_count: usize,
+    ) -> Result<Database, ServicesError> {
+        let conn = open_connection(path, key)?;
+        // Persistent: stored in the file
+        let journal_mode: String =
+            conn.query_row("pragma journal_mode = wal", NO_PARAMS, |row| row.get(0))?;
+        if journal_mode != "wal" {
+            warn!(
+                "Couldn't enable WAL, journal mode: {}. Reads will wait for writes.",
+                journal_mode
+            );
         }
+        // Safe in WAL mode: a crash can't corrupt the database, only lose the last commits
+        conn.execute_batch("pragma synchronous = normal")?;
+
+        let mut readers = vec![];
+        for _ in 0..reader_count {
+            let reader = open_connection(path, key)?;
+            reader.execute_batch("pragma query_only = on")?;
+            readers.push(Mutex::new(reader));
+        }
+
+        Ok(Database {
+            conn: ReentrantMutex::new(conn),
+            readers,
+            next_reader: AtomicUsize::new(0),
+            transaction_thread: Mutex::new(None),
+            after_commit: Mutex::new(vec![]),
+        })
+    }
+
+    // A free reader connection if there's one, otherwise waits for one (round robin).
+    fn reader(&self) -> ConnectionGuard {
+        if self.readers.is_empty() || self.in_transaction() {
+            return ConnectionGuard::Writer(self.conn.lock());
+        }
+        for reader in &self.readers {
+            if let Some(conn) = reader.try_lock() {
+                return ConnectionGuard::Reader(conn);
+            }
+        }
+        let index = self.next_reader.fetch_add(1, Ordering::Relaxed) % self.readers.len();
+        ConnectionGuard::Reader(self.readers[index].lock())
+    }
+
+    fn in_transaction(&self) -> bool {
+        *self.transaction_thread.lock() == Some(thread::current().id())
+    }
+
+    // Applies the migrations with a version greater than the database's user_version, in order.
+    // All in one transaction: if a migration fails, the database is left unchanged.
+    // Returns the resulting version.
+    pub fn migrate(&self, migrations: &[Migration]) -> Result<u32, rusqlite::Error> {
+        let conn = self.conn.lock();
+
+        let current_version = Self::user_version_with(&conn)?;
+        let latest_version = migrations.last().map_or(0, |migration| migration.version);
+        if current_version > latest_version {
+            warn!(
+                "Database version: {} is newer than latest known: {}. Not migrating.",
+                current_version, latest_version
+            );
+            return Ok(current_version);
+        }
+
+        let tx = Transaction::new_unchecked(&conn, TransactionBehavior::Deferred)?;
+        let mut version = current_version;
+        for migration in migrations.iter().filter(|m| m.version > current_version) {
+            info!(
+                "Migrating database to version: {} ({})",
+                migration.version, migration.description
+            );
+            (migration.apply)(&tx).map_err(|error| {
+                error!(
+                    "Migration to version: {} failed: {:?}",
+                    migration.version, error
+                );
+                error
+            })?;
+            // user_version is part of the database header, so it's also rolled back on error
+            tx.execute_batch(&format!("pragma user_version = {}", migration.version))?;
+            version = migration.version;
+        }
+        tx.commit()?;
+
+        Ok(version)
+    }
+
+    // Runs f in a transaction, committed if f succeeds. What f does with this database (on this
+    // thread) is part of the transaction, including nested transactions. Other threads wait for
+    // it to write, and don't see its writes until committed.
+    pub fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        E: From<rusqlite::Error>,
+        F: FnOnce(&Connection) -> Result<T, E>,
+    {
+        let conn = self.conn.lock();
+        if self.in_transaction() {
+            return f(&conn);
+        }
+
+        let tx = Transaction::new_unchecked(&conn, TransactionBehavior::Deferred)?;
+        let scope = TransactionScope::start(self);
+        let result = f(&tx);
+        let after_commit = scope.end();
+        let value = result?;
+        tx.commit()?;
+        drop(conn);
+
+        for f in after_commit {
+            f();
+        }
+        Ok(value)
+    }
+
+    // Runs f when the transaction of this thread is committed (not if it's rolled back), e.g. to
+    // notify its changes. Without a transaction, now.
+    pub fn after_commit<F: FnOnce() + Send + 'static>(&self, f: F) {
+        if self.in_transaction() {
+            self.after_commit.lock().push(Box::new(f));
+        } else {
+            f();
+        }
+    }
+
+    // Rebuilds the file, overwriting the free pages: deleted data can't be recovered from it.
+    // In WAL mode, the WAL file (which can contain deleted data) is emptied too.
+    pub fn secure_vacuum(&self) -> Result<(), rusqlite::Error> {
+        let conn = self.conn.lock();
+        conn.execute_batch("pragma secure_delete = on; vacuum;")?;
+        conn.query_row("pragma wal_checkpoint(truncate)", NO_PARAMS, |_| Ok(()))
+    }
+
+    pub fn user_version(&self) -> Result<u32, rusqlite::Error> {
+        let conn = self.conn.lock();
+        Self::user_version_with(&conn)
+    }
+
+    fn user_version_with(conn: &Connection) -> Result<u32, rusqlite::Error> {
+        conn.query_row("pragma user_version", NO_PARAMS, |row| row.get(0))
+    }
+}
+
+enum ConnectionGuard<'a> {
+    Writer(ReentrantMutexGuard<'a, Connection>),
+    Reader(MutexGuard<'a, Connection>),
+}
+
+impl Deref for ConnectionGuard<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            ConnectionGuard::Writer(conn) => conn,
+            ConnectionGuard::Reader(conn) => conn,
+        }
+    }
+}
+
+// Marks the current thread as running the transaction, until dropped: also if the transaction
+// panics, so the database can still be used (the transaction is rolled back when dropped).
+struct TransactionScope<'a> {
+    database: &'a Database,
+}
+
+impl<'a> TransactionScope<'a> {
+    fn start(database: &'a Database) -> TransactionScope<'a> {
+        *database.transaction_thread.lock() = Some(thread::current().id());
+        TransactionScope { database }
+    }
+
+    // What has to run after the commit
+    fn end(self) -> Vec<Box<dyn FnOnce() + Send>> {
+        std::mem::take(&mut *self.database.after_commit.lock())
+    }
+}
+
+impl Drop for TransactionScope<'_> {
+    fn drop(&mut self) {
+        *self.database.transaction_thread.lock() = None;
+        self.database.after_commit.lock().clear();
     }
 }
 
 pub trait Preferences {
-    fn last_completed_reports_interval(&self) -> Option<ReportsInterval>;
-    fn set_last_completed_reports_interval(&self, value: ReportsInterval);
+    fn last_completed_reports_interval(
+        &self,
+        source_id: &str,
+    ) -> Result<Option<ReportsInterval>, DaoError>;
+    fn set_last_completed_reports_interval(
+        &self,
+        source_id: &str,
+        value: ReportsInterval,
+    ) -> Result<(), DaoError>;
+
+    fn authorization_key(&self) -> Result<Option<[u8; 32]>, DaoError>;
+    fn set_autorization_key(&self, value: [u8; 32]) -> Result<(), DaoError>;
+
+    fn tck(&self) -> Result<Option<TckBytesWrapper>, DaoError>;
+    fn set_tck(&self, value: TckBytesWrapper) -> Result<(), DaoError>;
+
+    // Unix time the current authorization key was created. None if created before this was stored.
+    fn authorization_key_created(&self) -> Result<Option<u64>, DaoError>;
+    fn set_authorization_key_created(&self, value: u64) -> Result<(), DaoError>;
+
+    fn retired_authorization_keys(&self) -> Result<Vec<RetiredAuthorizationKey>, DaoError>;
+    fn set_retired_authorization_keys(
+        &self,
+        value: Vec<RetiredAuthorizationKey>,
+    ) -> Result<(), DaoError>;
+
+    // Start (unix time) of the window of the last report submitted by the user, to revoke or
+    // amend it. None if there's no report or it was revoked.
+    fn last_report_start(&self) -> Result<Option<u64>, DaoError>;
+    fn set_last_report_start(&self, value: Option<u64>) -> Result<(), DaoError>;
+
+    // Token of an exchanged verification code, until the report it verifies is sent
+    fn verification_token(&self) -> Result<Option<String>, DaoError>;
+    fn set_verification_token(&self, value: Option<String>) -> Result<(), DaoError>;
+}
+
+// An authorization key replaced by a new one: rotated (after the rotation period, or when its TCK
+// ratchet was exhausted), or the device's key when other keys were imported (recovery phrase or
+// backup).
+// Kept for the report period, to report the TCNs generated with it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetiredAuthorizationKey {
+    pub key: [u8; 32],
+    // TCK indices used with the key, inclusive
+    pub start_index: u16,
+    pub end_index: u16,
+    // Unix time. start_time is 0 if unknown.
+    pub start_time: u64,
+    pub end_time: u64,
+}
+
+pub const LAST_COMPLETED_REPORTS_INTERVAL: PreferenceKey<Option<ReportsInterval>> =
+    PreferenceKey::new("last_completed_reports_interval", || None).legacy(legacy_json);
+
+pub const AUTHORIZATION_KEY: PreferenceKey<Option<[u8; 32]>> =
+    PreferenceKey::new("authorization_key", || None)
+        .secret()
+        .legacy(legacy_authorization_key);
 
-    // TODO encrypted
-    fn authorization_key(&self) -> Option<[u8; 32]>;
-    fn set_autorization_key(&self, value: [u8; 32]);
+pub const TCK: PreferenceKey<Option<TckBytesWrapper>> = PreferenceKey::new("tck", || None)
+    .secret()
+    .legacy(legacy_json);
 
-    fn tck(&self) -> Option<TckBytesWrapper>;
-    fn set_tck(&self, value: TckBytesWrapper);
+pub const AUTHORIZATION_KEY_CREATED: PreferenceKey<Option<u64>> =
+    PreferenceKey::new("authorization_key_created", || None);
+
+pub const RETIRED_AUTHORIZATION_KEYS: PreferenceKey<Vec<RetiredAuthorizationKey>> =
+    PreferenceKey::new("retired_authorization_keys", Vec::new).secret();
+
+pub const LAST_REPORT_START: PreferenceKey<Option<u64>> =
+    PreferenceKey::new("last_report_start", || None);
+
+pub const VERIFICATION_TOKEN: PreferenceKey<Option<String>> =
+    PreferenceKey::new("verification_token", || None).secret();
+
+// All the keys above. Exported and imported entries, and plaintext secrets, are handled by name.
+pub const PREFERENCE_KEYS: &[&dyn AnyPreferenceKey] = &[
+    &LAST_COMPLETED_REPORTS_INTERVAL,
+    &AUTHORIZATION_KEY,
+    &TCK,
+    &AUTHORIZATION_KEY_CREATED,
+    &RETIRED_AUTHORIZATION_KEYS,
+    &LAST_REPORT_START,
+    &VERIFICATION_TOKEN,
+];
+
+// Stored as hex before the versioned format
+fn legacy_authorization_key(stored: &str) -> Option<Option<[u8; 32]>> {
+    let bytes = hex::decode(stored).ok()?;
+    if bytes.len() != 32 {
+        return None;
+    }
+    Some(Some(byte_vec_to_32_byte_array(bytes)))
 }
 
+// Called with the JSON of the new value
+type Observer = Box<dyn Fn(&Value) + Send + Sync>;
+type Observers = RwLock<HashMap<String, Vec<Observer>>>;
+
 pub struct PreferencesImpl {
     pub dao: PreferencesDao,
+    // Encrypts the secrets. None if the app didn't pass a key-encryption key: secrets stored in plaintext.
+    pub cipher: Option<SecretCipher>,
+    // Shared with the notifications waiting for a commit
+    observers: Arc<Observers>,
+}
+
+impl PreferencesImpl {
+    pub fn new(dao: PreferencesDao, cipher: Option<SecretCipher>) -> PreferencesImpl {
+        PreferencesImpl {
+            dao,
+            cipher,
+            observers: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    // The key's default if there's no value. Values that can't be decoded (e.g. malformed) are
+    // logged and treated as missing. Errors reading or decrypting the value are returned.
+    pub fn get<T: DeserializeOwned>(&self, key: &PreferenceKey<T>) -> Result<T, DaoError> {
+        Ok(self
+            .load_value(&key.name, key.secret)?
+            .and_then(|stored| preference_key::decode(key, &stored))
+            .unwrap_or_else(|| key.default_value()))
+    }
+
+    pub fn set<T: Serialize>(&self, key: &PreferenceKey<T>, value: &T) -> Result<(), DaoError> {
+        let json = serde_json::to_value(value)?;
+        self.save_value(&key.name, key.secret, &preference_key::encode(&json)?)?;
+
+        if self.observers.read().contains_key(key.name.as_ref()) {
+            let observers = self.observers.clone();
+            let name = key.name.to_string();
+            // Only values that were stored: not if the enclosing transaction is rolled back
+            self.dao.after_commit(move || {
+                if let Some(observers) = observers.read().get(&name) {
+                    for observer in observers {
+                        observer(&json);
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    // Calls observer with the new value every time the preference is set.
+    pub fn observe<T, F>(&self, key: &PreferenceKey<T>, observer: F)
+    where
+        T: DeserializeOwned,
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        self.observers
+            .write()
+            .entry(key.name.to_string())
+            .or_default()
+            .push(Box::new(move |json| {
+                if let Ok(value) = serde_json::from_value(json.clone()) {
+                    observer(value)
+                }
+            }));
+    }
+
+    // All the stored preferences, with the secrets decrypted. For backups.
+    pub fn export_entries(&self) -> Result<Vec<(String, String)>, ServicesError> {
+        let mut entries = vec![];
+        for name in self.dao.keys()? {
+            if let Some(value) = self.load_value(&name, Self::is_secret(&name))? {
+                entries.push((name, value));
+            }
+        }
+        Ok(entries)
+    }
+
+    // Stores exported entries, encrypting the secrets with the current cipher.
+    pub fn import_entries(&self, entries: &[(String, String)]) -> Result<(), ServicesError> {
+        for (name, value) in entries {
+            self.save_value(name, Self::is_secret(name), value)?;
+        }
+        Ok(())
+    }
+
+    // Encrypts secrets stored in plaintext, i.e. by versions without encryption.
+    // Fails if a stored secret can't be decrypted (wrong or missing key-encryption key): the keys
+    // would otherwise be treated as missing and replaced with new ones.
+    pub fn encrypt_plaintext_secrets(&self) -> Result<(), DaoError> {
+        for key in PREFERENCE_KEYS.iter().filter(|key| key.is_secret()) {
+            self.encrypt_if_plaintext(key.name())?;
+        }
+        Ok(())
+    }
+
+    // The stored value is encrypted as it is, without decoding it: values that can't be decoded
+    // (e.g. of a newer format) aren't lost.
+    fn encrypt_if_plaintext(&self, name: &str) -> Result<(), DaoError> {
+        if let Some(stored) = self.dao.load(name)? {
+            if self.cipher.is_some() && !SecretCipher::is_encrypted(&stored) {
+                info!("Encrypting plaintext secret: {}", name);
+                self.save_value(name, true, &stored)?;
+            } else {
+                self.load_value(name, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load_value(&self, name: &str, secret: bool) -> Result<Option<String>, DaoError> {
+        let value = match self.dao.load(name)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if !secret {
+            return Ok(Some(value));
+        }
+
+        match (&self.cipher, SecretCipher::is_encrypted(&value)) {
+            (Some(cipher), true) => {
+                let bytes = cipher.decrypt(&value).map_err(|error| {
+                    DaoError::InvalidData(format!(
+                        "Couldn't decrypt: {}. Wrong key? {:?}",
+                        name, error
+                    ))
+                })?;
+                let value = String::from_utf8(bytes)
+                    .map_err(|error| DaoError::InvalidData(format!("{}: {}", name, error)))?;
+                Ok(Some(value))
+            }
+            (None, true) => Err(DaoError::InvalidData(format!(
+                "Secret: {} is encrypted, but there's no key to decrypt it",
+                name
+            ))),
+            // Plaintext: encrypted at bootstrap if there's a cipher
+            (_, false) => Ok(Some(value)),
+        }
+    }
+
+    fn save_value(&self, name: &str, secret: bool, value: &str) -> Result<(), DaoError> {
+        match &self.cipher {
+            Some(cipher) if secret => {
+                let encrypted = cipher.encrypt(value.as_bytes()).map_err(|error| {
+                    DaoError::InvalidData(format!("Couldn't encrypt: {}: {:?}", name, error))
+                })?;
+                self.dao.save(name, &encrypted)
+            }
+            _ => self.dao.save(name, value),
+        }
+    }
+
+    fn is_secret(name: &str) -> bool {
+        PREFERENCE_KEYS
+            .iter()
+            .any(|key| key.is_secret() && key.name() == name)
+    }
+
+    // The default source keeps the key used before there were multiple sources.
+    fn last_completed_reports_interval_key(
+        source_id: &str,
+    ) -> PreferenceKey<Option<ReportsInterval>> {
+        if source_id == DEFAULT_REPORTS_SOURCE_ID {
+            LAST_COMPLETED_REPORTS_INTERVAL
+        } else {
+            LAST_COMPLETED_REPORTS_INTERVAL.named(format!(
+                "{}_{}",
+                LAST_COMPLETED_REPORTS_INTERVAL.name, source_id
+            ))
+        }
+    }
 }
 
 impl Preferences for PreferencesImpl {
-    fn last_completed_reports_interval(&self) -> Option<ReportsInterval> {
-        let str = self.dao.load("last_completed_reports_interval");
-        str.map(|str| {
-            let res = serde_json::from_str(str.as_ref());
-            expect_log!(res, "Invalid interval str")
-        })
+    fn last_completed_reports_interval(
+        &self,
+        source_id: &str,
+    ) -> Result<Option<ReportsInterval>, DaoError> {
+        self.get(&Self::last_completed_reports_interval_key(source_id))
     }
 
-    fn set_last_completed_reports_interval(&self, value: ReportsInterval) {
-        let res = serde_json::to_string(&value);
-        let str = expect_log!(res, "Couldn't serialize interval");
-        self.dao
-            .save("last_completed_reports_interval", str.as_ref())
+    fn set_last_completed_reports_interval(
+        &self,
+        source_id: &str,
+        value: ReportsInterval,
+    ) -> Result<(), DaoError> {
+        self.set(
+            &Self::last_completed_reports_interval_key(source_id),
+            &Some(value),
+        )
     }
 
-    fn authorization_key(&self) -> Option<[u8; 32]> {
-        let str = self.dao.load("authorization_key");
-        let bytes = str.map(|str| {
-            let res = hex::decode(str);
-            expect_log!(res, "Invalid interval str")
-        });
-        bytes.map(|bytes| byte_vec_to_32_byte_array(bytes))
+    fn authorization_key(&self) -> Result<Option<[u8; 32]>, DaoError> {
+        self.get(&AUTHORIZATION_KEY)
     }
 
-    fn set_autorization_key(&self, value: [u8; 32]) {
-        self.dao
-            .save("authorization_key", hex::encode(&value).as_ref())
+    fn set_autorization_key(&self, value: [u8; 32]) -> Result<(), DaoError> {
+        self.set(&AUTHORIZATION_KEY, &Some(value))
     }
 
-    fn tck(&self) -> Option<TckBytesWrapper> {
-        let str = self.dao.load("tck");
-        str.map(|str| {
-            let res = serde_json::from_str(str.as_ref());
-            expect_log!(res, "Invalid tck wrapper str")
-        })
+    fn tck(&self) -> Result<Option<TckBytesWrapper>, DaoError> {
+        self.get(&TCK)
+    }
+
+    fn set_tck(&self, value: TckBytesWrapper) -> Result<(), DaoError> {
+        self.set(&TCK, &Some(value))
+    }
+
+    fn authorization_key_created(&self) -> Result<Option<u64>, DaoError> {
+        self.get(&AUTHORIZATION_KEY_CREATED)
+    }
+
+    fn set_authorization_key_created(&self, value: u64) -> Result<(), DaoError> {
+        self.set(&AUTHORIZATION_KEY_CREATED, &Some(value))
     }
 
-    fn set_tck(&self, value: TckBytesWrapper) {
-        let res = serde_json::to_string(&value);
-        let str = expect_log!(res, "Couldn't serialize tck wrapper");
-        self.dao.save("tck", str.as_ref())
+    fn retired_authorization_keys(&self) -> Result<Vec<RetiredAuthorizationKey>, DaoError> {
+        self.get(&RETIRED_AUTHORIZATION_KEYS)
+    }
+
+    fn set_retired_authorization_keys(
+        &self,
+        value: Vec<RetiredAuthorizationKey>,
+    ) -> Result<(), DaoError> {
+        self.set(&RETIRED_AUTHORIZATION_KEYS, &value)
+    }
+
+    fn last_report_start(&self) -> Result<Option<u64>, DaoError> {
+        self.get(&LAST_REPORT_START)
+    }
+
+    fn set_last_report_start(&self, value: Option<u64>) -> Result<(), DaoError> {
+        self.set(&LAST_REPORT_START, &value)
+    }
+
+    fn verification_token(&self) -> Result<Option<String>, DaoError> {
+        self.get(&VERIFICATION_TOKEN)
+    }
+
+    fn set_verification_token(&self, value: Option<String>) -> Result<(), DaoError> {
+        self.set(&VERIFICATION_TOKEN, &value)
     }
 }
 
@@ -225,123 +741,523 @@ pub struct PreferencesTckMock {
 }
 
 impl Preferences for PreferencesTckMock {
-    fn last_completed_reports_interval(&self) -> Option<ReportsInterval> {
+    fn last_completed_reports_interval(
+        &self,
+        _: &str,
+    ) -> Result<Option<ReportsInterval>, DaoError> {
         let reports_interval = ReportsInterval {
             number: 8899222,
             length: 12232,
         };
-        Option::Some(reports_interval)
+        Ok(Some(reports_interval))
     }
 
-    fn set_last_completed_reports_interval(&self, _: ReportsInterval) {
-        return;
+    fn set_last_completed_reports_interval(
+        &self,
+        _: &str,
+        _: ReportsInterval,
+    ) -> Result<(), DaoError> {
+        Ok(())
     }
 
-    fn authorization_key(&self) -> std::option::Option<[u8; 32]> {
+    fn authorization_key(&self) -> Result<Option<[u8; 32]>, DaoError> {
         let bytes = [
             42, 118, 64, 131, 236, 36, 122, 23, 13, 108, 73, 171, 102, 145, 66, 91, 157, 105, 195,
             126, 139, 162, 15, 31, 0, 22, 31, 230, 242, 241, 225, 85,
         ];
-        return Option::Some(bytes);
+        Ok(Some(bytes))
+    }
+
+    fn set_autorization_key(&self, _value: [u8; 32]) -> Result<(), DaoError> {
+        Ok(())
+    }
+
+    fn tck(&self) -> Result<Option<TckBytesWrapper>, DaoError> {
+        Ok(Some(self.tck_bytes))
+    }
+
+    fn set_tck(&self, _value: TckBytesWrapper) -> Result<(), DaoError> {
+        Ok(())
+    }
+
+    fn authorization_key_created(&self) -> Result<Option<u64>, DaoError> {
+        Ok(None)
+    }
+
+    fn set_authorization_key_created(&self, _value: u64) -> Result<(), DaoError> {
+        Ok(())
+    }
+
+    fn retired_authorization_keys(&self) -> Result<Vec<RetiredAuthorizationKey>, DaoError> {
+        Ok(vec![])
+    }
+
+    fn set_retired_authorization_keys(
+        &self,
+        _value: Vec<RetiredAuthorizationKey>,
+    ) -> Result<(), DaoError> {
+        Ok(())
+    }
+
+    fn last_report_start(&self) -> Result<Option<u64>, DaoError> {
+        Ok(None)
     }
 
-    fn set_autorization_key(&self, _value: [u8; 32]) {
-        return;
+    fn set_last_report_start(&self, _value: Option<u64>) -> Result<(), DaoError> {
+        Ok(())
     }
 
-    fn tck(&self) -> std::option::Option<TckBytesWrapper> {
-        Some(self.tck_bytes)
+    fn verification_token(&self) -> Result<Option<String>, DaoError> {
+        Ok(None)
     }
 
-    fn set_tck(&self, _value: TckBytesWrapper) {
-        return;
+    fn set_verification_token(&self, _value: Option<String>) -> Result<(), DaoError> {
+        Ok(())
     }
 }
 
 #[derive(Clone)]
 pub struct PreferencesNoopMock {}
 impl Preferences for PreferencesNoopMock {
-    fn last_completed_reports_interval(&self) -> Option<ReportsInterval> {
-        Option::None
+    fn last_completed_reports_interval(
+        &self,
+        _: &str,
+    ) -> Result<Option<ReportsInterval>, DaoError> {
+        Ok(None)
     }
 
-    fn set_last_completed_reports_interval(&self, _: ReportsInterval) {}
+    fn set_last_completed_reports_interval(
+        &self,
+        _: &str,
+        _: ReportsInterval,
+    ) -> Result<(), DaoError> {
+        Ok(())
+    }
+
+    fn authorization_key(&self) -> Result<Option<[u8; 32]>, DaoError> {
+        Ok(None)
+    }
+
+    fn set_autorization_key(&self, _value: [u8; 32]) -> Result<(), DaoError> {
+        Ok(())
+    }
+
+    fn tck(&self) -> Result<Option<TckBytesWrapper>, DaoError> {
+        Ok(None)
+    }
+
+    fn set_tck(&self, _value: TckBytesWrapper) -> Result<(), DaoError> {
+        Ok(())
+    }
+
+    fn authorization_key_created(&self) -> Result<Option<u64>, DaoError> {
+        Ok(None)
+    }
+
+    fn set_authorization_key_created(&self, _value: u64) -> Result<(), DaoError> {
+        Ok(())
+    }
+
+    fn retired_authorization_keys(&self) -> Result<Vec<RetiredAuthorizationKey>, DaoError> {
+        Ok(vec![])
+    }
+
+    fn set_retired_authorization_keys(
+        &self,
+        _value: Vec<RetiredAuthorizationKey>,
+    ) -> Result<(), DaoError> {
+        Ok(())
+    }
 
-    fn authorization_key(&self) -> std::option::Option<[u8; 32]> {
-        Option::None
+    fn last_report_start(&self) -> Result<Option<u64>, DaoError> {
+        Ok(None)
     }
 
-    fn set_autorization_key(&self, _value: [u8; 32]) {}
+    fn set_last_report_start(&self, _value: Option<u64>) -> Result<(), DaoError> {
+        Ok(())
+    }
 
-    fn tck(&self) -> std::option::Option<TckBytesWrapper> {
-        Option::None
+    fn verification_token(&self) -> Result<Option<String>, DaoError> {
+        Ok(None)
     }
 
-    fn set_tck(&self, _value: TckBytesWrapper) {}
+    fn set_verification_token(&self, _value: Option<String>) -> Result<(), DaoError> {
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tcn_ext::tcn_keys::TckBytesWrapperExt;
+    use crate::{
+        database_encryption::tests::{remove_db_files, temp_db_path},
+        encryption::tests::test_cipher,
+        migrations::{tests::test_database, MIGRATIONS},
+        reports_interval::UnixTime,
+        reports_updater::{ObservedTcn, TcnDao, TcnDaoImpl},
+        tcn_ext::tcn_keys::TckBytesWrapperExt,
+    };
+    use std::{
+        panic::AssertUnwindSafe,
+        sync::{atomic::AtomicBool, mpsc},
+        thread,
+        time::Duration,
+    };
+    use tcn::TemporaryContactNumber;
 
     #[test]
     fn test_saves_last_completed_reports_interval() {
-        let database = Arc::new(Database::new(
-            Connection::open_in_memory().expect("Couldn't create database!"),
-        ));
+        let database = test_database();
         let preferences_dao = PreferencesDao::new(database.clone());
-        let preferences = PreferencesImpl {
-            dao: preferences_dao,
-        };
+        let preferences = PreferencesImpl::new(preferences_dao, None);
 
         let interval = ReportsInterval {
             number: 1,
             length: 10,
         };
-        preferences.set_last_completed_reports_interval(interval);
+        preferences
+            .set_last_completed_reports_interval(DEFAULT_REPORTS_SOURCE_ID, interval)
+            .unwrap();
 
         assert_eq!(
-            preferences.last_completed_reports_interval().unwrap(),
-            interval
+            preferences
+                .last_completed_reports_interval(DEFAULT_REPORTS_SOURCE_ID)
+                .unwrap(),
+            Some(interval)
         );
+        assert!(preferences
+            .last_completed_reports_interval("other")
+            .unwrap()
+            .is_none());
     }
 
     #[test]
     fn test_saves_tck() {
-        let database = Arc::new(Database::new(
-            Connection::open_in_memory().expect("Couldn't create database!"),
-        ));
+        let database = test_database();
         let preferences_dao = PreferencesDao::new(database.clone());
-        let preferences = PreferencesImpl {
-            dao: preferences_dao,
-        };
+        let preferences = PreferencesImpl::new(preferences_dao, None);
 
         let tck_bytes_wrapper = create_test_tck();
 
-        preferences.set_tck(tck_bytes_wrapper);
+        preferences.set_tck(tck_bytes_wrapper).unwrap();
 
-        assert_eq!(preferences.tck().unwrap(), tck_bytes_wrapper);
+        assert_eq!(preferences.tck().unwrap().unwrap(), tck_bytes_wrapper);
     }
 
     #[test]
     fn test_saves_autorization_key() {
-        let database = Arc::new(Database::new(
-            Connection::open_in_memory().expect("Couldn't create database!"),
-        ));
+        let database = test_database();
         let preferences_dao = PreferencesDao::new(database.clone());
-        let preferences = PreferencesImpl {
-            dao: preferences_dao,
-        };
+        let preferences = PreferencesImpl::new(preferences_dao, None);
 
         let rak_bytes = [
             42, 118, 64, 131, 236, 36, 122, 23, 13, 108, 73, 171, 102, 145, 66, 91, 157, 105, 195,
             126, 139, 162, 15, 31, 0, 22, 31, 230, 242, 241, 225, 85,
         ];
 
-        preferences.set_autorization_key(rak_bytes);
+        preferences.set_autorization_key(rak_bytes).unwrap();
 
-        assert_eq!(preferences.authorization_key().unwrap(), rak_bytes);
+        assert_eq!(preferences.authorization_key().unwrap().unwrap(), rak_bytes);
+    }
+
+    #[test]
+    fn stores_secrets_encrypted() {
+        let database = test_database();
+        let preferences =
+            PreferencesImpl::new(PreferencesDao::new(database.clone()), Some(test_cipher()));
+        let tck_bytes_wrapper = create_test_tck();
+
+        preferences.set_autorization_key([1; 32]).unwrap();
+        preferences.set_tck(tck_bytes_wrapper).unwrap();
+
+        let dao = PreferencesDao::new(database.clone());
+        assert!(SecretCipher::is_encrypted(
+            &dao.load("authorization_key").unwrap().unwrap()
+        ));
+        assert!(SecretCipher::is_encrypted(
+            &dao.load("tck").unwrap().unwrap()
+        ));
+        assert_eq!(preferences.authorization_key().unwrap().unwrap(), [1; 32]);
+        assert_eq!(preferences.tck().unwrap().unwrap(), tck_bytes_wrapper);
+    }
+
+    #[test]
+    fn encrypts_plaintext_secrets_in_place() {
+        let database = test_database();
+        let plaintext_preferences =
+            PreferencesImpl::new(PreferencesDao::new(database.clone()), None);
+        plaintext_preferences.set_autorization_key([1; 32]).unwrap();
+        plaintext_preferences.set_tck(create_test_tck()).unwrap();
+
+        let preferences =
+            PreferencesImpl::new(PreferencesDao::new(database.clone()), Some(test_cipher()));
+        preferences.encrypt_plaintext_secrets().unwrap();
+
+        let dao = PreferencesDao::new(database.clone());
+        assert!(SecretCipher::is_encrypted(
+            &dao.load("authorization_key").unwrap().unwrap()
+        ));
+        assert!(SecretCipher::is_encrypted(
+            &dao.load("tck").unwrap().unwrap()
+        ));
+        assert_eq!(preferences.authorization_key().unwrap().unwrap(), [1; 32]);
+        assert_eq!(preferences.tck().unwrap().unwrap(), create_test_tck());
+    }
+
+    #[test]
+    fn encrypts_plaintext_secrets_as_stored() {
+        let database = test_database();
+        let dao = PreferencesDao::new(database.clone());
+        dao.save("authorization_key", "not a key").unwrap();
+
+        let preferences =
+            PreferencesImpl::new(PreferencesDao::new(database.clone()), Some(test_cipher()));
+        preferences.encrypt_plaintext_secrets().unwrap();
+
+        let stored = dao.load("authorization_key").unwrap().unwrap();
+        assert!(SecretCipher::is_encrypted(&stored));
+        assert_eq!(
+            preferences
+                .load_value("authorization_key", true)
+                .unwrap()
+                .unwrap(),
+            "not a key"
+        );
+    }
+
+    #[test]
+    fn encrypted_secret_fails_with_wrong_key() {
+        let database = test_database();
+        let preferences =
+            PreferencesImpl::new(PreferencesDao::new(database.clone()), Some(test_cipher()));
+        preferences.set_autorization_key([1; 32]).unwrap();
+
+        let preferences = PreferencesImpl::new(
+            PreferencesDao::new(database.clone()),
+            Some(SecretCipher::new([9; 32])),
+        );
+
+        assert!(preferences.authorization_key().is_err());
+        assert!(preferences.encrypt_plaintext_secrets().is_err());
+        // Also without a key
+        let preferences = PreferencesImpl::new(PreferencesDao::new(database.clone()), None);
+        assert!(preferences.encrypt_plaintext_secrets().is_err());
+    }
+
+    #[test]
+    fn reads_values_stored_before_versioned_format() {
+        let database = test_database();
+        let dao = PreferencesDao::new(database.clone());
+        dao.save(
+            "last_completed_reports_interval",
+            r#"{"number":1,"length":10}"#,
+        )
+        .unwrap();
+        dao.save("authorization_key", &hex::encode([1; 32]))
+            .unwrap();
+
+        let preferences = PreferencesImpl::new(PreferencesDao::new(database.clone()), None);
+
+        assert_eq!(
+            preferences
+                .last_completed_reports_interval(DEFAULT_REPORTS_SOURCE_ID)
+                .unwrap(),
+            Some(ReportsInterval {
+                number: 1,
+                length: 10
+            })
+        );
+        assert_eq!(preferences.authorization_key().unwrap(), Some([1; 32]));
+    }
+
+    #[test]
+    fn malformed_value_returns_default() {
+        let database = test_database();
+        let dao = PreferencesDao::new(database.clone());
+        dao.save("authorization_key", "not a key").unwrap();
+        dao.save("tck", r#"{"version":1,"value":[1,2]}"#).unwrap();
+
+        let preferences = PreferencesImpl::new(PreferencesDao::new(database.clone()), None);
+
+        assert!(preferences.authorization_key().unwrap().is_none());
+        assert!(preferences.tck().unwrap().is_none());
+    }
+
+    #[test]
+    fn notifies_observers() {
+        let database = test_database();
+        let preferences = PreferencesImpl::new(PreferencesDao::new(database.clone()), None);
+        let key: PreferenceKey<u32> = PreferenceKey::new("observed", || 0);
+        let observed = Arc::new(Mutex::new(vec![]));
+        let observed_clone = observed.clone();
+        preferences.observe(&key, move |value| observed_clone.lock().push(value));
+
+        assert_eq!(preferences.get(&key).unwrap(), 0);
+        preferences.set(&key, &1).unwrap();
+        preferences.set(&key, &2).unwrap();
+        preferences.set_autorization_key([1; 32]).unwrap();
+
+        assert_eq!(preferences.get(&key).unwrap(), 2);
+        assert_eq!(*observed.lock(), vec![1, 2]);
+
+        // After the commit, only if committed
+        let result: Result<(), DaoError> = database.transaction(|_| {
+            preferences.set(&key, &3)?;
+            assert_eq!(*observed.lock(), vec![1, 2]);
+            Err(DaoError::InvalidData("Test".to_owned()))
+        });
+        assert!(result.is_err());
+        assert_eq!(preferences.get(&key).unwrap(), 2);
+        database.transaction(|_| preferences.set(&key, &4)).unwrap();
+        assert_eq!(*observed.lock(), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn writes_while_reading() {
+        let path = temp_db_path();
+        let database = Arc::new(Database::open(&path, None, 2).unwrap());
+        database.migrate(MIGRATIONS).unwrap();
+        let tcn_dao = Arc::new(TcnDaoImpl::new(database.clone()));
+        tcn_dao.save(&observed_tcn(0)).unwrap();
+
+        // The read is blocked (inside the row callback) until the write finished
+        let (written_sender, written_receiver) = mpsc::channel::<()>();
+        let (reading_sender, reading_receiver) = mpsc::channel::<()>();
+        let read_database = database.clone();
+        let reader = thread::spawn(move || {
+            read_database
+                .query("select tcn from tcn", NO_PARAMS, |_| {
+                    reading_sender.send(()).unwrap();
+                    Ok(written_receiver
+                        .recv_timeout(Duration::from_secs(5))
+                        .is_ok())
+                })
+                .unwrap()
+        });
+
+        reading_receiver.recv().unwrap();
+        tcn_dao.save(&observed_tcn(1)).unwrap();
+        written_sender.send(()).unwrap();
+
+        // The read doesn't see the write, which happened after it started
+        assert_eq!(reader.join().unwrap(), vec![true]);
+        assert_eq!(tcn_dao.all().unwrap().len(), 2);
+        drop(tcn_dao);
+        drop(database);
+        remove_db_files(&path);
+    }
+
+    #[test]
+    fn nested_transactions_are_rolled_back_with_the_outer_one() {
+        let path = temp_db_path();
+        let database = Arc::new(Database::open(&path, None, 2).unwrap());
+        database.migrate(MIGRATIONS).unwrap();
+        let dao = PreferencesDao::new(database.clone());
+
+        let result: Result<(), DaoError> = database.transaction(|_| {
+            dao.save("a", "1")?;
+            // Reads in the transaction see its writes
+            assert_eq!(dao.load("a")?, Some("1".to_owned()));
+            database.transaction(|tx| {
+                tx.execute(
+                    "insert into preferences(key, value) values('b', '2')",
+                    NO_PARAMS,
+                )
+            })?;
+            Err(DaoError::InvalidData("Failed".to_owned()))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(dao.load("a").unwrap(), None);
+        assert_eq!(dao.load("b").unwrap(), None);
+        drop(dao);
+        drop(database);
+        remove_db_files(&path);
+    }
+
+    #[test]
+    fn transaction_ends_if_it_panics() {
+        let path = temp_db_path();
+        let database = Arc::new(Database::open(&path, None, 2).unwrap());
+        database.migrate(MIGRATIONS).unwrap();
+        let dao = PreferencesDao::new(database.clone());
+
+        let notified = Arc::new(AtomicBool::new(false));
+        let notified_clone = notified.clone();
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            database.transaction(|_| -> Result<(), DaoError> {
+                dao.save("a", "1")?;
+                database.after_commit(move || notified_clone.store(true, Ordering::SeqCst));
+                panic!("Test");
+            })
+        }));
+
+        assert!(result.is_err());
+        assert!(!database.in_transaction());
+        assert_eq!(dao.load("a").unwrap(), None);
+        database.transaction(|_| dao.save("b", "2")).unwrap();
+        assert!(!notified.load(Ordering::SeqCst));
+        drop(dao);
+        drop(database);
+        remove_db_files(&path);
+    }
+
+    #[test]
+    fn concurrent_reads_and_writes_stress() {
+        let path = temp_db_path();
+        let database = Arc::new(Database::open(&path, None, 3).unwrap());
+        database.migrate(MIGRATIONS).unwrap();
+        let tcn_dao = Arc::new(TcnDaoImpl::new(database.clone()));
+        let writes_per_thread = 200;
+        let reading = Arc::new(AtomicBool::new(true));
+
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let tcn_dao = tcn_dao.clone();
+                let reading = reading.clone();
+                thread::spawn(move || {
+                    while reading.load(Ordering::Relaxed) {
+                        tcn_dao.all().unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        let writers: Vec<_> = (0..2u8)
+            .map(|writer| {
+                let tcn_dao = tcn_dao.clone();
+                thread::spawn(move || {
+                    for index in 0..writes_per_thread {
+                        tcn_dao
+                            .save(&ObservedTcn {
+                                tcn: TemporaryContactNumber([writer; 16]),
+                                time: UnixTime { value: index },
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        reading.store(false, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(tcn_dao.all().unwrap().len(), 2 * writes_per_thread as usize);
+        drop(tcn_dao);
+        drop(database);
+        remove_db_files(&path);
+    }
+
+    fn observed_tcn(byte: u8) -> ObservedTcn {
+        ObservedTcn {
+            tcn: TemporaryContactNumber([byte; 16]),
+            time: UnixTime { value: 1000 },
+        }
     }
 
     fn create_test_tck() -> TckBytesWrapper {
@@ -11,12 +11,82 @@ use crate::expect_log;
 use log::error;
 use std::convert::TryInto;
 
+pub const MEMO_VERSION: u16 = 1;
+// The kind is encoded in the version. Clients that don't know it would read a report: uploads
+// of these kinds have a marker that they can't decode (MemoKind::marker).
+pub const MEMO_VERSION_REVOCATION: u16 = 2;
+pub const MEMO_VERSION_AMENDMENT: u16 = 3;
+// Set in the version of reports of a verified positive test. These memos end with the SHA-256 of
+// the verification token, signed with the report: the backend accepts them only if uploaded with
+// that token, so it can't be used for other reports. Receivers can't check it.
+pub const MEMO_VERIFIED_FLAG: u16 = 0x100;
+pub const TOKEN_HASH_SIZE: usize = 32;
+pub const MAX_MARKER_SIZE: usize = 11;
+
 pub struct Memo {
     pub bytes: Vec<u8>,
 }
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MemoKind {
+    Report,
+    // Withdraws the reports signed with the same authorization key
+    Revocation,
+    // Replaces the reports signed with the same authorization key
+    Amendment,
+}
+
+impl MemoKind {
+    fn version(&self) -> u16 {
+        match self {
+            MemoKind::Report => MEMO_VERSION,
+            MemoKind::Revocation => MEMO_VERSION_REVOCATION,
+            MemoKind::Amendment => MEMO_VERSION_AMENDMENT,
+        }
+    }
+
+    // Prefixed to the uploaded (base64) report. It isn't base64, so clients that don't know
+    // revocations and amendments fail to decode them, instead of reading them as reports.
+    pub fn marker(&self) -> &'static str {
+        match self {
+            MemoKind::Report => "",
+            MemoKind::Revocation => "revocation:",
+            MemoKind::Amendment => "amendment:",
+        }
+    }
+
+    // The uploaded report without its marker. The kind is read from the memo.
+    pub fn without_marker(report: &str) -> &str {
+        [MemoKind::Revocation, MemoKind::Amendment]
+            .iter()
+            .map(|kind| kind.marker())
+            .find(|marker| report.starts_with(marker))
+            .map_or(report, |marker| &report[marker.len()..])
+    }
+
+    // Unknown versions are read as reports
+    fn with_version(version: u16) -> MemoKind {
+        match version {
+            MEMO_VERSION_REVOCATION => MemoKind::Revocation,
+            MEMO_VERSION_AMENDMENT => MemoKind::Amendment,
+            _ => MemoKind::Report,
+        }
+    }
+}
+
 pub trait MemoMapper {
     fn to_memo(&self, report: PublicReport) -> Memo;
+    // With token_hash the memo is flagged as verified
+    fn to_memo_of_kind(
+        &self,
+        kind: MemoKind,
+        token_hash: Option<[u8; TOKEN_HASH_SIZE]>,
+        report: PublicReport,
+    ) -> Memo;
     fn to_report(&self, memo: Memo) -> PublicReport;
+    fn kind(&self, memo: &Memo) -> MemoKind;
+    fn is_verified(&self, memo: &Memo) -> bool;
+    fn token_hash(&self, memo: &Memo) -> Option<[u8; TOKEN_HASH_SIZE]>;
 }
 
 pub struct MemoMapperImpl {}
@@ -28,14 +98,38 @@ impl MemoMapperImpl {
     const COUGH_SEVERITY_MAPPER: CoughSeverityMapper = CoughSeverityMapper {};
     const FEVER_SEVERITY_MAPPER: FeverSeverityMapper = FeverSeverityMapper {};
     const BOOLEAN_MAPPER: BoolMapper = BoolMapper {};
+
+    fn version(memo: &Memo) -> Option<u16> {
+        let bits: Vec<bool> = memo
+            .bytes
+            .iter()
+            .flat_map(|byte| byte.to_bits().bits)
+            .collect();
+        if bits.len() < Self::VERSION_MAPPER.bit_count() {
+            return None;
+        }
+        Some(extract(&bits, &Self::VERSION_MAPPER, 0).value)
+    }
 }
 
 impl MemoMapper for MemoMapperImpl {
     fn to_memo(&self, report: PublicReport) -> Memo {
-        let memo_version: u16 = 1;
+        self.to_memo_of_kind(MemoKind::Report, None, report)
+    }
 
+    fn to_memo_of_kind(
+        &self,
+        kind: MemoKind,
+        token_hash: Option<[u8; TOKEN_HASH_SIZE]>,
+        report: PublicReport,
+    ) -> Memo {
+        let version = if token_hash.is_some() {
+            kind.version() | MEMO_VERIFIED_FLAG
+        } else {
+            kind.version()
+        };
         let bits = vec![
-            Self::VERSION_MAPPER.to_bits(memo_version),
+            Self::VERSION_MAPPER.to_bits(version),
             Self::TIME_MAPPER.to_bits(report.report_time),
             Self::TIME_USER_INPUT_MAPPER.to_bits(report.earliest_symptom_time),
             Self::COUGH_SEVERITY_MAPPER.to_bits(report.cough_severity),
@@ -49,12 +143,15 @@ impl MemoMapper for MemoMapperImpl {
             Self::BOOLEAN_MAPPER.to_bits(report.no_symptoms),
         ];
 
-        Memo {
-            bytes: bits
-                .into_iter()
-                .fold(BitVector { bits: vec![] }, |acc, e| acc.concat(e))
-                .as_u8_array(),
+        let mut bytes = bits
+            .into_iter()
+            .fold(BitVector { bits: vec![] }, |acc, e| acc.concat(e))
+            .as_u8_array();
+        // After the fields: ignored by clients that don't know it
+        if let Some(token_hash) = token_hash {
+            bytes.extend_from_slice(&token_hash);
         }
+        Memo { bytes }
     }
 
     fn to_report(&self, memo: Memo) -> PublicReport {
@@ -66,7 +163,7 @@ impl MemoMapper for MemoMapperImpl {
 
         let mut next: usize = 0;
 
-        // Version for now not handled
+        // The version is read with kind and is_verified
         let _ = extract(&bits, &Self::VERSION_MAPPER, next).value(|v| next += v);
 
         let report_time = extract(&bits, &Self::TIME_MAPPER, next).value(|v| next += v);
@@ -99,6 +196,28 @@ impl MemoMapper for MemoMapperImpl {
             no_symptoms,
         }
     }
+
+    fn kind(&self, memo: &Memo) -> MemoKind {
+        Self::version(memo).map_or(MemoKind::Report, |version| {
+            MemoKind::with_version(version & !MEMO_VERIFIED_FLAG)
+        })
+    }
+
+    fn is_verified(&self, memo: &Memo) -> bool {
+        match Self::version(memo) {
+            Some(version) => version & MEMO_VERIFIED_FLAG != 0,
+            None => false,
+        }
+    }
+
+    fn token_hash(&self, memo: &Memo) -> Option<[u8; TOKEN_HASH_SIZE]> {
+        if !self.is_verified(memo) || memo.bytes.len() < TOKEN_HASH_SIZE {
+            return None;
+        }
+        memo.bytes[memo.bytes.len() - TOKEN_HASH_SIZE..]
+            .try_into()
+            .ok()
+    }
 }
 
 struct ExtractResult<T> {
@@ -179,4 +298,38 @@ mod tests {
 
         assert_eq!(mapped_report, report.clone());
     }
+
+    #[test]
+    fn maps_memo_kind() {
+        let memo_mapper = MemoMapperImpl {};
+        let report = PublicReport {
+            report_time: UnixTime { value: 1589209754 },
+            earliest_symptom_time: UserInput::None,
+            fever_severity: FeverSeverity::Mild,
+            cough_severity: CoughSeverity::None,
+            breathlessness: true,
+            muscle_aches: false,
+            loss_smell_or_taste: false,
+            diarrhea: false,
+            runny_nose: false,
+            other: false,
+            no_symptoms: false,
+        };
+
+        let memo = memo_mapper.to_memo(report.clone());
+        assert_eq!(memo_mapper.kind(&memo), MemoKind::Report);
+        assert!(!memo_mapper.is_verified(&memo));
+
+        assert_eq!(memo_mapper.token_hash(&memo), None);
+
+        for kind in &[MemoKind::Report, MemoKind::Revocation, MemoKind::Amendment] {
+            for token_hash in &[None, Some([7; TOKEN_HASH_SIZE])] {
+                let memo = memo_mapper.to_memo_of_kind(*kind, *token_hash, report.clone());
+                assert_eq!(memo_mapper.kind(&memo), *kind);
+                assert_eq!(memo_mapper.is_verified(&memo), token_hash.is_some());
+                assert_eq!(memo_mapper.token_hash(&memo), *token_hash);
+                assert_eq!(memo_mapper.to_report(memo), report);
+            }
+        }
+    }
 }
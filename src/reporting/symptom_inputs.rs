@@ -1,7 +1,18 @@
-use super::{memo::MemoMapper, public_report::*};
+use super::{
+    memo::{MemoKind, MemoMapper},
+    public_report::*,
+};
 use crate::{
-    errors::ServicesError, expect_log, networking::TcnApi, reports_interval::UnixTime,
-    tcn_ext::tcn_keys::TcnKeys,
+    errors::{DaoError, ServicesError},
+    expect_log,
+    networking::TcnApi,
+    preferences::Preferences,
+    reports_interval::UnixTime,
+    tcn_ext::{
+        emitted_tcns::ReportPreview,
+        tcn_keys::{ReportExt, TcnKeys, REPORT_PERIOD_SECS},
+    },
+    verification::{token_hash, ReportVerification, Verifier},
 };
 use log::*;
 use serde::{Deserialize, Serialize};
@@ -142,44 +153,184 @@ pub struct EarliestSymptom {
     pub time: UserInput<UnixTime>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReportingConfig {
+    // Reports include the TCNs since this long before the earliest symptom, when the user
+    // may have been contagious without symptoms.
+    pub pre_symptomatic_margin_secs: u64,
+}
+
+impl Default for ReportingConfig {
+    fn default() -> Self {
+        Self {
+            pre_symptomatic_margin_secs: 2 * 24 * 60 * 60,
+        }
+    }
+}
+
 pub trait SymptomInputsSubmitter<T: MemoMapper, U: TcnKeys, V: TcnApi> {
     fn submit_inputs(&self, inputs: SymptomInputs) -> Result<(), ServicesError>;
+    // What submitting the inputs would share, without sending anything.
+    fn preview_inputs(&self, inputs: SymptomInputs) -> Result<ReportPreview, ServicesError>;
+    // Withdraws the submitted reports, e.g. after a negative test.
+    fn revoke_report(&self) -> Result<(), ServicesError>;
+    // Replaces the submitted reports with the inputs.
+    fn amend_report(&self, inputs: SymptomInputs) -> Result<(), ServicesError>;
+    // Exchanges a verification code of a positive test. The next report is sent as verified.
+    fn verify_code(&self, code: &str) -> Result<(), ServicesError>;
 }
 
-pub struct SymptomInputsSubmitterImpl<'a, T: MemoMapper, U: TcnKeys, V: TcnApi> {
+pub struct SymptomInputsSubmitterImpl<'a, T: MemoMapper, U: TcnKeys, V: TcnApi, W: Preferences> {
     pub memo_mapper: &'a T,
     pub tcn_keys: Arc<U>,
-    pub api: &'a V,
+    pub api: Arc<V>,
+    pub preferences: Arc<W>,
+    pub verifier: Arc<Verifier<W>>,
+    pub config: ReportingConfig,
 }
 
-impl<'a, T: MemoMapper, U: TcnKeys, V: TcnApi> SymptomInputsSubmitter<T, U, V>
-    for SymptomInputsSubmitterImpl<'a, T, U, V>
+impl<'a, T: MemoMapper, U: TcnKeys, V: TcnApi, W: Preferences> SymptomInputsSubmitter<T, U, V>
+    for SymptomInputsSubmitterImpl<'a, T, U, V, W>
 {
     fn submit_inputs(&self, inputs: SymptomInputs) -> Result<(), ServicesError> {
         if let Some(report) = PublicReport::with_inputs(inputs, UnixTime::now()) {
-            self.send_report(report)
+            let from = self.report_start(&report);
+            self.send_report(MemoKind::Report, report, &from)?;
+            self.store_report_start(&from)?;
+            Ok(())
         } else {
             debug!("Nothing to send.");
             Ok(())
         }
     }
+
+    fn preview_inputs(&self, inputs: SymptomInputs) -> Result<ReportPreview, ServicesError> {
+        let now = UnixTime::now().value;
+        match PublicReport::with_inputs(inputs, UnixTime { value: now }) {
+            Some(report) => Ok(self.tcn_keys.preview_report(&self.report_start(&report))?),
+            // Nothing would be sent
+            None => Ok(ReportPreview {
+                from: now,
+                emission_times: vec![],
+            }),
+        }
+    }
+
+    fn revoke_report(&self) -> Result<(), ServicesError> {
+        let from = self.submitted_report_start()?;
+        // Carries no symptoms, for clients that don't know revocations
+        let report = PublicReport {
+            report_time: UnixTime::now(),
+            earliest_symptom_time: UserInput::None,
+            fever_severity: FeverSeverity::None,
+            cough_severity: CoughSeverity::None,
+            breathlessness: false,
+            muscle_aches: false,
+            loss_smell_or_taste: false,
+            diarrhea: false,
+            runny_nose: false,
+            other: false,
+            no_symptoms: false,
+        };
+        self.send_report(MemoKind::Revocation, report, &from)?;
+        self.preferences.set_last_report_start(None)?;
+        Ok(())
+    }
+
+    fn amend_report(&self, inputs: SymptomInputs) -> Result<(), ServicesError> {
+        let submitted_from = self.submitted_report_start()?;
+        let report = PublicReport::with_inputs(inputs, UnixTime::now()).ok_or_else(|| {
+            ServicesError::General("No inputs to amend the report with".to_owned())
+        })?;
+        // Signed with the keys of the submitted report, so the contacts alerted by it are updated
+        let from = UnixTime {
+            value: submitted_from.value.min(self.report_start(&report).value),
+        };
+        self.send_report(MemoKind::Amendment, report, &from)?;
+        self.store_report_start(&from)?;
+        Ok(())
+    }
+
+    fn verify_code(&self, code: &str) -> Result<(), ServicesError> {
+        self.verifier.verify_code(code)
+    }
 }
 
-impl<'a, T: MemoMapper, U: TcnKeys, V: TcnApi> SymptomInputsSubmitterImpl<'a, T, U, V> {
-    fn send_report(&self, report: PublicReport) -> Result<(), ServicesError> {
-        debug!("Will send public report: {:?}", report);
+impl<'a, T: MemoMapper, U: TcnKeys, V: TcnApi, W: Preferences>
+    SymptomInputsSubmitterImpl<'a, T, U, V, W>
+{
+    fn send_report(
+        &self,
+        kind: MemoKind,
+        report: PublicReport,
+        from: &UnixTime,
+    ) -> Result<(), ServicesError> {
+        debug!("Will send public report: {:?}, kind: {:?}", report, kind);
+
+        // Revocations need no proof
+        let token = match kind {
+            MemoKind::Revocation => None,
+            _ => self.verifier.token()?,
+        };
 
-        let memo = self.memo_mapper.to_memo(report);
+        let memo = self
+            .memo_mapper
+            .to_memo_of_kind(kind, token.as_deref().map(token_hash), report);
 
         debug!("Mapped public report to memo: {:?}", memo.bytes);
 
-        let signed_report = self.tcn_keys.create_report(memo.bytes)?;
+        // One per authorization key used in the report window
+        let reports: Vec<String> = self
+            .tcn_keys
+            .create_report(memo.bytes, from)?
+            .into_iter()
+            .map(|signed_report| {
+                let report = base64::encode(signed_report_to_bytes(signed_report));
+                format!("{}{}", kind.marker(), report)
+            })
+            .collect();
+
+        match token {
+            Some(token) => {
+                // The memos carry the token's hash, so the token can't be used for other reports
+                // One upload: if it fails, nothing was sent and the token can be used again
+                self.api
+                    .post_verified_reports(reports, ReportVerification::new(&token))?;
+                self.verifier.consume()?;
+            }
+            None => self.api.post_reports(reports)?,
+        }
+        Ok(())
+    }
 
-        let report_str = base64::encode(signed_report_to_bytes(signed_report));
+    // The TCNs since the pre-symptomatic margin before the earliest symptom are reported.
+    // Without the earliest symptom, all the TCNs that can be reported.
+    fn report_start(&self, report: &PublicReport) -> UnixTime {
+        let value = match &report.earliest_symptom_time {
+            UserInput::Some(time) => time
+                .value
+                .saturating_sub(self.config.pre_symptomatic_margin_secs),
+            UserInput::None => report.report_time.value.saturating_sub(REPORT_PERIOD_SECS),
+        };
+        debug!("Report window starts at: {}", value);
+        UnixTime { value }
+    }
 
-        self.api
-            .post_report(report_str)
-            .map_err(ServicesError::from)
+    fn submitted_report_start(&self) -> Result<UnixTime, ServicesError> {
+        self.preferences
+            .last_report_start()?
+            .map(|value| UnixTime { value })
+            .ok_or_else(|| ServicesError::General("No submitted report".to_owned()))
+    }
+
+    // If there are several reports, revocations and amendments cover all of them
+    fn store_report_start(&self, from: &UnixTime) -> Result<(), DaoError> {
+        let start = match self.preferences.last_report_start()? {
+            Some(last) => last.min(from.value),
+            None => from.value,
+        };
+        self.preferences.set_last_report_start(Some(start))
     }
 }
 
@@ -196,14 +347,20 @@ mod tests {
     use crate::errors;
     use crate::errors::ServicesError;
     use crate::errors::ServicesError::Error;
-    use crate::preferences::PreferencesTckMock;
-    use crate::reporting::memo::MemoMapperImpl;
+    use crate::migrations::tests::test_database;
+    use crate::networking::{NetworkingError, PaddedUpload};
+    use crate::preferences::{PreferencesDao, PreferencesImpl, PreferencesTckMock};
+    use crate::reporting::memo::{Memo, MemoMapperImpl};
     use crate::simple_logger;
+    use crate::tcn_ext::recovery_phrase::KeySet;
+    use crate::verification::FakeVerificationApi;
     use crate::{
         networking::TcnApiMock,
-        tcn_ext::tcn_keys::{ReportAuthorizationKeyExt, TcnKeysImpl},
+        tcn_ext::tcn_keys::{tests::test_tcn_keys, ReportAuthorizationKeyExt, TcnKeysImpl},
     };
-    use tcn::{ReportAuthorizationKey, TemporaryContactKey};
+    use parking_lot::Mutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tcn::{Report, ReportAuthorizationKey, TemporaryContactKey};
 
     #[test]
     fn test_public_report_with_inputs() {
@@ -294,20 +451,25 @@ mod tests {
 
         let preferences = Arc::new(PreferencesTckMock { tck_bytes });
 
-        let tcn_keys = Arc::new(TcnKeysImpl {
-            preferences: preferences.clone(),
-        });
+        let database = test_database();
+        let tcn_keys = Arc::new(test_tcn_keys(preferences.clone(), database.clone()));
 
         let submitter = SymptomInputsSubmitterImpl {
             memo_mapper: &MemoMapperImpl {},
             tcn_keys,
-            api: &TcnApiMock {},
+            api: Arc::new(TcnApiMock {}),
+            preferences: preferences.clone(),
+            verifier: Arc::new(Verifier::new(None, preferences.clone())),
+            config: ReportingConfig::default(),
         };
 
         let memo = submitter.memo_mapper.to_memo(report_which_should_be_sent);
 
-        let signed_report = match submitter.tcn_keys.create_report(memo.bytes) {
-            Ok(signed) => signed,
+        let signed_report = match submitter
+            .tcn_keys
+            .create_report(memo.bytes, &UnixTime { value: 0 })
+        {
+            Ok(mut signed) => signed.remove(0),
             Err(_) => return assert!(false), //Err(e),
         };
         let report = signed_report
@@ -387,6 +549,7 @@ mod tests {
         MemoMapperImpl,
         TcnKeysImpl<PreferencesTckMock>,
         TcnApiMock,
+        PreferencesTckMock,
     > {
         let rak_bytes = [
             42, 118, 64, 131, 236, 36, 122, 23, 13, 108, 73, 171, 102, 145, 66, 91, 157, 105, 195,
@@ -400,14 +563,16 @@ mod tests {
             tck_bytes: tck_bytes,
         });
 
-        let tcn_keys = Arc::new(TcnKeysImpl {
-            preferences: preferences.clone(),
-        });
+        let database = test_database();
+        let tcn_keys = Arc::new(test_tcn_keys(preferences.clone(), database.clone()));
 
         let submitter = SymptomInputsSubmitterImpl {
             memo_mapper: &MemoMapperImpl {},
             tcn_keys: tcn_keys,
-            api: &TcnApiMock {},
+            api: Arc::new(TcnApiMock {}),
+            preferences: preferences.clone(),
+            verifier: Arc::new(Verifier::new(None, preferences)),
+            config: ReportingConfig::default(),
         };
 
         submitter
@@ -425,4 +590,212 @@ mod tests {
             Err(_) => assert!(false),
         }
     }
+
+    #[test]
+    fn report_starts_before_earliest_symptom() {
+        let submitter = testing_get_submitter();
+        let mut report =
+            PublicReport::with_inputs(testing_get_inputs(), UnixTime { value: 0 }).unwrap();
+        report.report_time = UnixTime {
+            value: 1590356601 + 5 * 24 * 60 * 60,
+        };
+
+        report.earliest_symptom_time = UserInput::Some(UnixTime { value: 1590356601 });
+        assert_eq!(
+            submitter.report_start(&report),
+            UnixTime {
+                value: 1590356601 - 2 * 24 * 60 * 60
+            }
+        );
+
+        // Without earliest symptom: the whole report period
+        report.earliest_symptom_time = UserInput::None;
+        assert_eq!(
+            submitter.report_start(&report),
+            UnixTime {
+                value: report.report_time.value - REPORT_PERIOD_SECS
+            }
+        );
+    }
+
+    #[derive(Default)]
+    struct RecordingApi {
+        posted: Mutex<Vec<SignedReport>>,
+        markers: Mutex<Vec<String>>,
+        verifications: Mutex<Vec<ReportVerification>>,
+        fail_verified: AtomicBool,
+    }
+
+    impl TcnApi for RecordingApi {
+        fn get_reports(&self, _: u64, _: u64) -> Result<Vec<String>, NetworkingError> {
+            Ok(vec![])
+        }
+
+        fn post_report(&self, report: String) -> Result<(), NetworkingError> {
+            let base64 = MemoKind::without_marker(&report);
+            let marker = &report[..report.len() - base64.len()];
+            self.markers.lock().push(marker.to_owned());
+            let bytes = base64::decode(base64).unwrap();
+            self.posted
+                .lock()
+                .push(SignedReport::read(bytes.as_slice()).unwrap());
+            Ok(())
+        }
+
+        fn post_verified_reports(
+            &self,
+            reports: Vec<String>,
+            verification: ReportVerification,
+        ) -> Result<(), NetworkingError> {
+            if self.fail_verified.load(Ordering::SeqCst) {
+                return Err(NetworkingError {
+                    http_status: 500,
+                    message: "Test".to_owned(),
+                });
+            }
+            self.verifications.lock().push(verification);
+            for report in reports {
+                self.post_report(report)?;
+            }
+            Ok(())
+        }
+
+        fn post_padded_report(&self, _: PaddedUpload) -> Result<(), NetworkingError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn revokes_submitted_report_with_same_key() {
+        let database = test_database();
+        let preferences = Arc::new(PreferencesImpl::new(
+            PreferencesDao::new(database.clone()),
+            None,
+        ));
+        let tcn_keys = Arc::new(test_tcn_keys(preferences.clone(), database.clone()));
+        tcn_keys.generate_tcn().unwrap();
+        let api = Arc::new(RecordingApi::default());
+        let memo_mapper = &MemoMapperImpl {};
+        let submitter = SymptomInputsSubmitterImpl {
+            memo_mapper,
+            tcn_keys,
+            api: api.clone(),
+            preferences: preferences.clone(),
+            verifier: Arc::new(Verifier::new(None, preferences)),
+            config: ReportingConfig::default(),
+        };
+
+        // Nothing submitted yet
+        assert!(submitter.revoke_report().is_err());
+        assert!(submitter.amend_report(testing_get_inputs()).is_err());
+
+        submitter.submit_inputs(testing_get_inputs()).unwrap();
+        submitter.amend_report(testing_get_inputs()).unwrap();
+        submitter.revoke_report().unwrap();
+        // Already revoked
+        assert!(submitter.revoke_report().is_err());
+
+        let reports: Vec<(Vec<u8>, MemoKind)> = api
+            .posted
+            .lock()
+            .iter()
+            .map(|signed_report| {
+                let report = signed_report.clone().verify().unwrap();
+                let mut bytes = Vec::new();
+                report.write(Cursor::new(&mut bytes)).unwrap();
+                let kind = memo_mapper.kind(&Memo {
+                    bytes: report.memo_data().to_vec(),
+                });
+                // Starts with the verification key of the authorization key
+                (bytes[..32].to_vec(), kind)
+            })
+            .collect();
+        assert_eq!(
+            reports.iter().map(|(_, kind)| *kind).collect::<Vec<_>>(),
+            vec![MemoKind::Report, MemoKind::Amendment, MemoKind::Revocation]
+        );
+        assert!(reports.iter().all(|(rvk, _)| *rvk == reports[0].0));
+        assert_eq!(
+            *api.markers.lock(),
+            reports
+                .iter()
+                .map(|(_, kind)| kind.marker())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn sends_reports_of_all_keys_in_one_verified_upload() {
+        let database = test_database();
+        let preferences = Arc::new(PreferencesImpl::new(
+            PreferencesDao::new(database.clone()),
+            None,
+        ));
+        let tcn_keys = Arc::new(test_tcn_keys(preferences.clone(), database.clone()));
+        tcn_keys.generate_tcn().unwrap();
+        // Retires the device's key: the report is signed with both keys
+        tcn_keys
+            .import_keys(KeySet {
+                key: [7; 32],
+                created: UnixTime::now().value,
+                tck_index: 1,
+                retired: vec![],
+            })
+            .unwrap();
+        let api = Arc::new(RecordingApi::default());
+        let memo_mapper = &MemoMapperImpl {};
+        let submitter = SymptomInputsSubmitterImpl {
+            memo_mapper,
+            tcn_keys,
+            api: api.clone(),
+            preferences: preferences.clone(),
+            verifier: Arc::new(Verifier::new(
+                Some(Arc::new(FakeVerificationApi::new(
+                    vec!["123456".to_owned()],
+                ))),
+                preferences,
+            )),
+            config: ReportingConfig::default(),
+        };
+
+        assert!(submitter.verify_code("000000").is_err());
+        submitter.verify_code("123456").unwrap();
+        // Nothing was sent: the token is kept for the retry
+        api.fail_verified.store(true, Ordering::SeqCst);
+        assert!(submitter.submit_inputs(testing_get_inputs()).is_err());
+        assert!(api.posted.lock().is_empty());
+        api.fail_verified.store(false, Ordering::SeqCst);
+        submitter.submit_inputs(testing_get_inputs()).unwrap();
+        // The token was used
+        submitter.submit_inputs(testing_get_inputs()).unwrap();
+
+        let posted: Vec<Report> = api
+            .posted
+            .lock()
+            .iter()
+            .map(|signed_report| signed_report.clone().verify().unwrap())
+            .collect();
+        let verified: Vec<bool> = posted
+            .iter()
+            .map(|report| {
+                memo_mapper.is_verified(&Memo {
+                    bytes: report.memo_data().to_vec(),
+                })
+            })
+            .collect();
+        assert_eq!(verified, vec![true, true, false, false]);
+        assert_ne!(posted[0].rvk_bytes(), posted[1].rvk_bytes());
+
+        let verifications = api.verifications.lock();
+        assert_eq!(verifications.len(), 1);
+        for report in &posted[..2] {
+            let memo = Memo {
+                bytes: report.memo_data().to_vec(),
+            };
+            assert_eq!(
+                memo_mapper.token_hash(&memo),
+                Some(token_hash(&verifications[0].token))
+            );
+        }
+    }
 }
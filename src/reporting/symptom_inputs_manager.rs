@@ -6,8 +6,12 @@ use super::{
     },
 };
 use crate::{
-    errors::ServicesError, networking::TcnApiImpl, preferences::PreferencesImpl,
-    reports_interval::UnixTime, tcn_ext::tcn_keys::TcnKeysImpl,
+    cover_traffic::CoverTrafficApi,
+    errors::ServicesError,
+    networking::TcnApiImpl,
+    preferences::PreferencesImpl,
+    reports_interval::UnixTime,
+    tcn_ext::{emitted_tcns::ReportPreview, tcn_keys::TcnKeysImpl},
 };
 use chrono::{Duration, Utc};
 use log::*;
@@ -40,6 +44,10 @@ pub trait SymptomInputsProcessor {
 
     fn submit(&self) -> Result<(), ServicesError>;
     fn clear(&self) -> Result<(), ServicesError>;
+    fn preview(&self) -> Result<ReportPreview, ServicesError>;
+    fn revoke(&self) -> Result<(), ServicesError>;
+    fn amend(&self) -> Result<(), ServicesError>;
+    fn verify_code(&self, code: &str) -> Result<(), ServicesError>;
 }
 
 pub struct SymptomInputsProcessorImpl<T>
@@ -236,6 +244,22 @@ where
         self.inputs_manager.clear();
         Ok(())
     }
+
+    fn preview(&self) -> Result<ReportPreview, ServicesError> {
+        self.inputs_manager.preview()
+    }
+
+    fn revoke(&self) -> Result<(), ServicesError> {
+        self.inputs_manager.revoke()
+    }
+
+    fn amend(&self) -> Result<(), ServicesError> {
+        self.inputs_manager.amend()
+    }
+
+    fn verify_code(&self, code: &str) -> Result<(), ServicesError> {
+        self.inputs_manager.verify_code(code)
+    }
 }
 
 pub trait SymptomInputsManager {
@@ -252,12 +276,22 @@ pub trait SymptomInputsManager {
 
     fn submit(&self) -> Result<(), ServicesError>;
     fn clear(&self);
+    fn preview(&self) -> Result<ReportPreview, ServicesError>;
+    fn revoke(&self) -> Result<(), ServicesError>;
+    // Replaces the submitted report with the current inputs
+    fn amend(&self) -> Result<(), ServicesError>;
+    // Verification code of a positive test, for the next report
+    fn verify_code(&self, code: &str) -> Result<(), ServicesError>;
 }
 
 pub struct SymptomInputsManagerImpl<T>
 where
     // TODO no concrete types here?
-    T: SymptomInputsSubmitter<MemoMapperImpl, TcnKeysImpl<PreferencesImpl>, TcnApiImpl>,
+    T: SymptomInputsSubmitter<
+        MemoMapperImpl,
+        TcnKeysImpl<PreferencesImpl>,
+        CoverTrafficApi<TcnApiImpl>,
+    >,
 {
     pub inputs: Arc<RwLock<SymptomInputs>>,
     pub inputs_submitter: T,
@@ -266,7 +300,11 @@ where
 impl<T> SymptomInputsManagerImpl<T>
 where
     // TODO no concrete types here?
-    T: SymptomInputsSubmitter<MemoMapperImpl, TcnKeysImpl<PreferencesImpl>, TcnApiImpl>,
+    T: SymptomInputsSubmitter<
+        MemoMapperImpl,
+        TcnKeysImpl<PreferencesImpl>,
+        CoverTrafficApi<TcnApiImpl>,
+    >,
 {
     fn print_current_state(&self) {
         debug!("Symptom inputs state: {:?}", self.inputs);
@@ -276,7 +314,11 @@ where
 impl<T> SymptomInputsManager for SymptomInputsManagerImpl<T>
 where
     // TODO no concrete types here?
-    T: SymptomInputsSubmitter<MemoMapperImpl, TcnKeysImpl<PreferencesImpl>, TcnApiImpl>,
+    T: SymptomInputsSubmitter<
+        MemoMapperImpl,
+        TcnKeysImpl<PreferencesImpl>,
+        CoverTrafficApi<TcnApiImpl>,
+    >,
 {
     fn select_symptom_ids(&self, ids: HashSet<SymptomId>) {
         self.inputs.write().ids = ids;
@@ -338,9 +380,9 @@ where
     fn submit(&self) -> Result<(), ServicesError> {
         debug!("Submitting symptom inputs...");
         self.print_current_state();
-        let result = self
-            .inputs_submitter
-            .submit_inputs(self.inputs.read().clone());
+        // Not read-locked while submitting: deleting all data clears them with the keys locked
+        let inputs = self.inputs.read().clone();
+        let result = self.inputs_submitter.submit_inputs(inputs);
 
         if result.is_ok() {
             self.clear()
@@ -355,4 +397,32 @@ where
         *self.inputs.write() = Default::default();
         self.print_current_state();
     }
+
+    fn preview(&self) -> Result<ReportPreview, ServicesError> {
+        let inputs = self.inputs.read().clone();
+        self.inputs_submitter.preview_inputs(inputs)
+    }
+
+    fn revoke(&self) -> Result<(), ServicesError> {
+        debug!("Revoking submitted report...");
+        self.inputs_submitter.revoke_report()
+    }
+
+    fn amend(&self) -> Result<(), ServicesError> {
+        debug!("Amending submitted report...");
+        self.print_current_state();
+        let inputs = self.inputs.read().clone();
+        let result = self.inputs_submitter.amend_report(inputs);
+
+        if result.is_ok() {
+            self.clear()
+        }
+
+        result
+    }
+
+    fn verify_code(&self, code: &str) -> Result<(), ServicesError> {
+        debug!("Verifying code...");
+        self.inputs_submitter.verify_code(code)
+    }
 }
@@ -1,14 +1,16 @@
 use crate::{
+    alerts::{AlertDao, AlertStatus, StoredAlert},
     byte_vec_to_16_byte_array,
-    errors::{Error, ServicesError},
-    expect_log,
+    errors::{DaoError, Error, ServicesError},
     networking::{NetworkingError, TcnApi},
     preferences::{Database, Preferences},
     reporting::{
-        memo::{Memo, MemoMapper},
+        memo::{Memo, MemoKind, MemoMapper},
         public_report::PublicReport,
     },
     reports_interval,
+    sync_stats::{IntervalStats, SyncStats, SyncStatsDao},
+    tcn_ext::{emitted_tcns::EmittedTcnDao, tcn_keys::ReportExt},
 };
 use chrono::Utc;
 use log::*;
@@ -17,15 +19,23 @@ use reports_interval::{ReportsInterval, UnixTime};
 use rusqlite::{params, Row, NO_PARAMS};
 use serde::Serialize;
 use std::collections::HashMap;
-use std::{io::Cursor, sync::Arc, time::Instant};
+use std::{io::Cursor, ops::Range, sync::Arc, time::Instant};
 use tcn::{SignedReport, TemporaryContactNumber};
 
+// Reports contain the TCNs generated by the reporter in (at most) this period before the report.
+// Older observed TCNs aren't matched.
+pub const REPORTED_CONTACTS_MAX_AGE_SECS: u64 = 14 * 24 * 60 * 60;
+
+// A downloaded report, with the period (unix time) its TCNs can have been observed in
+pub type ReportWindow = (SignedReport, Range<u64>);
+
 pub trait TcnMatcher {
-    fn match_reports(
+    // Matches each report with the TCNs of tcn_dao observed in its window
+    fn match_reports<U: TcnDao>(
         &self,
-        tcns: Vec<ObservedTcn>,
-        reports: Vec<SignedReport>,
-    ) -> Result<Vec<MatchedReport>, ServicesError>;
+        tcn_dao: &U,
+        reports: Vec<ReportWindow>,
+    ) -> Result<MatchingResult, ServicesError>;
 }
 
 #[derive(Debug, Clone)]
@@ -34,63 +44,91 @@ pub struct MatchedReport {
     contact_time: UnixTime,
 }
 
+#[derive(Debug, Clone)]
+pub struct MatchingResult {
+    pub matched: Vec<MatchedReport>,
+    // Reports with an invalid signature. These are ignored.
+    pub verification_failures: u64,
+}
+
 pub struct TcnMatcherRayon {}
 
 impl TcnMatcher for TcnMatcherRayon {
-    fn match_reports(
+    fn match_reports<U: TcnDao>(
         &self,
-        tcns: Vec<ObservedTcn>,
-        reports: Vec<SignedReport>,
-    ) -> Result<Vec<MatchedReport>, ServicesError> {
-        Self::match_reports_with(tcns, reports)
+        tcn_dao: &U,
+        reports: Vec<ReportWindow>,
+    ) -> Result<MatchingResult, ServicesError> {
+        Self::match_reports_with(tcn_dao, reports)
     }
 }
 
 impl TcnMatcherRayon {
-    pub fn match_reports_with(
-        tcns: Vec<ObservedTcn>,
-        reports: Vec<SignedReport>,
-    ) -> Result<Vec<MatchedReport>, ServicesError> {
-        let observed_tcns_map: HashMap<[u8; 16], ObservedTcn> =
-            tcns.into_iter().map(|e| (e.tcn.0, e)).collect();
-
-        let observed_tcns_map = Arc::new(observed_tcns_map);
-
-        let res: Vec<Option<MatchedReport>> = reports
+    // The observed TCNs are streamed (ordered by time) over the union of the windows, and
+    // looked up in the TCNs of the reports: only the reports' TCNs are kept in memory.
+    pub fn match_reports_with<U: TcnDao>(
+        tcn_dao: &U,
+        reports: Vec<ReportWindow>,
+    ) -> Result<MatchingResult, ServicesError> {
+        let res: Vec<Result<Vec<TemporaryContactNumber>, tcn::Error>> = reports
             .par_iter()
-            .map(|report| Self::match_report_with(&observed_tcns_map, report))
+            .map(|(report, _)| Self::report_tcns(report))
             .collect();
 
-        let res: Vec<MatchedReport> = res
+        let verification_failures = res.iter().filter(|res| res.is_err()).count() as u64;
+
+        // Indices of the reports with each TCN. Reports that can't be verified have none.
+        let mut reports_by_tcn: HashMap<[u8; 16], Vec<usize>> = HashMap::new();
+        for (index, tcns) in res.into_iter().enumerate() {
+            for tcn in tcns.unwrap_or_default() {
+                reports_by_tcn.entry(tcn.0).or_default().push(index);
+            }
+        }
+
+        let mut contact_times: Vec<Option<UnixTime>> = vec![None; reports.len()];
+        let from = reports.iter().map(|(_, window)| window.start).min();
+        let to = reports.iter().map(|(_, window)| window.end).max();
+        if let (Some(from), Some(to)) = (from, to) {
+            tcn_dao.for_each_in_range(
+                &UnixTime { value: from },
+                &UnixTime { value: to },
+                |observed| {
+                    let indices = reports_by_tcn.get(&observed.tcn.0);
+                    for &index in indices.into_iter().flatten() {
+                        // The earliest contact in the report's window
+                        if contact_times[index].is_none()
+                            && reports[index].1.contains(&observed.time.value)
+                        {
+                            contact_times[index] = Some(observed.time.clone());
+                        }
+                    }
+                },
+            )?;
+        }
+
+        let matched: Vec<MatchedReport> = reports
             .into_iter()
-            .filter_map(|option| option) // drop None (reports that didn't match)
+            .zip(contact_times)
+            .filter_map(|((report, _), contact_time)| {
+                contact_time.map(|contact_time| MatchedReport {
+                    report,
+                    contact_time,
+                })
+            })
             .collect();
 
-        Ok(res)
-    }
-
-    pub fn match_report_with(
-        observed_tcns_map: &HashMap<[u8; 16], ObservedTcn>,
-        report: &SignedReport,
-    ) -> Option<MatchedReport> {
-        let rep = report.clone().verify();
-        match rep {
-            Ok(rep) => {
-                let mut out: Option<MatchedReport> = None;
-                for tcn in rep.temporary_contact_numbers() {
-                    if let Some(entry) = observed_tcns_map.get(&tcn.0) {
-                        out = Some(MatchedReport {
-                            report: report.clone(),
-                            contact_time: entry.time.clone(),
-                        });
-                        break;
-                    }
-                }
-                out
-            }
+        Ok(MatchingResult {
+            matched,
+            verification_failures,
+        })
+    }
+
+    fn report_tcns(report: &SignedReport) -> Result<Vec<TemporaryContactNumber>, tcn::Error> {
+        match report.clone().verify() {
+            Ok(rep) => Ok(rep.temporary_contact_numbers().collect()),
             Err(error) => {
                 error!("Report can't be matched. Verification failed: {:?}", error);
-                None
+                Err(error)
             }
         }
     }
@@ -98,8 +136,8 @@ impl TcnMatcherRayon {
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct ObservedTcn {
-    tcn: TemporaryContactNumber,
-    time: UnixTime,
+    pub tcn: TemporaryContactNumber,
+    pub time: UnixTime,
 }
 
 pub trait ObservedTcnProcessor {
@@ -121,6 +159,12 @@ where
         info!("Recording a TCN {:?}", tcn_str);
 
         let bytes_vec: Vec<u8> = hex::decode(tcn_str)?;
+        if bytes_vec.len() != 16 {
+            return Err(ServicesError::FFIParameters(format!(
+                "Invalid TCN length: {}",
+                bytes_vec.len()
+            )));
+        }
         let observed_tcn = ObservedTcn {
             tcn: TemporaryContactNumber(byte_vec_to_16_byte_array(bytes_vec)),
             time: UnixTime {
@@ -128,73 +172,96 @@ where
             },
         };
 
-        self.tcn_dao.save(&observed_tcn)
+        self.tcn_dao.save(&observed_tcn)?;
+        Ok(())
     }
 }
 
 pub trait TcnDao {
-    fn all(&self) -> Result<Vec<ObservedTcn>, ServicesError>;
-    fn save(&self, observed_tcn: &ObservedTcn) -> Result<(), ServicesError>;
+    fn all(&self) -> Result<Vec<ObservedTcn>, DaoError>;
+    // Passes the TCNs observed in [from, to) to f, ordered by time, as they're read
+    fn for_each_in_range<F: FnMut(ObservedTcn)>(
+        &self,
+        from: &UnixTime,
+        to: &UnixTime,
+        f: F,
+    ) -> Result<(), DaoError>;
+    // Number of times tcn was observed. Uses the tcn index.
+    fn sightings(&self, tcn: &TemporaryContactNumber) -> Result<u64, DaoError>;
+    fn save(&self, observed_tcn: &ObservedTcn) -> Result<(), DaoError>;
 }
 
 pub struct TcnDaoImpl {
     db: Arc<Database>,
 }
 
+// TODO ideally FFI should send byte arrays too
 impl TcnDaoImpl {
-    fn create_table_if_not_exists(db: &Arc<Database>) {
-        // TODO use blob for tcn? https://docs.rs/rusqlite/0.23.1/rusqlite/blob/index.html
-        // TODO ideally FFI should send byte arrays too
-        let res = db.execute_sql(
-            "create table if not exists tcn(
-                tcn text not null,
-                contact_time integer not null
-            )",
-            params![],
-        );
-        expect_log!(res, "Couldn't create tcn table");
-    }
-
-    fn to_tcn(row: &Row) -> ObservedTcn {
-        let tcn: Result<String, _> = row.get(0);
-        let contact_time = row.get(1);
-        let tcn_value = expect_log!(tcn, "Invalid row: no TCN");
-        let tcn_value_bytes_vec_res = hex::decode(tcn_value);
-        let tcn_value_bytes_vec = expect_log!(tcn_value_bytes_vec_res, "Invalid stored TCN format");
-        let tcn_value_bytes = byte_vec_to_16_byte_array(tcn_value_bytes_vec);
-        let contact_time_value: i64 = expect_log!(contact_time, "Invalid row: no contact time");
-        ObservedTcn {
-            tcn: TemporaryContactNumber(tcn_value_bytes),
+    fn to_tcn(row: &Row) -> Result<ObservedTcn, DaoError> {
+        let tcn_bytes: Vec<u8> = row.get(0)?;
+        let contact_time: i64 = row.get(1)?;
+        if tcn_bytes.len() != 16 {
+            return Err(DaoError::InvalidData(format!(
+                "Invalid stored TCN length: {}",
+                tcn_bytes.len()
+            )));
+        }
+        Ok(ObservedTcn {
+            tcn: TemporaryContactNumber(byte_vec_to_16_byte_array(tcn_bytes)),
             time: UnixTime {
-                value: contact_time_value as u64,
+                value: contact_time as u64,
             },
-        }
+        })
     }
 
     pub fn new(db: Arc<Database>) -> TcnDaoImpl {
-        Self::create_table_if_not_exists(&db);
         TcnDaoImpl { db }
     }
 }
 
 impl TcnDao for TcnDaoImpl {
-    fn all(&self) -> Result<Vec<ObservedTcn>, ServicesError> {
-        self.db
+    // Rows that can't be read are skipped
+    fn all(&self) -> Result<Vec<ObservedTcn>, DaoError> {
+        let tcns = self
+            .db
             .query("select tcn, contact_time from tcn", NO_PARAMS, |row| {
                 Self::to_tcn(row)
-            })
-            .map_err(ServicesError::from)
+            })?;
+        Ok(tcns)
     }
 
-    fn save(&self, observed_tcn: &ObservedTcn) -> Result<(), ServicesError> {
-        let tcn_str = hex::encode(observed_tcn.tcn.0);
+    // Uses the contact_time index
+    fn for_each_in_range<F: FnMut(ObservedTcn)>(
+        &self,
+        from: &UnixTime,
+        to: &UnixTime,
+        f: F,
+    ) -> Result<(), DaoError> {
+        self.db.query_each(
+            "select tcn, contact_time from tcn where contact_time >= ?1 and contact_time < ?2
+            order by contact_time",
+            params![from.value as i64, to.value as i64],
+            Self::to_tcn,
+            f,
+        )?;
+        Ok(())
+    }
+
+    fn sightings(&self, tcn: &TemporaryContactNumber) -> Result<u64, DaoError> {
+        let count: i64 = self.db.query_row(
+            "select count(*) from tcn where tcn = ?1",
+            params![&tcn.0[..]],
+            |row| row.get(0),
+        )?;
+        Ok(count as u64)
+    }
 
-        let res = self.db.execute_sql(
+    fn save(&self, observed_tcn: &ObservedTcn) -> Result<(), DaoError> {
+        self.db.execute_sql(
             "insert or replace into tcn(tcn, contact_time) values(?1, ?2)",
             // conversion to signed timestamp is safe, for obvious reasons.
-            params![tcn_str, observed_tcn.time.value as i64],
-        );
-        expect_log!(res, "Couldn't insert tcn");
+            params![&observed_tcn.tcn.0[..], observed_tcn.time.value as i64],
+        )?;
         Ok(())
     }
 }
@@ -221,19 +288,54 @@ pub struct Alert {
     pub id: String,
     pub report: PublicReport,
     pub contact_time: u64,
+    // Id of the reports source (backend) the report was downloaded from
+    pub source: String,
+    // Revoked and amended alerts have the id of an alert sent before, to be updated
+    pub status: AlertStatus,
+    // Report of a verified positive test, otherwise self-reported
+    pub verified: bool,
 }
 
-pub struct ReportsUpdater<'a, T: Preferences, U: TcnDao, V: TcnMatcher, W: TcnApi, X: MemoMapper> {
+// A backend reports are downloaded from, e.g. a regional deployment.
+pub struct ReportsSource<W: TcnApi> {
+    pub id: String,
+    pub api: Arc<W>,
+    pub interval_length: u64,
+}
+
+pub struct ReportsUpdater<
+    'a,
+    T: Preferences,
+    U: TcnDao,
+    V: TcnMatcher,
+    W: TcnApi,
+    X: MemoMapper,
+    Y: SyncStatsDao,
+> {
     pub preferences: Arc<T>,
     pub tcn_dao: Arc<U>,
     pub tcn_matcher: V,
-    pub api: &'a W,
+    pub sources: Vec<ReportsSource<W>>,
     pub memo_mapper: &'a X,
+    pub sync_stats_dao: Arc<Y>,
+    pub emitted_tcns: Arc<EmittedTcnDao>,
+    pub alert_dao: Arc<AlertDao>,
+}
+
+// A matched report, decoded
+struct ReceivedReport {
+    id: String,
+    rvk: [u8; 32],
+    memo: Vec<u8>,
+    kind: MemoKind,
+    report: PublicReport,
+    contact_time: u64,
+    source: String,
 }
 
 trait SignedReportExt {
     fn with_str(str: &str) -> Option<SignedReport> {
-        base64::decode(str)
+        base64::decode(MemoKind::without_marker(str))
             .also(|res| {
                 if let Err(error) = res {
                     error!("Error: {} decoding (base64) report: {:?}", error, res)
@@ -251,83 +353,351 @@ trait SignedReportExt {
 
 impl SignedReportExt for SignedReport {}
 
-impl<'a, T, U, V, W, X> ReportsUpdater<'a, T, U, V, W, X>
+impl<'a, T, U, V, W, X, Y> ReportsUpdater<'a, T, U, V, W, X, Y>
 where
     T: Preferences,
     U: TcnDao,
     V: TcnMatcher,
     W: TcnApi,
     X: MemoMapper,
+    Y: SyncStatsDao,
 {
     pub fn fetch_new_reports(&self) -> Result<Vec<Alert>, ServicesError> {
-        self.retrieve_and_match_new_reports().map(|signed_reports| {
-            signed_reports
+        let now: UnixTime = UnixTime::now();
+        let mut stats = SyncStats {
+            time: now.value,
+            ..SyncStats::default()
+        };
+
+        let result = self.retrieve_and_match_new_reports(&now, &mut stats);
+
+        stats.successful = result.is_ok();
+        if let Err(error) = &result {
+            stats.errors.push(error.to_string());
+        }
+        if let Err(error) = self.sync_stats_dao.save(&stats) {
+            error!("Couldn't save sync stats: {:?}", error);
+        }
+
+        result.map(|matched_reports| {
+            let mut received: Vec<ReceivedReport> = matched_reports
                 .into_iter()
-                .filter_map(|matched_report| self.to_ffi_alert(matched_report).ok())
-                .collect()
+                .filter_map(|(matched_report, source)| {
+                    self.to_received_report(matched_report, source).ok()
+                })
+                .collect();
+            // A revocation or amendment may come with the report it applies to
+            received.sort_by_key(|received| received.report.report_time.value);
+            let alerts = self.to_alerts(received);
+
+            let expired = now.value.saturating_sub(REPORTED_CONTACTS_MAX_AGE_SECS);
+            if let Err(error) = self.alert_dao.delete_contacted_before(expired) {
+                error!("Couldn't delete expired alerts: {:?}", error);
+            }
+            alerts
         })
     }
 
-    // Note: For now we will not create an FFI layer to handle JSON conversions, since it may be possible
-    // to use directly the data structures.
-    fn to_ffi_alert(&self, matched_report: MatchedReport) -> Result<Alert, ServicesError> {
+    fn to_received_report(
+        &self,
+        matched_report: MatchedReport,
+        source: String,
+    ) -> Result<ReceivedReport, ServicesError> {
         let report = matched_report.report.clone().verify()?;
-
-        let public_report = self.memo_mapper.to_report(Memo {
+        let memo = Memo {
             bytes: report.memo_data().to_vec(),
-        });
+        };
 
-        Ok(Alert {
+        Ok(ReceivedReport {
             id: format!("{:?}", matched_report.report.sig),
-            report: public_report,
+            rvk: report.rvk_bytes(),
+            kind: self.memo_mapper.kind(&memo),
+            report: self.memo_mapper.to_report(Memo {
+                bytes: memo.bytes.clone(),
+            }),
+            memo: memo.bytes,
             contact_time: matched_report.contact_time.value,
+            source,
         })
     }
 
-    fn retrieve_and_match_new_reports(&self) -> Result<Vec<MatchedReport>, ServicesError> {
-        let now: UnixTime = UnixTime::now();
+    // Stores the new alerts and applies revocations and amendments to the stored ones.
+    // Note: For now we will not create an FFI layer to handle JSON conversions, since it may be possible
+    // to use directly the data structures.
+    fn to_alerts(&self, received: Vec<ReceivedReport>) -> Vec<Alert> {
+        let mut alerts: Vec<Alert> = vec![];
+        for received in received {
+            let changed = match received.kind {
+                MemoKind::Report => self.store_new_alert(received),
+                MemoKind::Revocation => {
+                    let revoked = self.update_stored_alerts(&received.rvk, |alert| StoredAlert {
+                        status: AlertStatus::Revoked,
+                        ..alert
+                    });
+                    if revoked.is_empty() {
+                        info!("Revocation of a report without alerts. Ignoring.");
+                    }
+                    revoked
+                }
+                MemoKind::Amendment => {
+                    let amended = self.update_stored_alerts(&received.rvk, |alert| StoredAlert {
+                        memo: received.memo.clone(),
+                        status: AlertStatus::Amended,
+                        ..alert
+                    });
+                    // Contacts that weren't alerted by the original report
+                    if amended.is_empty() {
+                        self.store_new_alert(received)
+                    } else {
+                        amended
+                    }
+                }
+            };
+            for alert in changed {
+                let alert = self.to_ffi_alert(alert);
+                // The app gets only the last state of each alert
+                alerts.retain(|existing| existing.id != alert.id);
+                alerts.push(alert);
+            }
+        }
+        alerts
+    }
+
+    // Empty if the alert was already sent
+    fn store_new_alert(&self, received: ReceivedReport) -> Vec<StoredAlert> {
+        let alert = StoredAlert {
+            id: received.id,
+            rvk: received.rvk,
+            memo: received.memo,
+            contact_time: received.contact_time,
+            source: received.source,
+            status: AlertStatus::Active,
+        };
+        match self.alert_dao.insert(&alert) {
+            Ok(true) => vec![alert],
+            Ok(false) => vec![],
+            Err(error) => {
+                error!("Couldn't store alert: {:?}", error);
+                vec![alert]
+            }
+        }
+    }
+
+    // Applies f to the (not revoked) alerts of the reports signed with rvk. Returns the updated alerts.
+    fn update_stored_alerts<F: Fn(StoredAlert) -> StoredAlert>(
+        &self,
+        rvk: &[u8; 32],
+        f: F,
+    ) -> Vec<StoredAlert> {
+        let stored = match self.alert_dao.with_rvk(rvk) {
+            Ok(stored) => stored,
+            Err(error) => {
+                error!("Couldn't load alerts: {:?}", error);
+                return vec![];
+            }
+        };
+        stored
+            .into_iter()
+            .filter(|alert| alert.status != AlertStatus::Revoked)
+            .map(f)
+            .inspect(|alert| {
+                if let Err(error) = self.alert_dao.update(alert) {
+                    error!("Couldn't update alert: {:?}", error);
+                }
+            })
+            .collect()
+    }
+
+    fn to_ffi_alert(&self, alert: StoredAlert) -> Alert {
+        let memo = Memo { bytes: alert.memo };
+        Alert {
+            id: alert.id,
+            verified: self.memo_mapper.is_verified(&memo),
+            report: self.memo_mapper.to_report(memo),
+            contact_time: alert.contact_time,
+            source: alert.source,
+            status: alert.status,
+        }
+    }
+
+    // Returns the matches, each with the id of the source it was downloaded from.
+    fn retrieve_and_match_new_reports(
+        &self,
+        now: &UnixTime,
+        stats: &mut SyncStats,
+    ) -> Result<Vec<(MatchedReport, String)>, ServicesError> {
+        let mut retrieved: Vec<(&ReportsSource<W>, Vec<SignedReportsChunk>)> = vec![];
+        let mut first_error: Option<ServicesError> = None;
+        let mut reauth_required: Option<ServicesError> = None;
+
+        for source in &self.sources {
+            match self.retrieve_reports_from(source, now) {
+                Ok(chunks) => {
+                    Self::add_chunks_stats(stats, &source.id, &chunks);
+                    retrieved.push((source, chunks))
+                }
+                Err(error) => {
+                    error!("Couldn't retrieve reports from: {}: {:?}", source.id, error);
+                    stats.errors.push(format!("{}: {}", source.id, error));
+                    match error {
+                        ServicesError::ReauthRequired(_) => {
+                            reauth_required = reauth_required.or(Some(error))
+                        }
+                        _ => first_error = first_error.or(Some(error)),
+                    }
+                }
+            }
+        }
+
+        // The app has to know, so it always fails. Nothing is matched or stored: the sources
+        // that could be fetched are fetched again next time.
+        if let Some(error) = reauth_required {
+            return Err(error);
+        }
+        // Otherwise fail only if no source could be fetched. The others will be retried next time.
+        if retrieved.is_empty() {
+            if let Some(error) = first_error {
+                return Err(error);
+            }
+        }
 
-        let matching_reports = self.matching_reports(self.determine_start_interval(&now), &now);
+        let (reports, sources_by_report) = Self::merge(&retrieved);
 
-        if let Ok(matching_reports) = &matching_reports {
-            let intervals = matching_reports.iter().map(|c| c.interval).collect();
-            self.store_last_completed_interval(intervals, &now);
+        let matching_start_time = Instant::now();
+        let matching_result = self.find_matches(reports);
+        stats.matching_time_ms = matching_start_time.elapsed().as_millis() as u64;
+        let mut matching_result = matching_result?;
+        stats.verification_failures = matching_result.verification_failures;
+        matching_result.matched = self.exclude_own_reports(matching_result.matched);
+        stats.matches = matching_result.matched.len() as u64;
+
+        for (source, chunks) in &retrieved {
+            let intervals = chunks.iter().map(|chunk| chunk.interval).collect();
+            self.store_last_completed_interval(&source.id, intervals, now)?;
+        }
+
+        Ok(matching_result
+            .matched
+            .into_iter()
+            .map(|matched_report| {
+                let bytes = signed_report_to_bytes(matched_report.report.clone());
+                let source = sources_by_report.get(&bytes).cloned().unwrap_or_default();
+                (matched_report, source)
+            })
+            .collect())
+    }
+
+    // Our own reports match if the device observed its own TCNs: they're not alerts.
+    fn exclude_own_reports(&self, matched: Vec<MatchedReport>) -> Vec<MatchedReport> {
+        if matched.is_empty() {
+            return matched;
+        }
+        let emitted = match self.emitted_tcns.tcns() {
+            Ok(emitted) => emitted,
+            Err(error) => {
+                error!("Couldn't load emitted TCNs: {:?}", error);
+                return matched;
+            }
         };
+        matched
+            .into_iter()
+            .filter(|matched_report| {
+                let own = matched_report
+                    .report
+                    .clone()
+                    .verify()
+                    .map(|report| {
+                        report
+                            .temporary_contact_numbers()
+                            .any(|tcn| emitted.contains(&tcn.0))
+                    })
+                    .unwrap_or(false);
+                if own {
+                    info!("Ignoring own report");
+                }
+                !own
+            })
+            .collect()
+    }
 
-        matching_reports
-            .map(|chunks| chunks.into_iter().flat_map(|chunk| chunk.matched).collect())
+    fn add_chunks_stats(stats: &mut SyncStats, source_id: &str, chunks: &[SignedReportsChunk]) {
+        for chunk in chunks {
+            stats.bytes_downloaded += chunk.bytes;
+            stats.decode_failures += chunk.decode_failures;
+            stats.intervals.push(IntervalStats {
+                source: source_id.to_owned(),
+                interval_number: chunk.interval.number,
+                interval_length: chunk.interval.length,
+                reports: chunk.reports.len() as u64,
+            });
+        }
+    }
+
+    // Merges the reports of all sources, removing duplicates (reports are often
+    // mirrored across deployments). The first source (in configuration order) wins.
+    // A report has the TCNs used in the report period before its upload, in its interval.
+    fn merge(
+        retrieved: &[(&ReportsSource<W>, Vec<SignedReportsChunk>)],
+    ) -> (Vec<ReportWindow>, HashMap<Vec<u8>, String>) {
+        let mut reports: Vec<ReportWindow> = vec![];
+        let mut sources_by_report: HashMap<Vec<u8>, String> = HashMap::new();
+
+        for (source, chunks) in retrieved {
+            for chunk in chunks {
+                let from = chunk
+                    .interval
+                    .start()
+                    .saturating_sub(REPORTED_CONTACTS_MAX_AGE_SECS);
+                for report in &chunk.reports {
+                    let bytes = signed_report_to_bytes(report.clone());
+                    if !sources_by_report.contains_key(&bytes) {
+                        sources_by_report.insert(bytes, source.id.clone());
+                        reports.push((report.clone(), from..chunk.interval.end()));
+                    }
+                }
+            }
+        }
+        (reports, sources_by_report)
+    }
+
+    fn retrieve_reports_from(
+        &self,
+        source: &ReportsSource<W>,
+        now: &UnixTime,
+    ) -> Result<Vec<SignedReportsChunk>, ServicesError> {
+        let start_interval = self.determine_start_interval(source, now)?;
+        Self::generate_intervals_sequence(start_interval, now)
+            .map(|interval| self.retrieve_reports(source, interval))
+            .collect::<Result<Vec<SignedReportsChunk>, NetworkingError>>()
             .map_err(ServicesError::from)
     }
 
-    fn retrieve_last_completed_interval(&self) -> Option<ReportsInterval> {
-        self.preferences.last_completed_reports_interval()
+    fn retrieve_last_completed_interval(
+        &self,
+        source_id: &str,
+    ) -> Result<Option<ReportsInterval>, DaoError> {
+        self.preferences.last_completed_reports_interval(source_id)
     }
 
-    fn determine_start_interval(&self, time: &UnixTime) -> ReportsInterval {
-        let last = self.retrieve_last_completed_interval();
+    fn determine_start_interval(
+        &self,
+        source: &ReportsSource<W>,
+        time: &UnixTime,
+    ) -> Result<ReportsInterval, DaoError> {
+        let last = self
+            .retrieve_last_completed_interval(&source.id)?
+            // If the source's interval length changed, the stored interval numbers are meaningless
+            .filter(|interval| interval.length == source.interval_length);
         debug!(
-            "Determining start reports interval. Last completed interval: {:?}",
-            last
+            "Determining start reports interval for: {}. Last completed interval: {:?}",
+            source.id, last
         );
         let next = last.map(|interval| interval.next());
         debug!("Next interval: {:?}", next);
-        let result = next.unwrap_or_else(|| ReportsInterval::create_for_with_default_length(time));
+        let result =
+            next.unwrap_or_else(|| ReportsInterval::create_for(time, source.interval_length));
         debug!("Interval to fetch: {:?}", result);
-        result
-    }
-
-    fn matching_reports(
-        &self,
-        start_interval: ReportsInterval,
-        until: &UnixTime,
-    ) -> Result<Vec<MatchedReportsChunk>, ServicesError> {
-        let sequence = Self::generate_intervals_sequence(start_interval, until);
-        let reports = sequence.map(|interval| self.retrieve_reports(interval));
-        let matched_results = reports.map(|interval| self.match_retrieved_reports_result(interval));
-        matched_results
-            .into_iter()
-            .collect::<Result<Vec<MatchedReportsChunk>, ServicesError>>()
-            .map_err(ServicesError::from)
+        Ok(result)
     }
 
     fn generate_intervals_sequence(
@@ -340,13 +710,16 @@ where
 
     fn retrieve_reports(
         &self,
+        source: &ReportsSource<W>,
         interval: ReportsInterval,
     ) -> Result<SignedReportsChunk, NetworkingError> {
         let reports_strings_result: Result<Vec<String>, NetworkingError> =
-            self.api.get_reports(interval.number, interval.length);
+            source.api.get_reports(interval.number, interval.length);
 
-        reports_strings_result.map(|report_strings| SignedReportsChunk {
-            reports: report_strings
+        reports_strings_result.map(|report_strings| {
+            let bytes = report_strings.iter().map(|str| str.len() as u64).sum();
+            let count = report_strings.len() as u64;
+            let reports: Vec<SignedReport> = report_strings
                 .into_iter()
                 .filter_map(|report_string| {
                     SignedReport::with_str(&report_string).also(|res| {
@@ -355,70 +728,35 @@ where
                         }
                     })
                 })
-                .collect(),
-            interval,
+                .collect();
+            SignedReportsChunk {
+                decode_failures: count - reports.len() as u64,
+                reports,
+                interval,
+                bytes,
+            }
         })
     }
 
-    fn match_retrieved_reports_result(
-        &self,
-        reports_result: Result<SignedReportsChunk, NetworkingError>,
-    ) -> Result<MatchedReportsChunk, ServicesError> {
-        reports_result
-            .map_err(ServicesError::from)
-            .and_then(|chunk| self.to_matched_reports_chunk(&chunk))
-    }
-
-    /**
-     * Maps reports chunk to a new chunk containing possible matches.
-     */
-    fn to_matched_reports_chunk(
-        &self,
-        chunk: &SignedReportsChunk,
-    ) -> Result<MatchedReportsChunk, ServicesError> {
-        self.find_matches(chunk.reports.clone())
-            .map(|matches| MatchedReportsChunk {
-                reports: chunk.reports.clone(),
-                matched: matches,
-                interval: chunk.interval.clone(),
-            })
-            .map_err(ServicesError::from)
-    }
-
-    fn find_matches(
-        &self,
-        reports: Vec<SignedReport>,
-    ) -> Result<Vec<MatchedReport>, ServicesError> {
+    // Matches the reports with the TCNs observed in their windows
+    fn find_matches(&self, reports: Vec<ReportWindow>) -> Result<MatchingResult, ServicesError> {
         let matching_start_time = Instant::now();
 
         info!("R Start matching...");
 
-        let tcns = self.tcn_dao.all();
-
-        if let Ok(tcns) = &tcns {
-            let tcns_for_debugging: Vec<String> = tcns
-                .clone()
-                .into_iter()
-                .map(|tcn| hex::encode(tcn.tcn.0))
-                .collect();
-
-            info!("R DB TCNs: {:?}", tcns_for_debugging);
-        }
-
-        let matched_reports: Result<Vec<MatchedReport>, ServicesError> =
-            tcns.and_then(|tcns| self.tcn_matcher.match_reports(tcns, reports));
+        let matched_reports: Result<MatchingResult, ServicesError> = self
+            .tcn_matcher
+            .match_reports(self.tcn_dao.as_ref(), reports);
 
         let time = matching_start_time.elapsed().as_secs();
         info!("Took {:?}s to match reports", time);
 
-        if let Ok(reports) = &matched_reports {
+        if let Ok(MatchingResult {
+            matched: reports, ..
+        }) = &matched_reports
+        {
             if !reports.is_empty() {
-                let reports_strings: Vec<String> = reports
-                    .into_iter()
-                    .map(|report| base64::encode(signed_report_to_bytes(report.report.clone())))
-                    .collect();
-
-                info!("Matches found ({:?}): {:?}", reports.len(), reports_strings);
+                info!("Matches found: {:?}", reports.len());
             } else {
                 info!("No matches found");
             }
@@ -440,17 +778,23 @@ where
         reversed.into_iter().find(|i| i.ends_before(&time))
     }
 
-    fn store_last_completed_interval(&self, intervals: Vec<ReportsInterval>, now: &UnixTime) {
+    fn store_last_completed_interval(
+        &self,
+        source_id: &str,
+        intervals: Vec<ReportsInterval>,
+        now: &UnixTime,
+    ) -> Result<(), DaoError> {
         let interval = Self::interval_ending_before(intervals.clone(), now);
         debug!(
-            "Storing last completed reports interval: {:?}, for intervals: {:?}",
-            interval, intervals
+            "Storing last completed reports interval: {:?}, for source: {}, intervals: {:?}",
+            interval, source_id, intervals
         );
 
         if let Some(interval) = interval {
             self.preferences
-                .set_last_completed_reports_interval(interval);
+                .set_last_completed_reports_interval(source_id, interval)?;
         }
+        Ok(())
     }
 }
 
@@ -467,32 +811,33 @@ trait Also: Sized {
 
 impl<T> Also for T {}
 
-#[derive(Debug, Clone)]
-struct MatchedReportsChunk {
-    reports: Vec<SignedReport>,
-    matched: Vec<MatchedReport>,
-    interval: ReportsInterval,
-}
-
 #[derive(Debug, Clone)]
 struct SignedReportsChunk {
     reports: Vec<SignedReport>,
     interval: ReportsInterval,
+    // Size of the downloaded report strings
+    bytes: u64,
+    // Report strings that couldn't be decoded (not part of reports)
+    decode_failures: u64,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
-        networking::TcnApiImpl,
-        preferences::PreferencesImpl,
+        migrations::tests::test_database,
+        networking::{PaddedUpload, TcnApiImpl},
+        preferences::{PreferencesDao, PreferencesImpl, PreferencesNoopMock},
         reporting::{
             memo::MemoMapperImpl,
             public_report::{CoughSeverity, FeverSeverity},
             symptom_inputs::UserInput,
         },
+        sync_stats::SyncStatsDaoImpl,
+        tcn_ext::emitted_tcns::EmittedTcn,
+        verification::ReportVerification,
     };
-    use rusqlite::Connection;
+    use rusqlite::types::Value;
     use tcn::{MemoType, ReportAuthorizationKey};
 
     #[test]
@@ -516,6 +861,7 @@ mod tests {
                 TcnMatcherRayon,
                 TcnApiImpl,
                 MemoMapperImpl,
+                SyncStatsDaoImpl,
             >::interval_ending_before(intervals, &time);
 
         // time is contained in the interval, and it's the only interval, so there's no interval ending before of time's interval
@@ -548,6 +894,7 @@ mod tests {
                 TcnMatcherRayon,
                 TcnApiImpl,
                 MemoMapperImpl,
+                SyncStatsDaoImpl,
             >::interval_ending_before(intervals, &time);
 
         assert!(interval_ending_before.is_some());
@@ -568,6 +915,7 @@ mod tests {
                 TcnMatcherRayon,
                 TcnApiImpl,
                 MemoMapperImpl,
+                SyncStatsDaoImpl,
             >::interval_ending_before(intervals, &time);
 
         assert!(interval_ending_before.is_none());
@@ -576,9 +924,7 @@ mod tests {
     #[test]
     #[ignore]
     fn saves_and_loads_observed_tcn() {
-        let database = Arc::new(Database::new(
-            Connection::open_in_memory().expect("Couldn't create database!"),
-        ));
+        let database = test_database();
         let tcn_dao = TcnDaoImpl::new(database.clone());
 
         let observed_tcn = ObservedTcn {
@@ -603,9 +949,7 @@ mod tests {
     #[test]
     #[ignore]
     fn saves_and_loads_multiple_tcns() {
-        let database = Arc::new(Database::new(
-            Connection::open_in_memory().expect("Couldn't create database!"),
-        ));
+        let database = test_database();
         let tcn_dao = TcnDaoImpl::new(database.clone());
 
         let observed_tcn_1 = ObservedTcn {
@@ -651,9 +995,7 @@ mod tests {
     // it affects negatively performance.
     // TODO revisit
     fn saves_and_loads_repeated_tcns() {
-        let database = Arc::new(Database::new(
-            Connection::open_in_memory().expect("Couldn't create database!"),
-        ));
+        let database = test_database();
         let tcn_dao = TcnDaoImpl::new(database.clone());
 
         let observed_tcn_1 = ObservedTcn {
@@ -678,6 +1020,72 @@ mod tests {
         assert_eq!(loaded_tcns[1], observed_tcn_1);
     }
 
+    #[test]
+    fn skips_tcns_that_cant_be_read() {
+        let database = test_database();
+        let tcn_dao = TcnDaoImpl::new(database.clone());
+        let observed_tcn = ObservedTcn {
+            tcn: TemporaryContactNumber([
+                24, 229, 125, 245, 98, 86, 219, 221, 172, 25, 232, 150, 206, 66, 164, 173,
+            ]),
+            time: UnixTime { value: 1590528300 },
+        };
+        tcn_dao.save(&observed_tcn).unwrap();
+        for invalid_tcn in vec![Value::Text("text".to_owned()), Value::Blob(vec![1, 2])] {
+            database
+                .execute_sql(
+                    "insert into tcn(tcn, contact_time) values(?1, 1590528300)",
+                    params![invalid_tcn],
+                )
+                .unwrap();
+        }
+
+        assert_eq!(tcn_dao.all().unwrap(), vec![observed_tcn]);
+    }
+
+    #[test]
+    fn loads_tcns_in_range() {
+        let database = test_database();
+        let tcn_dao = TcnDaoImpl::new(database.clone());
+        let observed = |byte: u8, time: u64| ObservedTcn {
+            tcn: TemporaryContactNumber([byte; 16]),
+            time: UnixTime { value: time },
+        };
+        for tcn in &[
+            observed(1, 3000),
+            observed(2, 1000),
+            observed(1, 2000),
+            observed(3, 4000),
+        ] {
+            tcn_dao.save(tcn).unwrap();
+        }
+
+        let range = |from, to| {
+            let mut streamed = vec![];
+            tcn_dao
+                .for_each_in_range(&UnixTime { value: from }, &UnixTime { value: to }, |tcn| {
+                    streamed.push(tcn)
+                })
+                .unwrap();
+            streamed
+        };
+        // Ordered by time, end excluded
+        assert_eq!(
+            range(1000, 4000),
+            vec![observed(2, 1000), observed(1, 2000), observed(1, 3000)]
+        );
+        assert_eq!(range(4001, 5000), vec![]);
+
+        assert_eq!(
+            tcn_dao.sightings(&TemporaryContactNumber([1; 16])).unwrap(),
+            2
+        );
+        assert_eq!(
+            tcn_dao.sightings(&TemporaryContactNumber([4; 16])).unwrap(),
+            0
+        );
+    }
+
     // Utility to see quickly all TCNs (hex) for a report
     #[test]
     #[ignore]
@@ -699,15 +1107,17 @@ mod tests {
         let verification_contact_time = UnixTime { value: 1590528300 };
         let verification_report = SignedReport::with_str(verification_report_str).unwrap();
 
-        let mut reports: Vec<SignedReport> = vec![0; 20]
+        let window = verification_contact_time.value - 3600..verification_contact_time.value + 1;
+        let mut reports: Vec<ReportWindow> = vec![0; 20]
             .into_iter()
-            .map(|_| create_test_report())
+            .map(|_| (create_test_report(), window.clone()))
             .collect();
-        reports.push(verification_report);
+        reports.push((verification_report, window));
 
         // let matcher = TcnMatcherStdThreadSpawn {}; // 20 -> 1s, 200 -> 16s, 1000 -> 84s, 10000 ->
         let matcher = TcnMatcherRayon {}; // 20 -> 1s, 200 -> 7s, 1000 -> 87s, 10000 -> 927s
 
+        let tcn_dao = TcnDaoImpl::new(test_database());
         let tcns = vec![
             ObservedTcn {
                 tcn: TemporaryContactNumber([0; 16]),
@@ -722,12 +1132,15 @@ mod tests {
                 time: UnixTime { value: 1590528300 },
             },
         ];
+        for tcn in &tcns {
+            tcn_dao.save(tcn).unwrap();
+        }
 
         let matching_start_time = Instant::now();
 
-        let res = matcher.match_reports(tcns, reports);
+        let res = matcher.match_reports(&tcn_dao, reports);
 
-        let matches = res.unwrap();
+        let matches = res.unwrap().matched;
         assert_eq!(matches.len(), 1);
 
         let time = matching_start_time.elapsed().as_secs();
@@ -739,6 +1152,435 @@ mod tests {
         assert_eq!(matches[0].contact_time, verification_contact_time);
     }
 
+    #[test]
+    fn matches_tcns_observed_in_report_window() {
+        let tcn_dao = TcnDaoImpl::new(test_database());
+        let report = create_test_report();
+        let tcns: Vec<TemporaryContactNumber> = report
+            .clone()
+            .verify()
+            .unwrap()
+            .temporary_contact_numbers()
+            .take(2)
+            .collect();
+        for (tcn, time) in tcns.into_iter().zip(&[1000, 5000]) {
+            tcn_dao
+                .save(&ObservedTcn {
+                    tcn,
+                    time: UnixTime { value: *time },
+                })
+                .unwrap();
+        }
+
+        let contact_time = |window: Range<u64>| {
+            TcnMatcherRayon {}
+                .match_reports(&tcn_dao, vec![(report.clone(), window)])
+                .unwrap()
+                .matched
+                .first()
+                .map(|matched| matched.contact_time.value)
+        };
+        // The earliest contact in the window
+        assert_eq!(contact_time(0..6000), Some(1000));
+        assert_eq!(contact_time(2000..6000), Some(5000));
+        assert_eq!(contact_time(2000..5000), None);
+    }
+
+    struct ReportsApiFake {
+        reports: Vec<String>,
+        // Status of the failed requests
+        fail_status: Option<u16>,
+    }
+
+    impl TcnApi for ReportsApiFake {
+        fn get_reports(&self, _: u64, _: u64) -> Result<Vec<String>, NetworkingError> {
+            match self.fail_status {
+                Some(http_status) => Err(NetworkingError {
+                    http_status,
+                    message: "Test".to_owned(),
+                }),
+                None => Ok(self.reports.clone()),
+            }
+        }
+
+        fn post_report(&self, _: String) -> Result<(), NetworkingError> {
+            Ok(())
+        }
+
+        fn post_verified_reports(
+            &self,
+            _: Vec<String>,
+            _: ReportVerification,
+        ) -> Result<(), NetworkingError> {
+            Ok(())
+        }
+
+        fn post_padded_report(&self, _: PaddedUpload) -> Result<(), NetworkingError> {
+            Ok(())
+        }
+    }
+
+    fn source(id: &str, reports: Vec<String>, fail: bool) -> ReportsSource<ReportsApiFake> {
+        source_with_status(id, reports, if fail { Some(500) } else { None })
+    }
+
+    fn source_with_status(
+        id: &str,
+        reports: Vec<String>,
+        fail_status: Option<u16>,
+    ) -> ReportsSource<ReportsApiFake> {
+        ReportsSource {
+            id: id.to_owned(),
+            api: Arc::new(ReportsApiFake {
+                reports,
+                fail_status,
+            }),
+            interval_length: 3600,
+        }
+    }
+
+    #[test]
+    fn merges_and_deduplicates_reports_from_all_sources() {
+        let database = test_database();
+        let preferences = Arc::new(PreferencesImpl::new(
+            PreferencesDao::new(database.clone()),
+            None,
+        ));
+        let tcn_dao = Arc::new(TcnDaoImpl::new(database.clone()));
+        let sync_stats_dao = Arc::new(SyncStatsDaoImpl::new(database.clone()));
+
+        let now = UnixTime::now();
+        let save_first_tcn = |report: &SignedReport, time: u64| {
+            let tcn = report
+                .clone()
+                .verify()
+                .unwrap()
+                .temporary_contact_numbers()
+                .next()
+                .unwrap();
+            tcn_dao
+                .save(&ObservedTcn {
+                    tcn,
+                    time: UnixTime { value: time },
+                })
+                .unwrap();
+        };
+
+        let reports: Vec<SignedReport> = vec![0; 3].iter().map(|_| create_test_report()).collect();
+        for report in &reports {
+            // Before the upload of the reports
+            save_first_tcn(report, now.value - 5 * 3600);
+        }
+        let mut report_strings: Vec<String> = reports
+            .into_iter()
+            .map(|report| base64::encode(signed_report_to_bytes(report)))
+            .collect();
+        report_strings.push("invalid".to_owned());
+
+        // Observed before the period covered by the fetched reports: not matched
+        let old_report = create_test_report();
+        save_first_tcn(
+            &old_report,
+            now.value - REPORTED_CONTACTS_MAX_AGE_SECS - 5 * 3600,
+        );
+        let mut source_a_reports = report_strings[0..2].to_vec();
+        source_a_reports.push(base64::encode(signed_report_to_bytes(old_report)));
+
+        let last_completed = ReportsInterval::create_for(&now, 3600).number - 3;
+        for id in &["a", "b", "c"] {
+            preferences
+                .set_last_completed_reports_interval(
+                    id,
+                    ReportsInterval {
+                        number: last_completed,
+                        length: 3600,
+                    },
+                )
+                .unwrap();
+        }
+
+        let reports_updater = ReportsUpdater {
+            preferences: preferences.clone(),
+            tcn_dao: tcn_dao.clone(),
+            tcn_matcher: TcnMatcherRayon {},
+            sources: vec![
+                source("a", source_a_reports, false),
+                source("b", report_strings[1..4].to_vec(), false),
+                source("c", vec![], true),
+            ],
+            memo_mapper: &MemoMapperImpl {},
+            sync_stats_dao: sync_stats_dao.clone(),
+            emitted_tcns: Arc::new(EmittedTcnDao::new(database.clone())),
+            alert_dao: Arc::new(AlertDao::new(database.clone())),
+        };
+
+        let mut alerts = reports_updater.fetch_new_reports().unwrap();
+        alerts.sort_by(|a1, a2| a1.source.cmp(&a2.source));
+
+        // The report both sources returned is attributed to the first one
+        let sources: Vec<&str> = alerts.iter().map(|alert| alert.source.as_ref()).collect();
+        assert_eq!(sources, vec!["a", "a", "b"]);
+
+        // Progress is stored only for the sources that could be fetched
+        let progress = |id| {
+            preferences
+                .last_completed_reports_interval(id)
+                .unwrap()
+                .unwrap()
+                .number
+        };
+        assert!(progress("a") > last_completed);
+        assert!(progress("b") > last_completed);
+        assert_eq!(progress("c"), last_completed);
+
+        let stats = &sync_stats_dao.latest(1).unwrap()[0];
+        assert!(stats.successful);
+        assert_eq!(stats.matches, 3);
+        // Source "b" returned an invalid report string for each interval
+        let b_intervals = stats
+            .intervals
+            .iter()
+            .filter(|interval| interval.source == "b")
+            .count();
+        assert_eq!(stats.decode_failures, b_intervals as u64);
+        assert_eq!(stats.errors.len(), 1);
+        assert!(stats
+            .intervals
+            .iter()
+            .all(|interval| interval.source != "c"));
+        assert!(stats.bytes_downloaded > 0);
+    }
+
+    #[test]
+    fn ignores_own_reports() {
+        let database = test_database();
+        let tcn_dao = Arc::new(TcnDaoImpl::new(database.clone()));
+        let sync_stats_dao = Arc::new(SyncStatsDaoImpl::new(database.clone()));
+        let emitted_tcns = Arc::new(EmittedTcnDao::new(database.clone()));
+
+        let now = UnixTime::now();
+        let reports: Vec<SignedReport> = vec![0; 2].iter().map(|_| create_test_report()).collect();
+        for report in &reports {
+            let tcn = report
+                .clone()
+                .verify()
+                .unwrap()
+                .temporary_contact_numbers()
+                .next()
+                .unwrap();
+            tcn_dao
+                .save(&ObservedTcn {
+                    tcn,
+                    time: UnixTime {
+                        value: now.value - 3600,
+                    },
+                })
+                .unwrap();
+        }
+        // The first report was sent by this device
+        let own_tcn = reports[0]
+            .clone()
+            .verify()
+            .unwrap()
+            .temporary_contact_numbers()
+            .nth(1)
+            .unwrap();
+        emitted_tcns
+            .record(&EmittedTcn {
+                tcn: own_tcn,
+                key_created: now.value - 7200,
+                index: 2,
+                time: UnixTime {
+                    value: now.value - 3600,
+                },
+            })
+            .unwrap();
+        let own_report_id = format!("{:?}", reports[0].sig);
+
+        let reports_updater = ReportsUpdater {
+            preferences: Arc::new(PreferencesNoopMock {}),
+            tcn_dao,
+            tcn_matcher: TcnMatcherRayon {},
+            sources: vec![source(
+                "a",
+                reports
+                    .into_iter()
+                    .map(|report| base64::encode(signed_report_to_bytes(report)))
+                    .collect(),
+                false,
+            )],
+            memo_mapper: &MemoMapperImpl {},
+            sync_stats_dao: sync_stats_dao.clone(),
+            emitted_tcns,
+            alert_dao: Arc::new(AlertDao::new(database.clone())),
+        };
+
+        let alerts = reports_updater.fetch_new_reports().unwrap();
+
+        assert_eq!(alerts.len(), 1);
+        assert_ne!(alerts[0].id, own_report_id);
+        assert_eq!(sync_stats_dao.latest(1).unwrap()[0].matches, 1);
+    }
+
+    #[test]
+    fn applies_revocations_and_amendments_to_alerts() {
+        let database = test_database();
+        let tcn_dao = Arc::new(TcnDaoImpl::new(database.clone()));
+        let alert_dao = Arc::new(AlertDao::new(database.clone()));
+        let now = UnixTime::now();
+
+        let rak_a = ReportAuthorizationKey::new(rand::thread_rng());
+        let rak_b = ReportAuthorizationKey::new(rand::thread_rng());
+        let rak_unknown = ReportAuthorizationKey::new(rand::thread_rng());
+        let amended = PublicReport {
+            report_time: UnixTime { value: 1589209800 },
+            fever_severity: FeverSeverity::None,
+            ..test_public_report()
+        };
+        let revocation = PublicReport {
+            report_time: UnixTime { value: 1589209900 },
+            ..test_public_report()
+        };
+        let report_a = create_report_with(&rak_a, MemoKind::Report, true, test_public_report());
+        let report_b = create_report_with(&rak_b, MemoKind::Report, false, test_public_report());
+        for report in &[report_a.clone(), report_b.clone()] {
+            let tcn = report
+                .clone()
+                .verify()
+                .unwrap()
+                .temporary_contact_numbers()
+                .next()
+                .unwrap();
+            tcn_dao
+                .save(&ObservedTcn {
+                    tcn,
+                    time: UnixTime {
+                        value: now.value - 3600,
+                    },
+                })
+                .unwrap();
+        }
+        let fetch = |reports: Vec<SignedReport>| {
+            ReportsUpdater {
+                preferences: Arc::new(PreferencesNoopMock {}),
+                tcn_dao: tcn_dao.clone(),
+                tcn_matcher: TcnMatcherRayon {},
+                sources: vec![source(
+                    "a",
+                    reports
+                        .into_iter()
+                        .map(|report| base64::encode(signed_report_to_bytes(report)))
+                        .collect(),
+                    false,
+                )],
+                memo_mapper: &MemoMapperImpl {},
+                sync_stats_dao: Arc::new(SyncStatsDaoImpl::new(database.clone())),
+                emitted_tcns: Arc::new(EmittedTcnDao::new(database.clone())),
+                alert_dao: alert_dao.clone(),
+            }
+            .fetch_new_reports()
+            .unwrap()
+        };
+
+        let alerts = fetch(vec![report_a.clone()]);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].status, AlertStatus::Active);
+        assert!(alerts[0].verified);
+        let id_a = alerts[0].id.clone();
+
+        // The revocation is downloaded with the report: only the revoked alert is sent
+        let alerts = fetch(vec![
+            create_report_with(&rak_b, MemoKind::Revocation, false, revocation.clone()),
+            report_b,
+            create_report_with(
+                &rak_unknown,
+                MemoKind::Revocation,
+                false,
+                revocation.clone(),
+            ),
+        ]);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].status, AlertStatus::Revoked);
+        assert_eq!(alerts[0].report, test_public_report());
+
+        let alerts = fetch(vec![create_report_with(
+            &rak_a,
+            MemoKind::Amendment,
+            false,
+            amended.clone(),
+        )]);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].id, id_a);
+        assert_eq!(alerts[0].status, AlertStatus::Amended);
+        assert_eq!(alerts[0].report, amended);
+        // Not verified itself
+        assert!(!alerts[0].verified);
+
+        let alerts = fetch(vec![
+            report_a,
+            create_report_with(&rak_a, MemoKind::Revocation, false, revocation),
+        ]);
+        // The report was already sent
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].id, id_a);
+        assert_eq!(alerts[0].status, AlertStatus::Revoked);
+    }
+
+    #[test]
+    fn fails_if_no_source_can_be_fetched() {
+        let database = test_database();
+        let sync_stats_dao = Arc::new(SyncStatsDaoImpl::new(database.clone()));
+        let reports_updater = ReportsUpdater {
+            preferences: Arc::new(PreferencesNoopMock {}),
+            tcn_dao: Arc::new(TcnDaoImpl::new(database.clone())),
+            tcn_matcher: TcnMatcherRayon {},
+            sources: vec![source("a", vec![], true), source("b", vec![], true)],
+            memo_mapper: &MemoMapperImpl {},
+            sync_stats_dao: sync_stats_dao.clone(),
+            emitted_tcns: Arc::new(EmittedTcnDao::new(database.clone())),
+            alert_dao: Arc::new(AlertDao::new(database.clone())),
+        };
+
+        assert!(reports_updater.fetch_new_reports().is_err());
+
+        let report = sync_stats_dao.report().unwrap();
+        assert_eq!(report.last_successful_sync_time, None);
+        assert!(!report.syncs[0].successful);
+    }
+
+    #[test]
+    fn fails_if_a_source_requires_reauth() {
+        let database = test_database();
+        let preferences = Arc::new(PreferencesImpl::new(
+            PreferencesDao::new(database.clone()),
+            None,
+        ));
+        let reports_updater = ReportsUpdater {
+            preferences: preferences.clone(),
+            tcn_dao: Arc::new(TcnDaoImpl::new(database.clone())),
+            tcn_matcher: TcnMatcherRayon {},
+            sources: vec![
+                source("a", vec![], false),
+                source_with_status("b", vec![], Some(401)),
+                source("c", vec![], true),
+            ],
+            memo_mapper: &MemoMapperImpl {},
+            sync_stats_dao: Arc::new(SyncStatsDaoImpl::new(database.clone())),
+            emitted_tcns: Arc::new(EmittedTcnDao::new(database.clone())),
+            alert_dao: Arc::new(AlertDao::new(database.clone())),
+        };
+
+        match reports_updater.fetch_new_reports() {
+            Err(ServicesError::ReauthRequired(error)) => assert_eq!(error.http_status, 401),
+            other => panic!("Expected ReauthRequired, got: {:?}", other.map(|_| ())),
+        }
+        // Fetched again next time
+        assert_eq!(
+            preferences.last_completed_reports_interval("a").unwrap(),
+            None
+        );
+    }
+
     #[test]
     fn test_report_empty_is_none() {
         assert!(SignedReport::with_str("").is_none())
@@ -754,9 +1596,57 @@ mod tests {
         assert!(SignedReport::with_str("slkdjfslfd").is_none())
     }
 
+    #[test]
+    fn revocation_is_rejected_by_baseline_parser() {
+        // How clients before revocations decode reports
+        fn baseline_with_str(str: &str) -> Option<SignedReport> {
+            base64::decode(str)
+                .map_err(Error::from)
+                .and_then(|bytes| SignedReport::read(bytes.as_slice()).map_err(Error::from))
+                .ok()
+        }
+        let rak = ReportAuthorizationKey::new(rand::thread_rng());
+        for kind in &[MemoKind::Report, MemoKind::Revocation, MemoKind::Amendment] {
+            let report = create_report_with(&rak, *kind, false, test_public_report());
+            let report_str = format!(
+                "{}{}",
+                kind.marker(),
+                base64::encode(signed_report_to_bytes(report))
+            );
+            assert_eq!(
+                baseline_with_str(&report_str).is_some(),
+                *kind == MemoKind::Report
+            );
+            let report = SignedReport::with_str(&report_str)
+                .unwrap()
+                .verify()
+                .unwrap();
+            let memo = Memo {
+                bytes: report.memo_data().to_vec(),
+            };
+            assert_eq!(MemoMapperImpl {}.kind(&memo), *kind);
+        }
+    }
+
     fn create_test_report() -> SignedReport {
-        let memo_mapper = MemoMapperImpl {};
-        let public_report = PublicReport {
+        let rak = ReportAuthorizationKey::new(rand::thread_rng());
+        create_report_with(&rak, MemoKind::Report, false, test_public_report())
+    }
+
+    fn create_report_with(
+        rak: &ReportAuthorizationKey,
+        kind: MemoKind,
+        verified: bool,
+        public_report: PublicReport,
+    ) -> SignedReport {
+        let token_hash = if verified { Some([1; 32]) } else { None };
+        let memo_data = MemoMapperImpl {}.to_memo_of_kind(kind, token_hash, public_report);
+        rak.create_report(MemoType::CoEpiV1, memo_data.bytes, 1, 10000)
+            .unwrap()
+    }
+
+    fn test_public_report() -> PublicReport {
+        PublicReport {
             report_time: UnixTime { value: 1589209754 },
             earliest_symptom_time: UserInput::Some(UnixTime { value: 1589209754 }),
             fever_severity: FeverSeverity::Serious,
@@ -768,11 +1658,7 @@ mod tests {
             runny_nose: true,
             other: false,
             no_symptoms: true,
-        };
-        let rak = ReportAuthorizationKey::new(rand::thread_rng());
-        let memo_data = memo_mapper.to_memo(public_report);
-        rak.create_report(MemoType::CoEpiV1, memo_data.bytes, 1, 10000)
-            .unwrap()
+        }
     }
 }
 
@@ -0,0 +1,173 @@
+use crate::{errors::DaoError, preferences::Database};
+use rusqlite::{params, Row};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// Number of syncs kept in the database. Older ones are deleted when saving.
+const MAX_STORED_SYNCS: u32 = 50;
+
+// What happened during one execution of fetch_new_reports. Meant to diagnose e.g. missing alerts.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncStats {
+    // Start of the sync (unix time)
+    pub time: u64,
+    // True if reports of at least one source were downloaded and matched
+    pub successful: bool,
+    // Size of the downloaded reports (base64)
+    pub bytes_downloaded: u64,
+    pub intervals: Vec<IntervalStats>,
+    pub decode_failures: u64,
+    pub verification_failures: u64,
+    pub matches: u64,
+    pub matching_time_ms: u64,
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IntervalStats {
+    pub source: String,
+    pub interval_number: u64,
+    pub interval_length: u64,
+    pub reports: u64,
+}
+
+// Sent to the apps
+#[derive(Debug, Serialize)]
+pub struct SyncStatsReport {
+    pub last_successful_sync_time: Option<u64>,
+    // Most recent first
+    pub syncs: Vec<SyncStats>,
+}
+
+pub trait SyncStatsDao {
+    fn save(&self, stats: &SyncStats) -> Result<(), DaoError>;
+    fn latest(&self, limit: u32) -> Result<Vec<SyncStats>, DaoError>;
+    fn last_successful_sync_time(&self) -> Result<Option<u64>, DaoError>;
+
+    fn report(&self) -> Result<SyncStatsReport, DaoError> {
+        Ok(SyncStatsReport {
+            last_successful_sync_time: self.last_successful_sync_time()?,
+            syncs: self.latest(MAX_STORED_SYNCS)?,
+        })
+    }
+}
+
+pub struct SyncStatsDaoImpl {
+    db: Arc<Database>,
+}
+
+impl SyncStatsDaoImpl {
+    pub fn new(db: Arc<Database>) -> SyncStatsDaoImpl {
+        SyncStatsDaoImpl { db }
+    }
+
+    fn to_stats(row: &Row) -> Result<SyncStats, DaoError> {
+        let stats: String = row.get(0)?;
+        Ok(serde_json::from_str(&stats)?)
+    }
+}
+
+impl SyncStatsDao for SyncStatsDaoImpl {
+    fn save(&self, stats: &SyncStats) -> Result<(), DaoError> {
+        let json = serde_json::to_string(stats)?;
+        self.db.execute_sql(
+            "insert into sync_stats(time, successful, stats) values(?1, ?2, ?3)",
+            params![stats.time as i64, stats.successful, json],
+        )?;
+        self.db.execute_sql(
+            "delete from sync_stats where rowid not in
+                (select rowid from sync_stats order by rowid desc limit ?1)",
+            params![MAX_STORED_SYNCS],
+        )?;
+        Ok(())
+    }
+
+    fn latest(&self, limit: u32) -> Result<Vec<SyncStats>, DaoError> {
+        let stats = self.db.query(
+            "select stats from sync_stats order by rowid desc limit ?1",
+            params![limit],
+            |row| Self::to_stats(row),
+        )?;
+        Ok(stats)
+    }
+
+    fn last_successful_sync_time(&self) -> Result<Option<u64>, DaoError> {
+        let time: Option<i64> = self.db.query_row(
+            "select max(time) from sync_stats where successful = 1",
+            params![],
+            |row| row.get(0),
+        )?;
+        Ok(time.map(|time| time as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::tests::test_database;
+
+    fn create_dao() -> SyncStatsDaoImpl {
+        SyncStatsDaoImpl::new(test_database())
+    }
+
+    #[test]
+    fn saves_and_loads_stats() {
+        let dao = create_dao();
+        let stats = SyncStats {
+            time: 1592567315,
+            successful: true,
+            bytes_downloaded: 1234,
+            intervals: vec![IntervalStats {
+                source: "default".to_owned(),
+                interval_number: 73690,
+                interval_length: 21600,
+                reports: 3,
+            }],
+            decode_failures: 1,
+            verification_failures: 2,
+            matches: 1,
+            matching_time_ms: 40,
+            errors: vec![],
+        };
+
+        dao.save(&stats).unwrap();
+
+        assert_eq!(dao.latest(10).unwrap(), vec![stats]);
+    }
+
+    #[test]
+    fn last_successful_sync_ignores_failed_syncs() {
+        let dao = create_dao();
+        assert_eq!(dao.last_successful_sync_time().unwrap(), None);
+
+        for (time, successful) in &[(100, true), (200, true), (300, false)] {
+            dao.save(&SyncStats {
+                time: *time,
+                successful: *successful,
+                ..SyncStats::default()
+            })
+            .unwrap();
+        }
+
+        let report = dao.report().unwrap();
+        assert_eq!(report.last_successful_sync_time, Some(200));
+        let times: Vec<u64> = report.syncs.iter().map(|stats| stats.time).collect();
+        assert_eq!(times, vec![300, 200, 100]);
+    }
+
+    #[test]
+    fn keeps_only_latest_syncs() {
+        let dao = create_dao();
+        for time in 0..(MAX_STORED_SYNCS as u64 + 5) {
+            dao.save(&SyncStats {
+                time,
+                ..SyncStats::default()
+            })
+            .unwrap();
+        }
+
+        let syncs = dao.latest(MAX_STORED_SYNCS + 10).unwrap();
+        assert_eq!(syncs.len(), MAX_STORED_SYNCS as usize);
+        assert_eq!(syncs[0].time, MAX_STORED_SYNCS as u64 + 4);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::{
+    byte_vec_to_16_byte_array, errors::DaoError, preferences::Database, reports_interval::UnixTime,
+    tcn_ext::tcn_keys::REPORT_PERIOD_SECS,
+};
+use rusqlite::{params, Row, NO_PARAMS};
+use serde::Serialize;
+use std::{collections::HashSet, sync::Arc};
+use tcn::TemporaryContactNumber;
+
+// A TCN broadcast by this device, when it was first returned by generate_tcn.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmittedTcn {
+    pub tcn: TemporaryContactNumber,
+    // Identifies the authorization key the TCN was generated with
+    pub key_created: u64,
+    pub index: u16,
+    pub time: UnixTime,
+}
+
+// Sent to the apps: what a report would disclose.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ReportPreview {
+    // Start of the report window (unix time)
+    pub from: u64,
+    // Times the TCNs included in the report were first broadcast, ordered
+    pub emission_times: Vec<u64>,
+}
+
+// Log of the emitted TCNs. Only the ones that can still be reported are kept.
+pub struct EmittedTcnDao {
+    db: Arc<Database>,
+}
+
+impl EmittedTcnDao {
+    pub fn new(db: Arc<Database>) -> EmittedTcnDao {
+        EmittedTcnDao { db }
+    }
+
+    // Ignored if the TCN is already in the log
+    pub fn record(&self, emitted: &EmittedTcn) -> Result<(), DaoError> {
+        self.db.transaction(|tx| {
+            tx.execute(
+                "insert or ignore into emitted_tcn(tcn, key_created, tck_index, time)
+                values(?1, ?2, ?3, ?4)",
+                params![
+                    &emitted.tcn.0[..],
+                    emitted.key_created as i64,
+                    emitted.index,
+                    emitted.time.value as i64
+                ],
+            )?;
+            tx.execute(
+                "delete from emitted_tcn where time < ?1",
+                params![emitted.time.value.saturating_sub(REPORT_PERIOD_SECS) as i64],
+            )
+        })?;
+        Ok(())
+    }
+
+    // Emitted at or after from, ordered by time
+    pub fn since(&self, from: &UnixTime) -> Result<Vec<EmittedTcn>, DaoError> {
+        let emitted = self.db.query(
+            "select tcn, key_created, tck_index, time from emitted_tcn where time >= ?1
+            order by time",
+            params![from.value as i64],
+            Self::to_emitted_tcn,
+        )?;
+        Ok(emitted)
+    }
+
+    pub fn tcns(&self) -> Result<HashSet<[u8; 16]>, DaoError> {
+        let emitted = self.db.query(
+            "select tcn, key_created, tck_index, time from emitted_tcn",
+            NO_PARAMS,
+            Self::to_emitted_tcn,
+        )?;
+        Ok(emitted.into_iter().map(|emitted| emitted.tcn.0).collect())
+    }
+
+    fn to_emitted_tcn(row: &Row) -> Result<EmittedTcn, DaoError> {
+        let tcn: Vec<u8> = row.get(0)?;
+        if tcn.len() != 16 {
+            return Err(DaoError::InvalidData(format!(
+                "Invalid emitted TCN length: {}",
+                tcn.len()
+            )));
+        }
+        let key_created: i64 = row.get(1)?;
+        let time: i64 = row.get(3)?;
+        Ok(EmittedTcn {
+            tcn: TemporaryContactNumber(byte_vec_to_16_byte_array(tcn)),
+            key_created: key_created as u64,
+            index: row.get(2)?,
+            time: UnixTime { value: time as u64 },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::tests::test_database;
+
+    fn emitted(byte: u8, time: u64) -> EmittedTcn {
+        EmittedTcn {
+            tcn: TemporaryContactNumber([byte; 16]),
+            key_created: 1000,
+            index: byte as u16,
+            time: UnixTime { value: time },
+        }
+    }
+
+    #[test]
+    fn keeps_first_emission_of_reportable_tcns() {
+        let dao = EmittedTcnDao::new(test_database());
+        let start = 2_000_000;
+        dao.record(&emitted(1, start)).unwrap();
+        dao.record(&emitted(2, start + 100)).unwrap();
+        // Repeated: the first emission is kept
+        dao.record(&emitted(2, start + 200)).unwrap();
+
+        assert_eq!(
+            dao.since(&UnixTime { value: 0 }).unwrap(),
+            vec![emitted(1, start), emitted(2, start + 100)]
+        );
+        assert_eq!(
+            dao.since(&UnixTime { value: start + 1 }).unwrap(),
+            vec![emitted(2, start + 100)]
+        );
+
+        // Not reportable anymore: deleted
+        dao.record(&emitted(3, start + REPORT_PERIOD_SECS + 50))
+            .unwrap();
+        assert_eq!(
+            dao.tcns().unwrap(),
+            vec![[2; 16], [3; 16]].into_iter().collect()
+        );
+    }
+}
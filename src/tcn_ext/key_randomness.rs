@@ -0,0 +1,118 @@
+use crate::{errors::ServicesError, expect_log};
+#[cfg(any(test, feature = "test-fixtures"))]
+use hmac::{Hmac, Mac, NewMac};
+#[cfg(any(test, feature = "test-fixtures"))]
+use log::*;
+use rand::{thread_rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+#[cfg(any(test, feature = "test-fixtures"))]
+use sha2::Sha256;
+use std::sync::Arc;
+
+#[cfg(any(test, feature = "test-fixtures"))]
+pub const SEED_SIZE: usize = 32;
+
+// Source of the randomness of the authorization keys.
+pub trait KeyRandomness: Send + Sync {
+    // RNG to create the key created at created (unix time) with.
+    fn rng_for_key(&self, created: u64) -> ChaCha20Rng;
+}
+
+pub struct SystemRandomness {}
+
+impl KeyRandomness for SystemRandomness {
+    fn rng_for_key(&self, _created: u64) -> ChaCha20Rng {
+        let res = ChaCha20Rng::from_rng(thread_rng());
+        expect_log!(res, "Couldn't seed key RNG")
+    }
+}
+
+// The seed of the configuration is only accepted by builds with the "test-fixtures" feature:
+// anyone knowing it could forge or revoke the user's reports.
+pub fn key_randomness(seed: Option<&str>) -> Result<Arc<dyn KeyRandomness>, ServicesError> {
+    match seed {
+        None => Ok(Arc::new(SystemRandomness {})),
+        #[cfg(feature = "test-fixtures")]
+        Some(seed) => Ok(Arc::new(SeededRandomness::from_hex(seed)?)),
+        #[cfg(not(feature = "test-fixtures"))]
+        Some(_) => Err(ServicesError::General(
+            "A key seed was passed, but core wasn't built with \"test-fixtures\"".to_owned(),
+        )),
+    }
+}
+
+// Derives the keys from a seed and their creation time: with the same seed (and clock),
+// the same keys are created. Only for tests and simulations: the keys are predictable.
+#[cfg(any(test, feature = "test-fixtures"))]
+pub struct SeededRandomness {
+    seed: [u8; SEED_SIZE],
+}
+
+#[cfg(any(test, feature = "test-fixtures"))]
+impl SeededRandomness {
+    pub fn new(seed: [u8; SEED_SIZE]) -> SeededRandomness {
+        warn!("Deriving the authorization keys from a seed. Never use this in production!");
+        SeededRandomness { seed }
+    }
+
+    pub fn from_hex(seed_hex: &str) -> Result<SeededRandomness, ServicesError> {
+        let bytes = hex::decode(seed_hex)?;
+        if bytes.len() != SEED_SIZE {
+            return Err(ServicesError::General(format!(
+                "Invalid key seed length: {}, expected: {}",
+                bytes.len(),
+                SEED_SIZE
+            )));
+        }
+        let mut seed = [0; SEED_SIZE];
+        seed.copy_from_slice(&bytes);
+        Ok(Self::new(seed))
+    }
+}
+
+#[cfg(any(test, feature = "test-fixtures"))]
+impl KeyRandomness for SeededRandomness {
+    fn rng_for_key(&self, created: u64) -> ChaCha20Rng {
+        // HMAC-SHA256(seed, "rak" || created)
+        let res = Hmac::<Sha256>::new_varkey(&self.seed);
+        let mut mac = expect_log!(res, "Couldn't create HMAC");
+        mac.update(b"rak");
+        mac.update(&created.to_be_bytes());
+        let mut key_seed = [0; 32];
+        key_seed.copy_from_slice(&mac.finalize().into_bytes());
+        ChaCha20Rng::from_seed(key_seed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn bytes(rng: &mut ChaCha20Rng) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        rng.fill_bytes(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn seeded_randomness_depends_on_seed_and_creation_time() {
+        let randomness = SeededRandomness::new([1; SEED_SIZE]);
+        let same_seed = SeededRandomness::from_hex(&hex::encode([1; SEED_SIZE])).unwrap();
+        let other_seed = SeededRandomness::new([2; SEED_SIZE]);
+
+        let key = bytes(&mut randomness.rng_for_key(1000));
+        assert_eq!(bytes(&mut same_seed.rng_for_key(1000)), key);
+        assert_ne!(bytes(&mut randomness.rng_for_key(1001)), key);
+        assert_ne!(bytes(&mut other_seed.rng_for_key(1000)), key);
+
+        assert!(SeededRandomness::from_hex("0102").is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "test-fixtures"))]
+    fn rejects_seed_without_feature() {
+        assert!(key_randomness(None).is_ok());
+        assert!(key_randomness(Some(&hex::encode([1; SEED_SIZE]))).is_err());
+    }
+}
@@ -1 +1,4 @@
+pub mod emitted_tcns;
+pub mod key_randomness;
+pub mod recovery_phrase;
 pub mod tcn_keys;
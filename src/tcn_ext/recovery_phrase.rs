@@ -0,0 +1,238 @@
+use crate::{errors::ServicesError, preferences::RetiredAuthorizationKey};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+
+// Crockford's base32: no I, L, O, U, so it can be read and typed without ambiguity.
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const VERSION: u8 = 1;
+const CHECKSUM_SIZE: usize = 4;
+const GROUP_SIZE: usize = 5;
+const CURRENT_KEY_SIZE: usize = 32 + 8 + 2;
+const RETIRED_KEY_SIZE: usize = 32 + 2 + 2 + 8 + 8;
+
+// The authorization keys needed to report the TCNs broadcast by the device:
+// the current key with its TCK position, and the retired keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeySet {
+    pub key: [u8; 32],
+    pub created: u64,
+    pub tck_index: u16,
+    pub retired: Vec<RetiredAuthorizationKey>,
+}
+
+// Format: base32(version || current key || retired count || retired keys || checksum),
+// in dash separated groups. The checksum is the beginning of the SHA-256 of the rest.
+pub fn encode(key_set: &KeySet) -> String {
+    let mut bytes = vec![VERSION];
+    bytes.extend_from_slice(&key_set.key);
+    bytes.extend_from_slice(&key_set.created.to_be_bytes());
+    bytes.extend_from_slice(&key_set.tck_index.to_be_bytes());
+    // Retired keys are kept only for the report period, so there are never many
+    let retired = &key_set.retired[key_set.retired.len().saturating_sub(u8::MAX as usize)..];
+    bytes.push(retired.len() as u8);
+    for key in retired {
+        bytes.extend_from_slice(&key.key);
+        bytes.extend_from_slice(&key.start_index.to_be_bytes());
+        bytes.extend_from_slice(&key.end_index.to_be_bytes());
+        bytes.extend_from_slice(&key.start_time.to_be_bytes());
+        bytes.extend_from_slice(&key.end_time.to_be_bytes());
+    }
+    let checksum = checksum(&bytes);
+    bytes.extend_from_slice(&checksum);
+
+    let chars = to_base32(&bytes);
+    chars
+        .chunks(GROUP_SIZE)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+// Case insensitive. Dashes and whitespace are ignored, O is read as 0 and I, L as 1.
+pub fn decode(phrase: &str) -> Result<KeySet, ServicesError> {
+    let bytes = from_base32(phrase)?;
+    if bytes.len() < 1 + CURRENT_KEY_SIZE + 1 + CHECKSUM_SIZE {
+        return Err(ServicesError::General(
+            "Recovery phrase is too short".to_owned(),
+        ));
+    }
+    let (content, checksum_bytes) = bytes.split_at(bytes.len() - CHECKSUM_SIZE);
+    if checksum(content) != checksum_bytes {
+        return Err(ServicesError::General(
+            "Invalid recovery phrase: wrong checksum. Is there a typo?".to_owned(),
+        ));
+    }
+    if content[0] != VERSION {
+        return Err(ServicesError::General(format!(
+            "Unsupported recovery phrase version: {}",
+            content[0]
+        )));
+    }
+
+    let mut reader = Reader {
+        bytes: &content[1..],
+    };
+    let key = reader.key();
+    let created = reader.u64();
+    let tck_index = reader.u16();
+    let count = reader.take(1)[0] as usize;
+    if reader.bytes.len() != count * RETIRED_KEY_SIZE {
+        return Err(ServicesError::General(
+            "Invalid recovery phrase: unexpected length".to_owned(),
+        ));
+    }
+    let retired = (0..count)
+        .map(|_| RetiredAuthorizationKey {
+            key: reader.key(),
+            start_index: reader.u16(),
+            end_index: reader.u16(),
+            start_time: reader.u64(),
+            end_time: reader.u64(),
+        })
+        .collect();
+
+    Ok(KeySet {
+        key,
+        created,
+        tck_index,
+        retired,
+    })
+}
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut checksum = [0; CHECKSUM_SIZE];
+    checksum.copy_from_slice(&Sha256::digest(bytes)[..CHECKSUM_SIZE]);
+    checksum
+}
+
+// The lengths are checked before reading.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> &'a [u8] {
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        taken
+    }
+
+    fn key(&mut self) -> [u8; 32] {
+        let mut key = [0; 32];
+        key.copy_from_slice(self.take(32));
+        key
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_be_bytes(self.take(2).try_into().unwrap())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_be_bytes(self.take(8).try_into().unwrap())
+    }
+}
+
+fn to_base32(bytes: &[u8]) -> Vec<char> {
+    let mut chars = vec![];
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            chars.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        chars.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    chars
+}
+
+fn from_base32(phrase: &str) -> Result<Vec<u8>, ServicesError> {
+    let mut bytes = vec![];
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for char in phrase.chars() {
+        let char = match char.to_ascii_uppercase() {
+            '-' => continue,
+            char if char.is_whitespace() => continue,
+            'O' => '0',
+            'I' | 'L' => '1',
+            char => char,
+        };
+        let value = ALPHABET
+            .iter()
+            .position(|c| *c as char == char)
+            .ok_or_else(|| {
+                ServicesError::General(format!("Invalid character in recovery phrase: {}", char))
+            })?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    // The remaining bits are padding
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_set() -> KeySet {
+        KeySet {
+            key: [7; 32],
+            created: 1_600_000_000,
+            tck_index: 42,
+            retired: vec![
+                RetiredAuthorizationKey {
+                    key: [1; 32],
+                    start_index: 1,
+                    end_index: 96,
+                    start_time: 1_599_827_200,
+                    end_time: 1_599_913_600,
+                },
+                RetiredAuthorizationKey {
+                    key: [2; 32],
+                    start_index: 1,
+                    end_index: 95,
+                    start_time: 1_599_913_600,
+                    end_time: 1_600_000_000,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn decodes_encoded_key_set() {
+        let phrase = encode(&key_set());
+
+        assert!(phrase
+            .split('-')
+            .all(|group| group.len() <= GROUP_SIZE && !group.is_empty()));
+        assert_eq!(decode(&phrase).unwrap(), key_set());
+        // As transcribed by a user
+        let transcribed = phrase
+            .to_lowercase()
+            .replace('-', " ")
+            .replace('0', "o")
+            .replace('1', "l");
+        assert_eq!(decode(&transcribed).unwrap(), key_set());
+    }
+
+    #[test]
+    fn rejects_phrase_with_typo() {
+        let phrase = encode(&key_set());
+        let typo_index = phrase.find(|c| c != '-' && c != '2').unwrap();
+        let mut with_typo = phrase.clone();
+        with_typo.replace_range(typo_index..typo_index + 1, "2");
+
+        assert!(decode(&with_typo).is_err());
+        assert!(decode(&phrase[..phrase.len() - 10]).is_err());
+        assert!(decode("not a phrase!").is_err());
+    }
+}
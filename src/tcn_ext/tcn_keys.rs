@@ -1,17 +1,86 @@
 use crate::{
+    clock::Clock,
+    errors::{DaoError, ServicesError},
     expect_log,
-    preferences::{Preferences, TckBytesWrapper, TCK_SIZE_IN_BYTES},
+    preferences::{
+        Database, Preferences, RetiredAuthorizationKey, TckBytesWrapper, TCK_SIZE_IN_BYTES,
+    },
+    reports_interval::UnixTime,
+    tcn_ext::{
+        emitted_tcns::{EmittedTcn, EmittedTcnDao, ReportPreview},
+        key_randomness::KeyRandomness,
+        recovery_phrase::{self, KeySet},
+    },
 };
 use log::*;
-use std::{io::Cursor, sync::Arc};
+use parking_lot::ReentrantMutex;
+use serde::Deserialize;
+use std::{collections::HashSet, io::Cursor, sync::Arc};
 use tcn::{
-    Error, MemoType, ReportAuthorizationKey, SignedReport, TemporaryContactKey,
+    MemoType, Report, ReportAuthorizationKey, SignedReport, TemporaryContactKey,
     TemporaryContactNumber,
 };
 
+// Reports cover the TCNs of this period
+pub const REPORT_PERIOD_SECS: u64 = 14 * 24 * 60 * 60;
+// A new TCN is used every period. TCK index i is used in the i-th period since the key's creation.
+pub const TCN_PERIOD_SECS: u64 = 15 * 60;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct KeysConfig {
+    // The authorization key is replaced after this period, so an adversary can't link
+    // the TCNs of more than one period with a report. None: only when the TCK ratchet is exhausted.
+    pub rotation_period_secs: Option<u64>,
+    // Hex, 32 bytes. If set, the authorization keys are derived from it and their creation time,
+    // so test fixtures can reproduce a scenario. Requires the "test-fixtures" feature.
+    pub seed: Option<String>,
+}
+
+impl KeysConfig {
+    // Authorization keys a report can be signed with, i.e. the reports uploaded per submission.
+    // Imported keys (recovery phrase, backup) can add to these.
+    pub fn max_report_keys(&self) -> usize {
+        match self.rotation_period_secs {
+            // Including the keys used in a part of the period at both ends
+            Some(period) if period > 0 => (REPORT_PERIOD_SECS / period) as usize + 2,
+            // Rotated only when the ratchet is exhausted, which takes longer than the period
+            _ => 2,
+        }
+    }
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        Self {
+            rotation_period_secs: Some(24 * 60 * 60),
+            seed: None,
+        }
+    }
+}
+
 pub trait TcnKeys {
-    fn create_report(&self, report: Vec<u8>) -> Result<SignedReport, Error>;
-    fn generate_tcn(&self) -> TemporaryContactNumber;
+    // Reports the TCNs used since from (at most REPORT_PERIOD_SECS ago) until the current period.
+    // One report per authorization key used in that window (keys are rotated), the current key's first.
+    fn create_report(
+        &self,
+        report: Vec<u8>,
+        from: &UnixTime,
+    ) -> Result<Vec<SignedReport>, ServicesError>;
+    // The TCN of the current period: repeated calls in the same period return the same TCN.
+    fn generate_tcn(&self) -> Result<TemporaryContactNumber, ServicesError>;
+    // What create_report with this from would share, as far as the emitted TCNs log knows.
+    fn preview_report(&self, from: &UnixTime) -> Result<ReportPreview, DaoError>;
+    // The keys needed to report the TCNs broadcast until now, as a phrase the user can write down.
+    fn export_recovery_phrase(&self) -> Result<String, ServicesError>;
+    // Continues with the keys of the phrase (e.g. on a new phone). The keys used on this device
+    // until now are retired, so their TCNs can still be reported.
+    fn import_recovery_phrase(&self, phrase: &str) -> Result<(), ServicesError>;
+    // As import_recovery_phrase, with keys from elsewhere (e.g. a backup).
+    fn import_keys(&self, key_set: KeySet) -> Result<(), ServicesError>;
+    // Runs f while the keys aren't read or updated by other threads (e.g. so deleted keys aren't
+    // written back). f can still use them.
+    fn with_keys_locked<R, F: FnOnce() -> R>(&self, f: F) -> R;
 }
 
 pub trait ReportAuthorizationKeyExt {
@@ -34,42 +103,253 @@ pub trait TckBytesWrapperExt {
 
 impl TckBytesWrapperExt for TckBytesWrapper {}
 
+pub trait ReportExt {
+    // The verification key of the authorization key the report was created with
+    fn rvk_bytes(&self) -> [u8; 32];
+}
+
+impl ReportExt for Report {
+    // Written first
+    fn rvk_bytes(&self) -> [u8; 32] {
+        let mut buf = Vec::new();
+        let res = self.write(Cursor::new(&mut buf));
+        expect_log!(res, "Couldn't write report bytes");
+        let mut rvk = [0; 32];
+        rvk.copy_from_slice(&buf[..32]);
+        rvk
+    }
+}
+
 pub struct TcnKeysImpl<T>
 where
     T: Preferences,
 {
     pub preferences: Arc<T>,
+    pub rotation_period_secs: Option<u64>,
+    pub clock: Arc<dyn Clock>,
+    pub emitted_tcns: Arc<EmittedTcnDao>,
+    pub randomness: Arc<dyn KeyRandomness>,
+    // The key state is read and updated in a transaction
+    database: Arc<Database>,
+    // Held while the key state is read and updated (e.g. the BLE advertiser and GATT server
+    // generate TCNs concurrently): otherwise a TCN could be skipped, or a key rotated twice.
+    key_state: ReentrantMutex<()>,
 }
 
-impl<T> TcnKeys for TcnKeysImpl<T>
+impl<T> TcnKeysImpl<T>
 where
     T: Preferences,
 {
-    fn create_report(&self, report: Vec<u8>) -> Result<SignedReport, Error> {
-        let end_index = self.tck().index();
-        let periods = 14 * 24 * (60 / 15);
-        let mut start_index = 1;
-        if end_index > periods {
-            start_index = (end_index - periods) as u16
+    pub fn new(
+        preferences: Arc<T>,
+        database: Arc<Database>,
+        rotation_period_secs: Option<u64>,
+        clock: Arc<dyn Clock>,
+        emitted_tcns: Arc<EmittedTcnDao>,
+        randomness: Arc<dyn KeyRandomness>,
+    ) -> TcnKeysImpl<T> {
+        TcnKeysImpl {
+            preferences,
+            rotation_period_secs,
+            clock,
+            emitted_tcns,
+            randomness,
+            database,
+            key_state: ReentrantMutex::new(()),
         }
-        debug!("start_index={}, end_index={}", start_index, end_index);
+    }
+}
+
+impl<T> TcnKeys for TcnKeysImpl<T>
+where
+    T: Preferences,
+{
+    fn create_report(
+        &self,
+        report: Vec<u8>,
+        from: &UnixTime,
+    ) -> Result<Vec<SignedReport>, ServicesError> {
+        let _key_state = self.key_state.lock();
+        self.database.transaction(|_| {
+            let now = self.clock.now().value;
+            let from = self.report_start(from);
+            let emitted = self.emitted_since(from);
+
+            let rak = self.rak()?;
+            let created = self.key_created(now)?;
+            let end_index = self.tck()?.index();
+            let start_index = Self::first_emitted_index(&emitted, created)
+                .unwrap_or_else(|| Self::index_at(created, from))
+                .min(end_index);
+            debug!("start_index={}, end_index={}", start_index, end_index);
+
+            let mut reports = vec![rak.create_report(
+                MemoType::CoEpiV1,
+                report.clone(),
+                start_index,
+                end_index,
+            )?];
 
-        self.rak()
-            .create_report(MemoType::CoEpiV1, report, start_index, end_index)
+            // Most recent first
+            for retired in self.preferences.retired_authorization_keys()?.iter().rev() {
+                if retired.end_time < from {
+                    break;
+                }
+                let start_index = if retired.start_time > 0 {
+                    Self::first_emitted_index(&emitted, retired.start_time)
+                        .unwrap_or_else(|| Self::index_at(retired.start_time, from))
+                        .max(retired.start_index)
+                        .min(retired.end_index)
+                } else {
+                    retired.start_index
+                };
+                debug!(
+                    "Retired key: start_index={}, end_index={}",
+                    start_index, retired.end_index
+                );
+                reports.push(
+                    ReportAuthorizationKey::with_bytes(retired.key).create_report(
+                        MemoType::CoEpiV1,
+                        report.clone(),
+                        start_index,
+                        retired.end_index,
+                    )?,
+                );
+            }
+
+            Ok(reports)
+        })
     }
 
-    fn generate_tcn(&self) -> TemporaryContactNumber {
-        let tck = self.tck();
-        let tcn = tck.temporary_contact_number();
-        let new_tck = tck.ratchet();
+    fn generate_tcn(&self) -> Result<TemporaryContactNumber, ServicesError> {
+        // Read, ratchet, persist and log in one step: a crash can't leave e.g. a new key with the
+        // old key's TCK. The lock orders the concurrent calls of this process.
+        let _key_state = self.key_state.lock();
+        self.database.transaction(|_| {
+            let now = self.clock.now().value;
+            self.rak()?;
+            if self.rotation_due(now)? {
+                self.rotate_rak(now)?;
+            }
 
-        if let Some(new_tck) = new_tck {
-            self.set_tck(new_tck);
-        }
+            // Past the last index. Otherwise the last TCN would be broadcast forever, making the user trackable.
+            if Self::periods_since(self.key_created(now)?, now) > u16::MAX as u64 {
+                self.rotate_rak(now)?;
+            }
+
+            let created = self.key_created(now)?;
+            let index = Self::index_at(created, now);
+            let tcn = self.tcn_for(index)?;
+            debug!("Generated tcn: {:?}, index: {}", tcn, index);
+
+            // The index actually used (it's not ratcheted back if the clock moved back)
+            let emitted = EmittedTcn {
+                tcn,
+                key_created: created,
+                index: self.tck()?.index(),
+                time: UnixTime { value: now },
+            };
+            if let Err(error) = self.emitted_tcns.record(&emitted) {
+                error!("Couldn't record emitted TCN: {:?}", error);
+            }
+            Ok(tcn)
+        })
+    }
+
+    fn export_recovery_phrase(&self) -> Result<String, ServicesError> {
+        let _key_state = self.key_state.lock();
+        self.database.transaction(|_| {
+            let now = self.clock.now().value;
+            let key = Self::rak_to_bytes(self.rak()?);
+            Ok(recovery_phrase::encode(&KeySet {
+                key,
+                created: self.key_created(now)?,
+                tck_index: self.tck()?.index(),
+                retired: self.preferences.retired_authorization_keys()?,
+            }))
+        })
+    }
+
+    fn import_recovery_phrase(&self, phrase: &str) -> Result<(), ServicesError> {
+        self.import_keys(recovery_phrase::decode(phrase)?)
+    }
+
+    fn import_keys(&self, key_set: KeySet) -> Result<(), ServicesError> {
+        let _key_state = self.key_state.lock();
+        self.database.transaction(|_| {
+            let now = self.clock.now().value;
+
+            // The first TCN of the key that can still be reported
+            let first_index =
+                Self::index_at(key_set.created, now.saturating_sub(REPORT_PERIOD_SECS))
+                    .min(key_set.tck_index)
+                    .max(1);
+            let rak = ReportAuthorizationKey::with_bytes(key_set.key);
+            let mut tck = rak.initial_temporary_contact_key();
+            let mut first_tcn = tck.temporary_contact_number();
+            while tck.index() < key_set.tck_index {
+                tck = tck.ratchet().ok_or("Invalid TCK index")?;
+                if tck.index() == first_index {
+                    first_tcn = tck.temporary_contact_number();
+                }
+            }
+            let mut retired = key_set.retired;
+            retired.extend(self.preferences.retired_authorization_keys()?);
+            if let Some(device_key) = self.preferences.authorization_key()? {
+                retired.push(RetiredAuthorizationKey {
+                    key: device_key,
+                    start_index: 1,
+                    end_index: self.tck()?.index(),
+                    start_time: self.preferences.authorization_key_created()?.unwrap_or(0),
+                    end_time: now,
+                });
+            }
+            // Ordered by end time, as when rotating. Without the restored current key and duplicates.
+            retired.sort_by_key(|key| key.end_time);
+            let mut keys = HashSet::new();
+            keys.insert(key_set.key);
+            retired.retain(|key| keys.insert(key.key) && key.end_time + REPORT_PERIOD_SECS >= now);
+            info!(
+                "Importing keys. TCK index: {}, retired keys: {}",
+                key_set.tck_index,
+                retired.len()
+            );
 
-        debug!("Generated tcn: {:?}", tcn);
-        // TODO: if None, rotate RAK
-        tcn
+            self.preferences.set_retired_authorization_keys(retired)?;
+            self.preferences.set_autorization_key(key_set.key)?;
+            self.preferences
+                .set_authorization_key_created(key_set.created)?;
+            self.set_tck(tck)?;
+
+            // Otherwise the log, which has only the TCNs broadcast by this device, would narrow the
+            // report window to them. The emission time is the start of the TCN's period.
+            let emitted = EmittedTcn {
+                tcn: first_tcn,
+                key_created: key_set.created,
+                index: first_index,
+                time: UnixTime {
+                    value: key_set.created + (first_index as u64 - 1) * TCN_PERIOD_SECS,
+                },
+            };
+            if let Err(error) = self.emitted_tcns.record(&emitted) {
+                error!("Couldn't record restored TCN: {:?}", error);
+            }
+            Ok(())
+        })
+    }
+
+    fn with_keys_locked<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        let _key_state = self.key_state.lock();
+        f()
+    }
+
+    fn preview_report(&self, from: &UnixTime) -> Result<ReportPreview, DaoError> {
+        let from = self.report_start(from);
+        let emitted = self.emitted_tcns.since(&UnixTime { value: from })?;
+        Ok(ReportPreview {
+            from,
+            emission_times: emitted.iter().map(|emitted| emitted.time.value).collect(),
+        })
     }
 }
 
@@ -77,27 +357,133 @@ impl<T> TcnKeysImpl<T>
 where
     T: Preferences,
 {
-    fn rak(&self) -> ReportAuthorizationKey {
-        self.preferences
-            .authorization_key()
-            .map(|rak_bytes| ReportAuthorizationKey::with_bytes(rak_bytes)) //Self::bytes_to_rak(rak_bytes))
-            .unwrap_or_else(|| {
-                let new_key = ReportAuthorizationKey::new(rand::thread_rng());
-                self.preferences
-                    .set_autorization_key(Self::rak_to_bytes(new_key));
-                new_key
+    // Reports don't go further back than REPORT_PERIOD_SECS
+    fn report_start(&self, from: &UnixTime) -> u64 {
+        let now = self.clock.now().value;
+        from.value.max(now.saturating_sub(REPORT_PERIOD_SECS))
+    }
+
+    // Empty if the log can't be read: the report window is then derived from the times only.
+    fn emitted_since(&self, from: u64) -> Vec<EmittedTcn> {
+        self.emitted_tcns
+            .since(&UnixTime { value: from })
+            .unwrap_or_else(|error| {
+                error!("Couldn't load emitted TCNs: {:?}", error);
+                vec![]
             })
     }
 
-    fn tck(&self) -> TemporaryContactKey {
+    // First index of the key created at key_created that was broadcast, if the log has it.
+    // TCNs emitted before the log existed aren't in it.
+    fn first_emitted_index(emitted: &[EmittedTcn], key_created: u64) -> Option<u16> {
+        emitted
+            .iter()
+            .filter(|emitted| emitted.key_created == key_created)
+            .map(|emitted| emitted.index)
+            .min()
+    }
+
+    fn rak(&self) -> Result<ReportAuthorizationKey, DaoError> {
+        match self.preferences.authorization_key()? {
+            Some(rak_bytes) => Ok(ReportAuthorizationKey::with_bytes(rak_bytes)),
+            None => self.create_rak(),
+        }
+    }
+
+    fn create_rak(&self) -> Result<ReportAuthorizationKey, DaoError> {
+        let created = self.clock.now().value;
+        let new_key = ReportAuthorizationKey::new(self.randomness.rng_for_key(created));
         self.preferences
-            .tck()
-            .map(|tck_bytes| Self::bytes_to_tck(tck_bytes))
-            .unwrap_or_else(|| self.rak().initial_temporary_contact_key())
+            .set_autorization_key(Self::rak_to_bytes(new_key))?;
+        self.preferences.set_authorization_key_created(created)?;
+        Ok(new_key)
+    }
+
+    // Keys created before the creation time was stored are aligned, so the current TCK is the one of now.
+    fn key_created(&self, now: u64) -> Result<u64, DaoError> {
+        match self.preferences.authorization_key_created()? {
+            Some(created) => Ok(created),
+            None => {
+                let created =
+                    now.saturating_sub((self.tck()?.index() as u64 - 1) * TCN_PERIOD_SECS);
+                self.preferences.set_authorization_key_created(created)?;
+                Ok(created)
+            }
+        }
+    }
+
+    // Number of TCN periods started since created, including the current one.
+    fn periods_since(created: u64, time: u64) -> u64 {
+        time.saturating_sub(created) / TCN_PERIOD_SECS + 1
+    }
+
+    // TCK index used at time, for a key created at created. Capped at the last index.
+    fn index_at(created: u64, time: u64) -> u16 {
+        Self::periods_since(created, time).min(u16::MAX as u64) as u16
     }
 
-    fn set_tck(&self, tck: TemporaryContactKey) {
-        self.preferences.set_tck(Self::tck_to_bytes(tck));
+    // Ratchets the stored TCK forward to index. If the clock moved back, the current TCK is kept:
+    // TCNs are never reused.
+    fn tcn_for(&self, index: u16) -> Result<TemporaryContactNumber, DaoError> {
+        let mut tck = self.tck()?;
+        if tck.index() >= index {
+            return Ok(tck.temporary_contact_number());
+        }
+        while tck.index() < index {
+            // index is at most the last one
+            let res = tck.ratchet().ok_or("Exhausted");
+            tck = expect_log!(res, "TCK ratchet exhausted");
+        }
+        let tcn = tck.temporary_contact_number();
+        self.set_tck(tck)?;
+        Ok(tcn)
+    }
+
+    // Keys of unknown age (created before this was stored) are rotated immediately.
+    fn rotation_due(&self, now: u64) -> Result<bool, DaoError> {
+        Ok(match self.rotation_period_secs {
+            Some(period) => self
+                .preferences
+                .authorization_key_created()?
+                .map(|created| created + period <= now)
+                .unwrap_or(true),
+            None => false,
+        })
+    }
+
+    // Retires the current key and starts using a new one.
+    // Retired keys that can't be reported anymore are deleted.
+    fn rotate_rak(&self, now: u64) -> Result<(), DaoError> {
+        let end_index = self.tck()?.index();
+        info!(
+            "Rotating authorization key. Last used TCK index: {}",
+            end_index
+        );
+
+        let mut retired = self.preferences.retired_authorization_keys()?;
+        retired.retain(|key| key.end_time + REPORT_PERIOD_SECS >= now);
+        retired.push(RetiredAuthorizationKey {
+            key: Self::rak_to_bytes(self.rak()?),
+            start_index: 1,
+            end_index,
+            start_time: self.preferences.authorization_key_created()?.unwrap_or(0),
+            end_time: now,
+        });
+        self.preferences.set_retired_authorization_keys(retired)?;
+
+        let new_key = self.create_rak()?;
+        self.set_tck(new_key.initial_temporary_contact_key())
+    }
+
+    fn tck(&self) -> Result<TemporaryContactKey, DaoError> {
+        match self.preferences.tck()? {
+            Some(tck_bytes) => Ok(Self::bytes_to_tck(tck_bytes)),
+            None => Ok(self.rak()?.initial_temporary_contact_key()),
+        }
+    }
+
+    fn set_tck(&self, tck: TemporaryContactKey) -> Result<(), DaoError> {
+        self.preferences.set_tck(Self::tck_to_bytes(tck))
     }
 
     fn rak_to_bytes(rak: ReportAuthorizationKey) -> [u8; 32] {
@@ -129,9 +515,32 @@ where
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
-    use crate::preferences::PreferencesTckMock;
+    use crate::{
+        clock::{tests::FakeClock, SystemClock},
+        encryption::{tests::test_cipher, SecretCipher},
+        migrations::tests::test_database,
+        preferences::{PreferencesDao, PreferencesImpl, PreferencesTckMock},
+        tcn_ext::key_randomness::{SeededRandomness, SystemRandomness},
+    };
+    use rusqlite::NO_PARAMS;
+    use std::thread;
+
+    // Keys with the system clock and randomness, for the tests of their users
+    pub fn test_tcn_keys<T: Preferences>(
+        preferences: Arc<T>,
+        database: Arc<Database>,
+    ) -> TcnKeysImpl<T> {
+        TcnKeysImpl::new(
+            preferences,
+            database.clone(),
+            None,
+            Arc::new(SystemClock {}),
+            Arc::new(EmittedTcnDao::new(database)),
+            Arc::new(SystemRandomness {}),
+        )
+    }
 
     #[test]
     fn test_rak() {
@@ -199,4 +608,440 @@ mod tests {
         info!("Number of generated TCNS: {}", tcns.len());
         assert_eq!(100, tcns.len(), "Expected 100 TCNs");
     }
+
+    const TIME: u64 = 1_600_000_000;
+
+    fn create_tcn_keys(
+        rotation_period_secs: Option<u64>,
+    ) -> (
+        TcnKeysImpl<PreferencesImpl>,
+        Arc<PreferencesImpl>,
+        Arc<FakeClock>,
+    ) {
+        create_tcn_keys_with(rotation_period_secs, Arc::new(SystemRandomness {}))
+    }
+
+    fn create_tcn_keys_with(
+        rotation_period_secs: Option<u64>,
+        randomness: Arc<dyn KeyRandomness>,
+    ) -> (
+        TcnKeysImpl<PreferencesImpl>,
+        Arc<PreferencesImpl>,
+        Arc<FakeClock>,
+    ) {
+        let database = test_database();
+        let preferences = Arc::new(PreferencesImpl::new(
+            PreferencesDao::new(database.clone()),
+            None,
+        ));
+        let clock = Arc::new(FakeClock::new(TIME));
+        let tcn_keys = TcnKeysImpl::new(
+            preferences.clone(),
+            database.clone(),
+            rotation_period_secs,
+            clock.clone(),
+            Arc::new(EmittedTcnDao::new(database)),
+            randomness,
+        );
+        (tcn_keys, preferences, clock)
+    }
+
+    fn verified_reports(
+        tcn_keys: &TcnKeysImpl<PreferencesImpl>,
+    ) -> Vec<Vec<TemporaryContactNumber>> {
+        verified_reports_from(tcn_keys, 0)
+    }
+
+    fn verified_reports_from(
+        tcn_keys: &TcnKeysImpl<PreferencesImpl>,
+        from: u64,
+    ) -> Vec<Vec<TemporaryContactNumber>> {
+        tcn_keys
+            .create_report(vec![1], &UnixTime { value: from })
+            .unwrap()
+            .into_iter()
+            .map(|report| {
+                report
+                    .verify()
+                    .unwrap()
+                    .temporary_contact_numbers()
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn generates_one_tcn_per_period() {
+        let (tcn_keys, preferences, clock) = create_tcn_keys(None);
+
+        let tcn_1 = tcn_keys.generate_tcn().unwrap();
+        clock.advance(TCN_PERIOD_SECS - 1);
+        assert_eq!(tcn_keys.generate_tcn().unwrap(), tcn_1);
+
+        clock.advance(1);
+        let tcn_2 = tcn_keys.generate_tcn().unwrap();
+        assert_ne!(tcn_2, tcn_1);
+
+        // Indices follow the time, also if no TCN was generated in between (or after a restart)
+        clock.advance(10 * TCN_PERIOD_SECS);
+        let restarted = TcnKeysImpl::new(
+            preferences.clone(),
+            tcn_keys.database.clone(),
+            None,
+            clock.clone(),
+            tcn_keys.emitted_tcns.clone(),
+            tcn_keys.randomness.clone(),
+        );
+        let tcn_12 = restarted.generate_tcn().unwrap();
+        assert_eq!(restarted.tck().unwrap().index(), 12);
+        assert_eq!(restarted.generate_tcn().unwrap(), tcn_12);
+
+        let mut expected = restarted.rak().unwrap().initial_temporary_contact_key();
+        for _ in 1..12 {
+            expected = expected.ratchet().unwrap();
+        }
+        assert_eq!(expected.temporary_contact_number(), tcn_12);
+    }
+
+    #[test]
+    fn report_covers_the_indices_of_the_report_period() {
+        let (tcn_keys, _, clock) = create_tcn_keys(None);
+        let tcn_1 = tcn_keys.generate_tcn().unwrap();
+        clock.advance(99 * TCN_PERIOD_SECS);
+        tcn_keys.generate_tcn().unwrap();
+
+        let reports = verified_reports(&tcn_keys);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].len(), 100);
+        assert_eq!(reports[0][0], tcn_1);
+
+        // Only the periods of the last 14 days (including the current one)
+        clock.advance(REPORT_PERIOD_SECS);
+        let tcn = tcn_keys.generate_tcn().unwrap();
+        let reports = verified_reports(&tcn_keys);
+        assert_eq!(
+            reports[0].len() as u64,
+            REPORT_PERIOD_SECS / TCN_PERIOD_SECS + 1
+        );
+        assert_eq!(reports[0].last(), Some(&tcn));
+        assert!(!reports[0].contains(&tcn_1));
+    }
+
+    #[test]
+    fn report_starts_at_the_requested_time() {
+        let (tcn_keys, preferences, clock) = create_tcn_keys(Some(24 * 60 * 60));
+        // 3 days, with a key per day
+        for _ in 0..3 * 24 * 4 {
+            tcn_keys.generate_tcn().unwrap();
+            clock.advance(TCN_PERIOD_SECS);
+        }
+        let tcn = tcn_keys.generate_tcn().unwrap();
+        assert_eq!(preferences.retired_authorization_keys().unwrap().len(), 3);
+
+        // From the middle of the second day
+        let from = TIME + 36 * 60 * 60;
+        let reports = verified_reports_from(&tcn_keys, from);
+
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0], vec![tcn]);
+        assert_eq!(reports[1].len(), 24 * 4);
+        assert_eq!(reports[2].len(), 12 * 4);
+
+        // Later than now: only the current period
+        let reports = verified_reports_from(&tcn_keys, clock.now().value + 1000);
+        assert_eq!(reports, vec![vec![tcn]]);
+    }
+
+    #[test]
+    fn report_starts_at_the_first_emitted_tcn() {
+        let (tcn_keys, _, clock) = create_tcn_keys(None);
+        tcn_keys.rak().unwrap();
+        // Not broadcasting in the first 10 periods
+        clock.advance(10 * TCN_PERIOD_SECS);
+        let tcn_11 = tcn_keys.generate_tcn().unwrap();
+        clock.advance(TCN_PERIOD_SECS);
+        let tcn_12 = tcn_keys.generate_tcn().unwrap();
+
+        let reports = verified_reports(&tcn_keys);
+        assert_eq!(reports, vec![vec![tcn_11, tcn_12]]);
+
+        let preview = tcn_keys.preview_report(&UnixTime { value: 0 }).unwrap();
+        assert_eq!(
+            preview,
+            ReportPreview {
+                from: clock.now().value - REPORT_PERIOD_SECS,
+                emission_times: vec![TIME + 10 * TCN_PERIOD_SECS, TIME + 11 * TCN_PERIOD_SECS],
+            }
+        );
+    }
+
+    #[test]
+    fn issues_each_index_once_when_called_concurrently() {
+        let (tcn_keys, preferences, clock) = create_tcn_keys(Some(4 * TCN_PERIOD_SECS));
+        let tcn_keys = Arc::new(tcn_keys);
+
+        let mut issued = vec![];
+        for _ in 0..12 {
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    let tcn_keys = tcn_keys.clone();
+                    thread::spawn(move || {
+                        (0..5)
+                            .map(|_| tcn_keys.generate_tcn().unwrap().0)
+                            .collect::<Vec<[u8; 16]>>()
+                    })
+                })
+                .collect();
+            let tcns: HashSet<[u8; 16]> = threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect();
+            // All the threads got the TCN of the period
+            assert_eq!(tcns.len(), 1);
+            issued.extend(tcns);
+            clock.advance(TCN_PERIOD_SECS);
+        }
+
+        assert_eq!(issued.iter().collect::<HashSet<_>>().len(), 12);
+        // Rotated once every 4 periods: no key was created twice
+        assert_eq!(preferences.retired_authorization_keys().unwrap().len(), 2);
+        let emitted = tcn_keys.emitted_tcns.since(&UnixTime { value: 0 }).unwrap();
+        let indices: Vec<u16> = emitted.iter().map(|emitted| emitted.index).collect();
+        assert_eq!(indices, [1, 2, 3, 4].repeat(3));
+
+        let reported: HashSet<[u8; 16]> = verified_reports(&tcn_keys)
+            .into_iter()
+            .flatten()
+            .map(|tcn| tcn.0)
+            .collect();
+        assert!(issued.iter().all(|tcn| reported.contains(tcn)));
+    }
+
+    #[test]
+    fn seeded_keys_reproduce_a_scenario() {
+        // Generates TCNs for 2 days, with a key per day
+        let run = |seed: [u8; 32]| {
+            let (tcn_keys, _, clock) =
+                create_tcn_keys_with(Some(24 * 60 * 60), Arc::new(SeededRandomness::new(seed)));
+            let mut tcns = vec![];
+            for _ in 0..2 * 24 * 4 {
+                tcns.push(tcn_keys.generate_tcn().unwrap());
+                clock.advance(TCN_PERIOD_SECS);
+            }
+            (tcns, verified_reports(&tcn_keys))
+        };
+
+        let (tcns, reports) = run([1; 32]);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(run([1; 32]), (tcns.clone(), reports));
+        assert_ne!(run([2; 32]).0, tcns);
+    }
+
+    #[test]
+    fn imported_recovery_phrase_restores_keys() {
+        let (old_phone, _, old_clock) = create_tcn_keys(Some(24 * 60 * 60));
+        let mut old_tcns = vec![];
+        // Rotated once, and not due again when importing
+        for _ in 0..2 * 24 * 4 - 4 {
+            old_tcns.push(old_phone.generate_tcn().unwrap());
+            old_clock.advance(TCN_PERIOD_SECS);
+        }
+        let phrase = old_phone.export_recovery_phrase().unwrap();
+
+        // The new phone already broadcast a TCN with its own key
+        let (new_phone, new_preferences, new_clock) = create_tcn_keys(Some(24 * 60 * 60));
+        new_clock.advance(old_clock.now().value - TIME - TCN_PERIOD_SECS);
+        let new_phone_tcn = new_phone.generate_tcn().unwrap();
+        new_clock.advance(TCN_PERIOD_SECS);
+
+        new_phone.import_recovery_phrase(&phrase).unwrap();
+
+        // The old phone's retired key and the new phone's key
+        assert_eq!(
+            new_preferences.retired_authorization_keys().unwrap().len(),
+            2
+        );
+        // Continues with the old phone's key and TCK position
+        assert_eq!(
+            new_phone.generate_tcn().unwrap(),
+            old_phone.generate_tcn().unwrap()
+        );
+        let reported: Vec<TemporaryContactNumber> =
+            verified_reports(&new_phone).into_iter().flatten().collect();
+        assert!(old_tcns.iter().all(|tcn| reported.contains(tcn)));
+        assert!(reported.contains(&new_phone_tcn));
+
+        assert!(new_phone.import_recovery_phrase("0000-0000").is_err());
+    }
+
+    #[test]
+    fn keeps_keys_that_cant_be_decrypted() {
+        let database = test_database();
+        let create = |cipher| {
+            TcnKeysImpl::new(
+                Arc::new(PreferencesImpl::new(
+                    PreferencesDao::new(database.clone()),
+                    Some(cipher),
+                )),
+                database.clone(),
+                None,
+                Arc::new(FakeClock::new(TIME)),
+                Arc::new(EmittedTcnDao::new(database.clone())),
+                Arc::new(SystemRandomness {}),
+            )
+        };
+        let tcn_keys = create(test_cipher());
+        let tcn = tcn_keys.generate_tcn().unwrap();
+        let stored = PreferencesDao::new(database.clone())
+            .load("authorization_key")
+            .unwrap();
+
+        // Fails instead of creating a new key
+        assert!(create(SecretCipher::new([9; 32])).generate_tcn().is_err());
+        assert_eq!(
+            PreferencesDao::new(database.clone())
+                .load("authorization_key")
+                .unwrap(),
+            stored
+        );
+        assert_eq!(tcn_keys.generate_tcn().unwrap(), tcn);
+    }
+
+    #[test]
+    fn interrupted_rotation_is_rolled_back() {
+        let (tcn_keys, preferences, clock) = create_tcn_keys(Some(24 * 60 * 60));
+        tcn_keys.generate_tcn().unwrap();
+        let key = preferences.authorization_key().unwrap();
+
+        // Storing the new key's TCK fails, after the new key was stored
+        tcn_keys
+            .database
+            .execute_sql(
+                "create trigger fail_tck before insert on preferences when new.key = 'tck'
+                begin select raise(abort, 'Failed'); end",
+                NO_PARAMS,
+            )
+            .unwrap();
+        clock.advance(24 * 60 * 60);
+        assert!(tcn_keys.generate_tcn().is_err());
+        assert_eq!(preferences.authorization_key().unwrap(), key);
+        assert!(preferences.retired_authorization_keys().unwrap().is_empty());
+
+        tcn_keys
+            .database
+            .execute_sql("drop trigger fail_tck", NO_PARAMS)
+            .unwrap();
+        tcn_keys.generate_tcn().unwrap();
+        assert_ne!(preferences.authorization_key().unwrap(), key);
+        assert_eq!(preferences.retired_authorization_keys().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rotates_rak_when_tck_ratchet_is_exhausted() {
+        let (tcn_keys, preferences, clock) = create_tcn_keys(None);
+        let rak = tcn_keys.rak().unwrap();
+        clock.advance((u16::MAX as u64 - 1) * TCN_PERIOD_SECS);
+        let last_tcn = tcn_keys.generate_tcn().unwrap();
+        assert_eq!(tcn_keys.tck().unwrap().index(), u16::MAX);
+
+        clock.advance(TCN_PERIOD_SECS);
+        let next_tcns = [tcn_keys.generate_tcn().unwrap(), {
+            clock.advance(TCN_PERIOD_SECS);
+            tcn_keys.generate_tcn().unwrap()
+        }];
+
+        // New key, starting at the first TCK
+        assert!(next_tcns.iter().all(|tcn| tcn != &last_tcn));
+        assert_ne!(
+            preferences.authorization_key().unwrap(),
+            Some(TcnKeysImpl::<PreferencesImpl>::rak_to_bytes(rak))
+        );
+        assert_eq!(tcn_keys.tck().unwrap().index(), 2);
+        let retired = preferences.retired_authorization_keys().unwrap();
+        assert_eq!(retired.len(), 1);
+        assert_eq!(
+            retired[0].key,
+            TcnKeysImpl::<PreferencesImpl>::rak_to_bytes(rak)
+        );
+        assert_eq!(
+            (retired[0].start_index, retired[0].end_index),
+            (1, u16::MAX)
+        );
+
+        // Reports cover the TCNs of both keys
+        let reports = verified_reports(&tcn_keys);
+        assert_eq!(reports.len(), 2);
+        assert!(next_tcns.iter().all(|tcn| reports[0].contains(tcn)));
+        assert!(reports[1].contains(&last_tcn));
+    }
+
+    #[test]
+    fn rotates_rak_after_rotation_period() {
+        let (tcn_keys, preferences, clock) = create_tcn_keys(Some(24 * 60 * 60));
+
+        let tcn_1 = tcn_keys.generate_tcn().unwrap();
+        clock.advance(TCN_PERIOD_SECS);
+        tcn_keys.generate_tcn().unwrap();
+        // Not due yet
+        assert!(preferences.retired_authorization_keys().unwrap().is_empty());
+        let first_key = preferences.authorization_key().unwrap().unwrap();
+
+        clock.advance(24 * 60 * 60);
+        let tcn_2 = tcn_keys.generate_tcn().unwrap();
+
+        let retired = preferences.retired_authorization_keys().unwrap();
+        assert_eq!(retired.len(), 1);
+        assert_eq!(retired[0].key, first_key);
+        assert_eq!((retired[0].start_index, retired[0].end_index), (1, 2));
+        assert_eq!(retired[0].start_time, TIME);
+        assert_ne!(preferences.authorization_key().unwrap(), Some(first_key));
+        assert_eq!(
+            preferences.authorization_key_created().unwrap(),
+            Some(clock.now().value)
+        );
+        assert_eq!(tcn_keys.tck().unwrap().index(), 1);
+
+        // Keys that ended before the report period aren't reported
+        let mut retired = preferences.retired_authorization_keys().unwrap();
+        retired.insert(
+            0,
+            RetiredAuthorizationKey {
+                key: [1; 32],
+                start_index: 1,
+                end_index: 10,
+                start_time: 0,
+                end_time: clock.now().value - REPORT_PERIOD_SECS - 60,
+            },
+        );
+        preferences.set_retired_authorization_keys(retired).unwrap();
+
+        let reports = verified_reports(&tcn_keys);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0], vec![tcn_2]);
+        assert_eq!(reports[1].len(), 2);
+        assert_eq!(reports[1][0], tcn_1);
+    }
+
+    #[test]
+    fn reports_are_signed_with_at_most_max_report_keys() {
+        let config = KeysConfig::default();
+        let (tcn_keys, _, clock) = create_tcn_keys(config.rotation_period_secs);
+
+        for _ in 0..20 * 4 {
+            tcn_keys.generate_tcn().unwrap();
+            clock.advance(6 * 60 * 60);
+        }
+
+        let reports = verified_reports(&tcn_keys);
+        assert!(reports.len() > 14);
+        assert!(reports.len() <= config.max_report_keys());
+        assert_eq!(
+            KeysConfig {
+                rotation_period_secs: None,
+                seed: None
+            }
+            .max_report_keys(),
+            2
+        );
+    }
 }
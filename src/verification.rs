@@ -0,0 +1,178 @@
+use crate::{
+    errors::ServicesError, networking::NetworkingError, preferences::Preferences,
+    reporting::memo::TOKEN_HASH_SIZE,
+};
+use log::*;
+#[cfg(any(test, feature = "test-fixtures"))]
+use parking_lot::Mutex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+// Passed by the apps in the bootstrap configuration.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct VerificationConfig {
+    // Endpoint the verification codes are exchanged at
+    pub url: Option<String>,
+    // Without url: codes accepted (once) locally, to test the flow without a verification server.
+    // Requires the "test-fixtures" feature.
+    pub fake_codes: Vec<String>,
+}
+
+// Verification server of the health authority.
+pub trait VerificationApi: Send + Sync {
+    // Exchanges a one-time verification code (e.g. given with a positive test) for a token.
+    fn exchange_code(&self, code: &str) -> Result<String, NetworkingError>;
+}
+
+// Fake codes are only accepted by builds with the "test-fixtures" feature: anyone could send
+// verified reports with them.
+#[cfg(feature = "test-fixtures")]
+pub fn fake_verification_api(
+    codes: Vec<String>,
+) -> Result<Arc<dyn VerificationApi>, ServicesError> {
+    Ok(Arc::new(FakeVerificationApi::new(codes)))
+}
+
+#[cfg(not(feature = "test-fixtures"))]
+pub fn fake_verification_api(
+    _codes: Vec<String>,
+) -> Result<Arc<dyn VerificationApi>, ServicesError> {
+    Err(ServicesError::General(
+        "Fake verification codes were passed, but core wasn't built with \"test-fixtures\""
+            .to_owned(),
+    ))
+}
+
+#[cfg(any(test, feature = "test-fixtures"))]
+pub struct FakeVerificationApi {
+    codes: Mutex<Vec<String>>,
+}
+
+#[cfg(any(test, feature = "test-fixtures"))]
+impl FakeVerificationApi {
+    pub fn new(codes: Vec<String>) -> FakeVerificationApi {
+        warn!("Using fake verification codes. Never use this in production!");
+        FakeVerificationApi {
+            codes: Mutex::new(codes),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "test-fixtures"))]
+impl VerificationApi for FakeVerificationApi {
+    fn exchange_code(&self, code: &str) -> Result<String, NetworkingError> {
+        let mut codes = self.codes.lock();
+        match codes.iter().position(|c| c == code) {
+            Some(index) => {
+                codes.remove(index);
+                Ok(format!("fake-token-{}", code))
+            }
+            None => Err(NetworkingError {
+                http_status: 400,
+                message: "Invalid verification code".to_owned(),
+            }),
+        }
+    }
+}
+
+// Sent with the upload of the reports of a submission (one per authorization key), for the
+// backend to accept them as verified. Their memos have the token's hash (token_hash).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportVerification {
+    pub token: String,
+}
+
+impl ReportVerification {
+    pub fn new(token: &str) -> ReportVerification {
+        ReportVerification {
+            token: token.to_owned(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.token.len()
+    }
+}
+
+// SHA-256 of the token, in the memos of the reports it verifies. Signed with the reports, so
+// the token can't be used for others.
+pub fn token_hash(token: &str) -> [u8; TOKEN_HASH_SIZE] {
+    let mut hash = [0; TOKEN_HASH_SIZE];
+    hash.copy_from_slice(&Sha256::digest(token.as_bytes()));
+    hash
+}
+
+// Stores the token until the reports it verifies are sent (also across restarts).
+pub struct Verifier<T: Preferences> {
+    api: Option<Arc<dyn VerificationApi>>,
+    preferences: Arc<T>,
+}
+
+impl<T: Preferences> Verifier<T> {
+    // Without api, verification isn't available
+    pub fn new(api: Option<Arc<dyn VerificationApi>>, preferences: Arc<T>) -> Verifier<T> {
+        Verifier { api, preferences }
+    }
+
+    pub fn verify_code(&self, code: &str) -> Result<(), ServicesError> {
+        let api = self
+            .api
+            .as_ref()
+            .ok_or_else(|| ServicesError::General("Verification is not configured".to_owned()))?;
+        let token = api.exchange_code(code.trim())?;
+        info!("Verification code accepted");
+        self.preferences.set_verification_token(Some(token))?;
+        Ok(())
+    }
+
+    pub fn token(&self) -> Result<Option<String>, ServicesError> {
+        Ok(self.preferences.verification_token()?)
+    }
+
+    // The token can be used only once
+    pub fn consume(&self) -> Result<(), ServicesError> {
+        Ok(self.preferences.set_verification_token(None)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        migrations::tests::test_database,
+        preferences::{PreferencesDao, PreferencesImpl},
+    };
+
+    #[test]
+    fn exchanges_code_once() {
+        let preferences = Arc::new(PreferencesImpl::new(
+            PreferencesDao::new(test_database()),
+            None,
+        ));
+        let api: Arc<dyn VerificationApi> =
+            Arc::new(FakeVerificationApi::new(vec!["123456".to_owned()]));
+        let verifier = Verifier::new(Some(api.clone()), preferences.clone());
+
+        assert!(verifier.verify_code("654321").is_err());
+        assert_eq!(verifier.token().unwrap(), None);
+        verifier.verify_code(" 123456 ").unwrap();
+        // Stored: still there after a restart
+        let restarted = Verifier::new(Some(api), preferences.clone());
+        let token = restarted.token().unwrap().unwrap();
+        restarted.consume().unwrap();
+        assert!(restarted.verify_code("123456").is_err());
+        assert_eq!(verifier.token().unwrap(), None);
+        assert_ne!(token_hash(&token), token_hash("other"));
+        assert!(Verifier::new(None, preferences)
+            .verify_code("123456")
+            .is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "test-fixtures"))]
+    fn rejects_fake_codes_without_feature() {
+        assert!(fake_verification_api(vec!["123456".to_owned()]).is_err());
+    }
+}